@@ -0,0 +1,84 @@
+//! Per-track pattern algorithms.
+
+use alg::rnd::Rnd;
+
+/// Algorithm used to generate the pattern of a track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algo {
+    /// The classic generative algorithm from alg.
+    Generative = 0,
+    /// Steps spread out as evenly as possible over the length.
+    Euclid = 1,
+    /// Each step is randomly active with a probability of steps/length.
+    Density = 2,
+    /// Every Nth step is active, where N is steps.
+    Nth = 3,
+}
+
+impl Default for Algo {
+    fn default() -> Self {
+        Self::Generative
+    }
+}
+
+impl Algo {
+    pub const fn len() -> usize {
+        4
+    }
+
+    /// Short label for the segment display.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Algo::Generative => "gen ",
+            Algo::Euclid => "eucl",
+            Algo::Density => "dens",
+            Algo::Nth => "nth ",
+        }
+    }
+
+    /// Write the pattern for a track. The generative algorithm leaves the
+    /// pattern untouched since it's already generated by alg.
+    pub fn generate(&self, seed: u32, steps: u8, length: u8, offset: u8, pattern: &mut [u8]) {
+        if *self == Algo::Generative {
+            return;
+        }
+
+        let steps = steps as usize;
+        let length = length as usize;
+        let offset = offset as usize;
+
+        let mut rnd = Rnd::new(seed);
+
+        for n in 0..length {
+            // Always draw a random number to keep the sequence independent of algorithm.
+            let r = rnd.next();
+
+            let hit = match self {
+                Algo::Generative => unreachable!(),
+                Algo::Euclid => (n * steps) % length < steps,
+                Algo::Density => (r as u64 * length as u64) < (steps as u64 * u32::MAX as u64),
+                Algo::Nth => steps > 0 && n % steps == 0,
+            };
+
+            pattern[(n + offset) % length] = if hit { 1 } else { 0 };
+        }
+    }
+}
+
+impl From<i8> for Algo {
+    fn from(mut x: i8) -> Self {
+        use Algo::*;
+
+        while x < 0 {
+            x += Self::len() as i8;
+        }
+
+        match x % (Self::len() as i8) {
+            0 => Generative,
+            1 => Euclid,
+            2 => Density,
+            3 => Nth,
+            _ => panic!("Unhandled Algo number"),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Timing of the generative code, run on target before the main loop.
+//!
+//! Built with the `bench` feature. Each case is run a number of times, timed with the
+//! cycle counter and logged as µS per iteration. The state goes through the same
+//! operations as from the inputs, so the regenerate cost includes the bookkeeping
+//! around it.
+
+use alg::clock::Time;
+use cortex_m::peripheral::DWT;
+
+use crate::state::Oper;
+use crate::state::State;
+use crate::CPU_SPEED;
+
+/// Iterations per case.
+const ITERATIONS: u32 = 1000;
+
+pub fn run() {
+    info!("Bench {} iterations per case", ITERATIONS);
+
+    let mut state = State::new();
+    let mut now = Time::<{ CPU_SPEED }>::default();
+
+    // Every seed change regenerates all tracks.
+    time("regenerate", || {
+        now.count += 1;
+        state.update(now, core::iter::once(Oper::Seed(1)));
+        state.update(now, core::iter::once(Oper::Seed(-1)));
+    });
+
+    // An offset change only regenerates that track.
+    time("regenerate_track", || {
+        now.count += 1;
+        state.update(now, core::iter::once(Oper::Offset(0, 1)));
+        state.update(now, core::iter::once(Oper::Offset(0, -1)));
+    });
+
+    time("update_time", || {
+        now.count += 1000;
+        state.update_time(now);
+    });
+
+    // Fold the output into something logged, so it's not optimized away.
+    let mut acc = 0;
+    time("to_display", || {
+        acc ^= state.to_display().0[1];
+    });
+    trace!("Bench to_display acc: {}", acc);
+}
+
+fn time(name: &str, mut f: impl FnMut()) {
+    let start = DWT::get_cycle_count();
+
+    for _ in 0..ITERATIONS {
+        f();
+    }
+
+    let cycles = DWT::get_cycle_count().wrapping_sub(start);
+    let micros = cycles as f32 / (CPU_SPEED / 1_000_000) as f32 / ITERATIONS as f32;
+
+    info!("Bench {}: {:.02}µS", name, micros);
+}
@@ -0,0 +1,136 @@
+//! Interrupt driven clock and reset inputs.
+//!
+//! Polling the pins in the main loop means an edge is only seen when the loop gets
+//! around to it, which jitters the measured intervals and can miss short pulses
+//! when the loop is busy. Instead the edges are captured in an interrupt and
+//! timestamped with the cycle counter.
+
+use alg::clock::Time;
+use alg::input::DigitalInput;
+use alg::input::HiLo;
+use arrayvec::ArrayVec;
+use bsp::interrupt;
+use cortex_m::peripheral::DWT;
+use imxrt_hal::gpio::Input;
+use imxrt_hal::gpio::GPIO;
+use teensy4_bsp as bsp;
+
+use crate::inter::Interrupt;
+use crate::inter::InterruptConfiguration;
+use crate::lock::Lock;
+use crate::CPU_SPEED;
+
+// AD_B1_11 - GPIO1_IO27
+type ClkPin = GPIO<bsp::common::P21, Input>;
+// AD_B1_10 - GPIO1_IO26
+type RstPin = GPIO<bsp::common::P20, Input>;
+
+/// Captured edges as (pin level after the edge, cycle count).
+pub type EdgeReads = ArrayVec<(bool, u32), 16>;
+
+/// Attach interrupts to the clock and reset pins. Returns the inputs for clock and reset.
+pub fn setup_clock_interrupts(mut clk: ClkPin, mut rst: RstPin) -> (IrqDigitalIn, IrqDigitalIn) {
+    static mut INT: Option<(ClkPin, RstPin, Lock<EdgeReads>, Lock<EdgeReads>)> = None;
+
+    #[cortex_m_rt::interrupt]
+    fn GPIO1_Combined_16_31() {
+        // Take the timestamp first thing, before any other work.
+        let cycles = DWT::get_cycle_count();
+
+        cortex_m::interrupt::free(|cs| {
+            let (clk, rst, clk_reads, rst_reads) = unsafe { INT.as_mut().unwrap() };
+
+            if clk.is_interrupt_status() {
+                clk.clear_interrupt_status();
+                let mut reads = clk_reads.get(cs);
+                // Drop the edge rather than panic if the main loop is stalled.
+                let _ = reads.try_push((clk.is_set(), cycles));
+            }
+
+            if rst.is_interrupt_status() {
+                rst.clear_interrupt_status();
+                let mut reads = rst_reads.get(cs);
+                let _ = reads.try_push((rst.is_set(), cycles));
+            }
+        });
+    }
+
+    let clk_reads = Lock::new(EdgeReads::new());
+    let rst_reads = Lock::new(EdgeReads::new());
+
+    let clk_in = IrqDigitalIn::new(clk_reads.clone(), clk.is_set());
+    let rst_in = IrqDigitalIn::new(rst_reads.clone(), rst.is_set());
+
+    cortex_m::interrupt::free(|_cs| {
+        info!("setup clock interrupts");
+
+        clk.set_interrupt_configuration(InterruptConfiguration::EitherEdge);
+        clk.set_interrupt_enable(true);
+        clk.clear_interrupt_status();
+        rst.set_interrupt_configuration(InterruptConfiguration::EitherEdge);
+        rst.set_interrupt_enable(true);
+        rst.clear_interrupt_status();
+
+        unsafe {
+            INT = Some((clk, rst, clk_reads, rst_reads));
+        }
+
+        // Both pins map to the same interrupt.
+        unsafe { cortex_m::peripheral::NVIC::unmask(bsp::interrupt::GPIO1_Combined_16_31) };
+    });
+
+    (clk_in, rst_in)
+}
+
+/// Digital input fed by edges captured in an interrupt.
+///
+/// Each tick consumes at most one captured edge, and reports the level with the
+/// time the edge actually happened rather than the time of the tick.
+pub struct IrqDigitalIn {
+    reads: Lock<EdgeReads>,
+    high: bool,
+    at: Time<{ CPU_SPEED }>,
+}
+
+impl IrqDigitalIn {
+    fn new(reads: Lock<EdgeReads>, high: bool) -> Self {
+        IrqDigitalIn {
+            reads,
+            high,
+            at: Time::default(),
+        }
+    }
+}
+
+impl DigitalInput<{ CPU_SPEED }> for IrqDigitalIn {
+    fn tick(&mut self, now: Time<{ CPU_SPEED }>) -> HiLo<{ CPU_SPEED }> {
+        let next = cortex_m::interrupt::free(|cs| {
+            let mut reads = self.reads.get(cs);
+            if reads.is_empty() {
+                None
+            } else {
+                Some(reads.remove(0))
+            }
+        });
+
+        if let Some((high, cycles)) = next {
+            // The cycle counter wraps every ~7s at 600MHz, far longer than
+            // any edge waits in the queue.
+            let elapsed = DWT::get_cycle_count().wrapping_sub(cycles);
+
+            let mut at = now;
+            at.count -= elapsed as i64;
+
+            self.high = high;
+            self.at = at;
+        } else {
+            self.at = now;
+        }
+
+        if self.high {
+            HiLo::Hi(self.at)
+        } else {
+            HiLo::Lo(self.at)
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Abstraction over the segment display, so the readout hardware can be swapped.
+
+use cortex_m::interrupt::CriticalSection;
+
+use crate::max6958::Segs4;
+use crate::state::State;
+
+/// A 4 character segment display.
+pub trait SegmentDisplay {
+    type Error;
+
+    /// Show the segments and decimal points. Bit 0-3 of `dp` corresponds to digit 0-3.
+    fn show(&mut self, segs: Segs4, dp: u8, cs: &CriticalSection) -> Result<(), Self::Error>;
+
+    /// Set the intensity 0x00-0x3f.
+    fn set_intensity(&mut self, intensity: u8, cs: &CriticalSection) -> Result<(), Self::Error>;
+
+    /// Turn the display off/on, keeping the contents.
+    fn set_shutdown(&mut self, shutdown: bool, cs: &CriticalSection) -> Result<(), Self::Error>;
+}
+
+/// What the state shows on the display, kept to only write the display on a change.
+pub struct Readout {
+    segs: Segs4,
+    dp: u8,
+}
+
+impl Readout {
+    pub fn new() -> Self {
+        Readout {
+            segs: Segs4::new(),
+            dp: 0,
+        }
+    }
+
+    /// Take what the state shows. Whether that differs from before.
+    pub fn update(&mut self, state: &State) -> bool {
+        let segs = state.to_display();
+        let dp = state.to_display_dp();
+
+        if segs == self.segs && dp == self.dp {
+            return false;
+        }
+
+        self.segs = segs;
+        self.dp = dp;
+
+        true
+    }
+
+    /// Write the readout to a display.
+    pub fn show<D: SegmentDisplay>(
+        &self,
+        display: &mut D,
+        cs: &CriticalSection,
+    ) -> Result<(), D::Error> {
+        display.show(self.segs, self.dp, cs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use super::*;
+
+    /// Display remembering what it was last asked to show.
+    #[derive(Default)]
+    struct TestDisplay {
+        shown: Option<(Segs4, u8)>,
+    }
+
+    impl SegmentDisplay for TestDisplay {
+        type Error = Infallible;
+
+        fn show(&mut self, segs: Segs4, dp: u8, _: &CriticalSection) -> Result<(), Infallible> {
+            self.shown = Some((segs, dp));
+            Ok(())
+        }
+
+        fn set_intensity(&mut self, _: u8, _: &CriticalSection) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn set_shutdown(&mut self, _: bool, _: &CriticalSection) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn readout_on_change() {
+        // Nothing runs concurrently in the test.
+        let cs = unsafe { CriticalSection::new() };
+
+        let mut state = State::new();
+        let mut readout = Readout::new();
+        let mut display = TestDisplay::default();
+
+        assert!(readout.update(&state));
+        assert!(!readout.update(&state));
+
+        state.shift = true;
+        assert!(readout.update(&state));
+
+        readout.show(&mut display, &cs).unwrap();
+
+        let segs: Segs4 = "fn  ".into();
+        assert_eq!(display.shown, Some((segs, state.to_display_dp())));
+    }
+}
@@ -1,43 +1,56 @@
 //! Wrapper for all the errors.
 
+#[cfg(not(test))]
 use imxrt_hal::i2c;
+#[cfg(not(test))]
 use imxrt_hal::spi;
+#[cfg(not(test))]
 use imxrt_hal::spi::ModeError;
 
 #[derive(Debug)]
 pub enum Error {
+    #[cfg(not(test))]
     SpiClockSpeedError(spi::ClockSpeedError),
+    #[cfg(not(test))]
     I2CClockSpeedError(i2c::ClockSpeedError),
+    #[cfg(not(test))]
     SpiError(spi::Error),
+    #[cfg(not(test))]
     I2CError(i2c::Error),
+    #[cfg(not(test))]
     ModeError(ModeError),
     Other(&'static str),
 }
 
+#[cfg(not(test))]
 impl From<spi::ClockSpeedError> for Error {
     fn from(e: spi::ClockSpeedError) -> Self {
         Error::SpiClockSpeedError(e)
     }
 }
 
+#[cfg(not(test))]
 impl From<i2c::ClockSpeedError> for Error {
     fn from(e: i2c::ClockSpeedError) -> Self {
         Error::I2CClockSpeedError(e)
     }
 }
 
+#[cfg(not(test))]
 impl From<spi::Error> for Error {
     fn from(e: spi::Error) -> Self {
         Error::SpiError(e)
     }
 }
 
+#[cfg(not(test))]
 impl From<i2c::Error> for Error {
     fn from(e: i2c::Error) -> Self {
         Error::I2CError(e)
     }
 }
 
+#[cfg(not(test))]
 impl From<ModeError> for Error {
     fn from(e: ModeError) -> Self {
         Error::ModeError(e)
@@ -0,0 +1,727 @@
+//! The firmware proper, setting up the hardware and running the main loop.
+
+use alg::clock::Clock;
+use alg::clock::Time;
+use alg::input::DigitalInput;
+use bsp::hal::adc;
+use bsp::hal::ccm;
+use core::fmt::Debug;
+use core::sync::atomic::{AtomicU8, Ordering};
+use cortex_m::interrupt::CriticalSection;
+use cortex_m::peripheral::DWT;
+use embedded_hal::adc::OneShot;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi;
+use imxrt_hal::gpio::Output;
+use imxrt_hal::gpio::GPIO;
+use teensy4_bsp as bsp;
+
+#[cfg(feature = "bench")]
+use crate::bench;
+use crate::clock_irq;
+use crate::display::{Readout, SegmentDisplay};
+use crate::error::Error;
+use crate::input::ClickLockout;
+use crate::input::Inputs;
+use crate::input::LongPress;
+use crate::input::PinDigitalIn;
+use crate::input::SeedCurve;
+use crate::input::RESET_MIN_WIDTH;
+use crate::input::{accel_encoder, button, encoder};
+use crate::layout::{Ext, LAYOUT};
+#[cfg(feature = "encoder_led")]
+use crate::led;
+use crate::link::Link;
+use crate::lock::Lock;
+use crate::logging;
+use crate::max6958;
+use crate::mcp23s17;
+use crate::mcp23s17::Mcp23S17;
+use crate::mcp4728;
+use crate::output::Gate;
+use crate::output::Outputs;
+use crate::state::InputMode;
+use crate::state::OperQueue;
+use crate::state::State;
+use crate::supply;
+use crate::testpattern;
+use crate::timeutil::TimeExt;
+use crate::usbcmd;
+use crate::CPU_SPEED;
+
+/// Intensity of the display.
+const DISPLAY_INTENSITY: u8 = 40;
+
+/// Time to ramp up the display intensity when enabling it.
+const DISPLAY_RAMP: Time<{ CPU_SPEED }> = Time::from_millis(100);
+
+/// LED used to communicate panics etc.
+type LedPcbPin = GPIO<bsp::common::P5, Output>;
+
+static mut LED_PCB: Option<LedPcbPin> = None;
+
+/// The I2C bus shared by the display and DAC.
+type I2cBus = bsp::hal::i2c::I2C<bsp::hal::iomuxc::consts::U1>;
+
+/// DAC used to zero the CVs on panic.
+static mut DAC_PANIC: Option<mcp4728::Mcp4728<I2cBus>> = None;
+
+/// Inverted gates as a mask, bit 0 being gate 1, so a panic can leave them idle.
+static GATE_INVERT: AtomicU8 = AtomicU8::new(0);
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    if let Err(e) = do_run() {
+        panic!("main failed: {:?}", e);
+    }
+
+    unreachable!();
+}
+
+fn do_run() -> Result<(), Error> {
+    // this fails if there is no USB connected. To get it working,
+    // connect the USB and power cycle.
+    let usb_reader = logging::init_active();
+    let logging = usb_reader.is_some();
+    let mut usb_cmd = usb_reader.map(usbcmd::UsbCmd::new);
+
+    let mut p = bsp::Peripherals::take().unwrap();
+    let mut cp = cortex_m::Peripherals::take().unwrap();
+    let mut systick = bsp::SysTick::new(cp.SYST);
+
+    // Wait so we don't miss the first log message, crashes etc. No point
+    // in waiting when standalone.
+    if logging {
+        systick.delay(logging::STARTUP_DELAY_MS);
+    }
+
+    info!("Set clock frequency to: {:?}", ccm::PLL1::ARM_HZ);
+
+    // Set clock to the recommended 600 MHz.
+    p.ccm
+        .pll1
+        .set_arm_clock(ccm::PLL1::ARM_HZ, &mut p.ccm.handle, &mut p.dcdc);
+
+    info!("Enable trace and cycle counter");
+
+    // // needed for timer
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
+
+    let mut clock = Clock::<_, CPU_SPEED>::new(DWT::get_cycle_count);
+
+    let pins = bsp::t40::into_pins(p.iomuxc);
+
+    info!("Get gate pins");
+
+    let pin_gate1 = GPIO::new(pins.p0).output();
+    let pin_gate2 = GPIO::new(pins.p1).output();
+    let pin_gate3 = GPIO::new(pins.p2).output();
+    let pin_gate4 = GPIO::new(pins.p3).output();
+
+    info!("Get rst/clk pins");
+
+    // Reset and clock pins. Notice convention here. "clk" means the external
+    // clock signal and "clock" means our internal cycle clock.
+    let pin_rst = GPIO::new(pins.p20);
+    let pin_clk = GPIO::new(pins.p21);
+
+    // Edges are captured in an interrupt for precise timing.
+    let (clock_in, reset_in) = clock_irq::setup_clock_interrupts(pin_clk, pin_rst);
+
+    // Manual step advance trigger.
+    let pin_adv = GPIO::new(pins.p22);
+
+    // Interrupt pints for ext1 and ext2
+    // let ext1_irq = GPIO::new(pins.p8);
+    // let ext2_irq = GPIO::new(pins.p7);
+
+    let mut led_pcb = GPIO::new(pins.p5).output();
+    led_pcb.set();
+    unsafe { LED_PCB = Some(led_pcb) };
+
+    // 1.6E-5
+    // 16µS
+
+    let (_, _, _, spi4_builder) = p.spi.clock(
+        &mut p.ccm.handle,
+        ccm::spi::ClockSelect::Pll2,
+        ccm::spi::PrescalarSelect::LPSPI_PODF_5,
+    );
+
+    // Last reading to proces of io_ext1.
+    let mut io_ext1_read = 0;
+    // Last reading to process of io_ext2.
+    let mut io_ext2_read = 0;
+
+    let mut spi_io = spi4_builder.build(pins.p11, pins.p12, pins.p13);
+
+    // // From datasheet for MCP23S17 we see that max speed is 10MHz
+    spi_io.set_clock_speed(bsp::hal::spi::ClockSpeed(5_000_000))?;
+
+    spi_io.set_mode(spi::MODE_0)?;
+    spi_io.clear_fifo();
+
+    let spi_lock = Lock::new(spi_io);
+
+    let spi_cs_ext1 = GPIO::new(pins.p10).output();
+    let spi_cs_ext2 = GPIO::new(pins.p9).output();
+
+    let mut io_ext1 = mcp23s17::builder()
+        .enable_all_interrupts(mcp23s17::InterruptMode::CompareAgainstPrevious)
+        .set_all_pull_up(true)
+        .build(spi_lock.clone(), spi_cs_ext1)?;
+    let mut io_ext2 = mcp23s17::builder()
+        .enable_all_interrupts(mcp23s17::InterruptMode::CompareAgainstPrevious)
+        .set_all_pull_up(true)
+        .build(spi_lock.clone(), spi_cs_ext2)?;
+
+    // Third io expander driving the LEDs in the encoders.
+    #[cfg(feature = "encoder_led")]
+    let mut enc_leds = {
+        let spi_cs_ext3 = GPIO::new(pins.p6).output();
+        let io_ext3 = mcp23s17::builder()
+            .set_all_output()
+            .build(spi_lock.clone(), spi_cs_ext3)?;
+        led::EncoderLeds::new(io_ext3)
+    };
+
+    fn verify<E, I, C>(cs: &CriticalSection, io_ext: &mut Mcp23S17<I, C>) -> Result<(), Error>
+    where
+        I: Transfer<u16, Error = E>,
+        I: Write<u16, Error = E>,
+        C: OutputPin,
+        C::Error: Debug,
+    {
+        io_ext.verify_config(cs)?;
+        io_ext.read_int_cap(cs)?;
+        io_ext.read_inputs(cs)?;
+        Ok(())
+    }
+
+    cortex_m::interrupt::free(|cs| {
+        verify(cs, &mut io_ext1)?;
+        verify(cs, &mut io_ext2)?;
+        io_ext1_read = io_ext1.read_inputs(cs)?;
+        io_ext2_read = io_ext2.read_inputs(cs)?;
+        Ok::<_, Error>(())
+    })?;
+
+    // Holding the seed button while powering on does a factory reset. The button
+    // is pulled up, so held means low. Debounce by requiring it held over 50ms.
+    let mut factory_reset = true;
+    for _ in 0..10 {
+        let x = cortex_m::interrupt::free(|cs| match LAYOUT.seed.ext {
+            Ext::Ext1 => io_ext1.read_inputs(cs),
+            Ext::Ext2 => io_ext2.read_inputs(cs),
+        })?;
+        if x & LAYOUT.seed.btn != 0 {
+            factory_reset = false;
+            break;
+        }
+        systick.delay(5);
+    }
+
+    // Holding the length button while powering on plays the test pattern.
+    let x = cortex_m::interrupt::free(|cs| match LAYOUT.length.ext {
+        Ext::Ext1 => io_ext1.read_inputs(cs),
+        Ext::Ext2 => io_ext2.read_inputs(cs),
+    })?;
+    let test_pattern = x & LAYOUT.length.btn == 0;
+
+    // The supply rail through a divider, see supply.
+    let (adc1_builder, _) = p.adc.clock(&mut p.ccm.handle);
+    let mut adc1 = adc1_builder.build(adc::ClockSelect::default(), adc::ClockDivision::default());
+    let mut supply_in = adc::AnalogInput::new(pins.p16);
+
+    let (i2c1_builder, _, _, _) = p.i2c.clock(
+        &mut p.ccm.handle,
+        ccm::i2c::ClockSelect::OSC, // 24MHz
+        // TODO: Investigate what this is.
+        ccm::i2c::PrescalarSelect::DIVIDE_3,
+    );
+
+    // The return of "builder.build()" is a configured I2C master running at 100KHz.
+    let mut i2c = i2c1_builder.build(pins.p19, pins.p18);
+
+    // From datasheet MAX6958, serial max speed is 400KHz
+    i2c.set_clock_speed(bsp::hal::i2c::ClockSpeed::KHz400)?;
+
+    // Link to another unit on LPUART2, pins 14 (TX) and 15 (RX).
+    let uarts = p.uart.clock(
+        &mut p.ccm.handle,
+        ccm::uart::ClockSelect::OSC,
+        ccm::uart::PrescalarSelect::DIVIDE_1,
+    );
+    let uart2 = uarts
+        .uart2
+        .init(pins.p14, pins.p15, 115_200)
+        .map_err(|_| Error::Other("Failed to init link UART"))?;
+    let mut link = Link::new(uart2);
+
+    // let mut rnd = Rnd::new(1);
+    let i2c_lock = Lock::new(i2c);
+
+    let mut seg = max6958::Max6958::new(i2c_lock.clone(), max6958::Variant::A);
+    let mut dac = mcp4728::Mcp4728::new(i2c_lock.clone());
+    unsafe { DAC_PANIC = Some(mcp4728::Mcp4728::new(i2c_lock.clone())) };
+    // LDAC is tied low, so multi-write updates the outputs immediately.
+    dac.set_partial_write(true);
+
+    // At intensity 40 + scan limit 0123, we get 2mA per led segment.
+    // 8 segments * 2mA x 4 chars = 64mA for the display.
+    let mut intensity_ramp = max6958::IntensityRamp::new(DISPLAY_INTENSITY, DISPLAY_RAMP);
+
+    cortex_m::interrupt::free(|cs| {
+        // Start dark and ramp up in the main loop.
+        seg.set_intensity(0, cs)?;
+
+        seg.set_shutdown(false, cs)?;
+
+        seg.set_scan_limit(max6958::ScanLimit::Digit0123, cs)?;
+
+        Ok::<_, Error>(())
+    })?;
+
+    intensity_ramp.start(clock.now());
+
+    // The u16 is transferred as:
+    // [A7, A6, A5, A4,   A3, A2, A1, A0,   B7, B6, B5, B4,   B3, B2, B1, B0]
+    // See layout for the wiring of the controls.
+    let l = &LAYOUT;
+    let read = |ext: Ext| match ext {
+        Ext::Ext1 => &io_ext1_read,
+        Ext::Ext2 => &io_ext2_read,
+    };
+
+    let mut inputs = Inputs {
+        // Clock signal in. Inverted.
+        clock: clock_in.edge(),
+        // Last tick, since we want intervals.
+        clock_last: None,
+
+        // Reset signal in. Inverted.
+        reset: reset_in.edge(),
+        reset_start: None,
+        reset_min_width: RESET_MIN_WIDTH,
+        reset_held_tick: None,
+
+        // Step advance signal in. Inverted.
+        advance: PinDigitalIn(pin_adv).edge(),
+
+        // The seed curve takes the place of the accelerator.
+        seed: encoder(read(l.seed.ext), l.seed.pin_a, l.seed.pin_b),
+        seed_btn: button(read(l.seed.ext), l.seed.btn),
+        seed_held: false,
+        seed_click: false,
+        seed_lockout: ClickLockout::default(),
+        seed_curve: SeedCurve::default(),
+
+        length: encoder(read(l.length.ext), l.length.pin_a, l.length.pin_b),
+        length_btn: button(read(l.length.ext), l.length.btn),
+        length_press: LongPress::default(),
+        length_down: false,
+
+        offs_press: Default::default(),
+        step_press: Default::default(),
+        gate_tap: [None; 4],
+        offs_down: [false; 4],
+        step_down: [false; 4],
+        fill_held: [false; 4],
+
+        offs1: encoder(read(l.offs[0].ext), l.offs[0].pin_a, l.offs[0].pin_b),
+        offs1_btn: button(read(l.offs[0].ext), l.offs[0].btn),
+        step1: accel_encoder(read(l.step[0].ext), l.step[0].pin_a, l.step[0].pin_b),
+        step1_btn: button(read(l.step[0].ext), l.step[0].btn),
+
+        offs2: encoder(read(l.offs[1].ext), l.offs[1].pin_a, l.offs[1].pin_b),
+        offs2_btn: button(read(l.offs[1].ext), l.offs[1].btn),
+        step2: accel_encoder(read(l.step[1].ext), l.step[1].pin_a, l.step[1].pin_b),
+        step2_btn: button(read(l.step[1].ext), l.step[1].btn),
+
+        offs3: encoder(read(l.offs[2].ext), l.offs[2].pin_a, l.offs[2].pin_b),
+        offs3_btn: button(read(l.offs[2].ext), l.offs[2].btn),
+        step3: accel_encoder(read(l.step[2].ext), l.step[2].pin_a, l.step[2].pin_b),
+        step3_btn: button(read(l.step[2].ext), l.step[2].btn),
+
+        offs4: encoder(read(l.offs[3].ext), l.offs[3].pin_a, l.offs[3].pin_b),
+        offs4_btn: button(read(l.offs[3].ext), l.offs[3].btn),
+        step4: accel_encoder(read(l.step[3].ext), l.step[3].pin_a, l.step[3].pin_b),
+        step4_btn: button(read(l.step[3].ext), l.step[3].btn),
+    };
+
+    let mut outputs = Outputs {
+        playhead_last: 0,
+        lfo_above: [false; 4],
+        gate1: Gate::new(pin_gate1, 50),
+        gate2: Gate::new(pin_gate2, 50),
+        gate3: Gate::new(pin_gate3, 50),
+        gate4: Gate::new(pin_gate4, 50),
+    };
+
+    let mut start = clock.now();
+    let mut loop_count = 0_u32;
+    let mut last_time_update = start;
+    let mut last_display_update = start;
+    let mut readout = Readout::new();
+    let mut last_standby = false;
+    let mut last_ext_read = clock.now();
+    let mut last_supply_read = clock.now();
+    let mut last_gates = 0_u8;
+
+    let mut state = State::new();
+
+    if factory_reset {
+        info!("Factory reset");
+        state.factory_reset(clock.now());
+    }
+
+    let mut opers = OperQueue::new();
+
+    #[cfg(feature = "bench")]
+    bench::run();
+
+    if test_pattern {
+        info!("Test pattern, click length to exit");
+
+        let mut tp = testpattern::TestPattern::new(clock.now());
+        let mut last_dac = clock.now();
+        let mut released_at = None;
+
+        loop {
+            clock.tick();
+            let now = clock.now();
+
+            let (gs, cv) = tp.tick(now);
+            outputs.tick_gates(now, gs, &testpattern::STEP);
+
+            if now - last_dac < Time::from_millis(1) {
+                continue;
+            }
+            last_dac = now;
+
+            let x = cortex_m::interrupt::free(|cs| {
+                dac.set_channels(&cv, cs)?;
+                let x = match LAYOUT.length.ext {
+                    Ext::Ext1 => io_ext1.read_inputs(cs),
+                    Ext::Ext2 => io_ext2.read_inputs(cs),
+                }?;
+                Ok::<_, Error>(x)
+            })?;
+
+            // Exit on the next press, after letting go of the boot press. The
+            // release must be settled, so its bounce isn't taken as a press.
+            let held = x & LAYOUT.length.btn == 0;
+            if !held {
+                released_at.get_or_insert(now);
+            } else if let Some(at) = released_at {
+                if now - at > Time::from_millis(50) {
+                    break;
+                }
+                released_at = None;
+            }
+        }
+
+        outputs.panic(clock.now());
+        cortex_m::interrupt::free(|cs| dac.set_channels(&[Some(0); 4], cs))?;
+
+        info!("Test pattern done");
+    }
+
+    info!("Start main loop");
+
+    loop {
+        clock.tick();
+
+        let now = clock.now();
+
+        let time_lapsed = now - start;
+        if logging && time_lapsed >= Time::from_secs(10) {
+            // 2021-07-01 this is: 71_424_181
+            //  rotary enc decel   52_566_664
+            //  after locks etc:   11_904_273 0.84µS per loop
+            //  io_ext_change:     20_832_340 0.48µS
+            //  -- full LFO etc (now tests are with clock pulse)
+            //  first optimize:     3_590_341 2.79µS
+            //  minimize lfo upd:  14_310_145 0.70µS
+            //
+            // We know a reset happens roughly 800ns before the
+            // next clock pulse.
+            //
+            // ----+---------------_----------+---->
+            //     |               |   800ns  |
+            info!(
+                "{:.0}ms loop count: {}, {:.02}µS/loop",
+                time_lapsed.as_millis_f32(),
+                loop_count,
+                time_lapsed.as_micros_f32() / loop_count as f32
+            );
+            // The predicted interval is per step, which we count as a beat.
+            if let Some(bpm) = state.predicted.as_bpm(1) {
+                info!("Tempo: {:.01} BPM", bpm);
+            }
+            info!("State: {:#?}", state);
+            start = now;
+            loop_count = 0;
+        }
+
+        // This is quite expensive. By doing it every 10µs we are quite confident to
+        // do 4096 updates in the minimum length a track can be. Breaks down if
+        // the clock pulse is very high.
+        if now - last_time_update >= Time::from_micros(10) {
+            last_time_update = now;
+            state.update_time(now);
+        }
+
+        if now - last_supply_read >= supply::READ_INTERVAL {
+            last_supply_read = now;
+
+            if let Ok(x) = adc1.read(&mut supply_in) {
+                let mv = supply::millivolts(x);
+                let low = supply::is_low(mv, state.supply_low);
+
+                if low && !state.supply_low {
+                    // There is no storage yet. An early save of the settings goes here.
+                    warn!("Low supply: {}mV", mv);
+                } else if !low && state.supply_low {
+                    info!("Supply recovered: {}mV", mv);
+                }
+
+                state.supply_low = low;
+            }
+        }
+
+        let lfo_upd = state.tick_lfo(now);
+        let lfo_upd = state.dac_channels(&lfo_upd);
+
+        // A slewing DAC needs updates until it reaches the target.
+        let any_lfo_upd = lfo_upd.iter().any(|l| l.is_some()) || dac.is_slewing();
+
+        // set to true if we really have an io_ext change. that way
+        // we can avoid a gazillion tick() in inputs.tick().
+        let mut io_ext_change = false;
+
+        // Update the display. Only do this 100Hz, if needed. The run mode animation
+        // is throttled further, while edits stay snappy.
+        let display_interval = if *state.display_mode() == InputMode::Run {
+            Time::from_millis(50)
+        } else {
+            Time::from_millis(10)
+        };
+
+        let mut display_update = false;
+        if !state.standby && now - last_display_update >= display_interval {
+            last_display_update = now;
+            display_update = readout.update(&state);
+        }
+
+        // In standby we poll the inputs less frequently.
+        let ext_read_interval = if state.standby {
+            Time::from_millis(10)
+        } else {
+            Time::from_micros(300)
+        };
+
+        let do_ext_read = now - last_ext_read > ext_read_interval;
+        if do_ext_read {
+            last_ext_read = now;
+        }
+
+        let standby_change = state.standby != last_standby;
+        last_standby = state.standby;
+
+        if standby_change && !state.standby {
+            intensity_ramp.start(now);
+        }
+
+        let intensity = if state.standby {
+            None
+        } else {
+            intensity_ramp.tick(now)
+        };
+
+        // Panic is handled before the outputs tick, so gates resume on the next step.
+        let panic = state.take_panic();
+        if panic {
+            outputs.panic(now);
+        }
+
+        // A stopped clock may drive the gates low, leaving the CVs.
+        if state.take_gates_clear() {
+            outputs.panic(now);
+        }
+
+        #[cfg(feature = "encoder_led")]
+        let led_update = enc_leds.needs_update(&state);
+        #[cfg(not(feature = "encoder_led"))]
+        let led_update = false;
+
+        // We want to avoid taking the free lock as much as possible. It costs
+        // 8µS to take it, and this way we only take it if we really need to.
+        if any_lfo_upd
+            || display_update
+            || do_ext_read
+            || led_update
+            || standby_change
+            || panic
+            || intensity.is_some()
+        {
+            cortex_m::interrupt::free(|cs| {
+                //
+                if do_ext_read {
+                    {
+                        let x = io_ext1.read_inputs(cs)?;
+                        if x != io_ext1_read {
+                            io_ext1_read = x;
+                            io_ext_change = true;
+                        }
+                    }
+
+                    {
+                        let x = io_ext2.read_inputs(cs)?;
+                        if x != io_ext2_read {
+                            io_ext2_read = x;
+                            io_ext_change = true;
+                        }
+                    }
+                }
+
+                if standby_change {
+                    if !state.standby {
+                        // Dark before waking, the ramp takes it from here.
+                        seg.set_intensity(0, cs)?;
+                    }
+
+                    seg.set_shutdown(state.standby, cs)?;
+
+                    if state.standby {
+                        dac.set_channels(&[Some(0); 4], cs)?;
+                    } else {
+                        // Restore the DAC promptly on wake.
+                        dac.set_channels(&state.dac_values(), cs)?;
+                    }
+                }
+
+                if any_lfo_upd && !state.standby {
+                    dac.set_channels(&lfo_upd, cs)?;
+                }
+
+                if panic {
+                    dac.set_channels(&[Some(0); 4], cs)?;
+                }
+
+                if let Some(i) = intensity {
+                    seg.set_intensity(i, cs)?;
+                }
+
+                if display_update {
+                    readout.show(&mut seg, cs)?;
+                }
+
+                #[cfg(feature = "encoder_led")]
+                if led_update {
+                    enc_leds.update(&state, cs)?;
+                }
+
+                Ok::<_, Error>(())
+            })?;
+        }
+
+        // Read all potential input and turn it into operations.
+        inputs.tick(now, &mut opers, io_ext_change);
+
+        // Commands from a computer.
+        if let Some(usb_cmd) = &mut usb_cmd {
+            usb_cmd.tick(&mut opers, &mut state);
+        }
+
+        // Send or receive the transport of a linked unit.
+        link.tick(now, state.link_role, &mut opers, state.params.seed);
+
+        // Current length of operations.
+        let len = opers.len();
+
+        if len > 0 {
+            // Apply the operations to the state.
+            state.update(now, opers.drain(0..len));
+        }
+
+        // Update output gates.
+        outputs.tick(now, &state);
+
+        GATE_INVERT.store(outputs.invert_mask(), Ordering::Relaxed);
+
+        // Propagate output gate states to LFOs.
+        let gates = outputs.gate_mask();
+        for (i, lfo) in state.lfo.iter_mut().enumerate() {
+            lfo.set_gate_high(gates & (1 << i) != 0);
+        }
+
+        // Latch the step values of the channels whose gate rose.
+        state.latch_step_values(gates & !last_gates);
+        last_gates = gates;
+
+        loop_count += 1;
+    }
+}
+
+#[panic_handler]
+fn panic(p: &core::panic::PanicInfo) -> ! {
+    // since usb debugging requires the interrupts to work, we re-enable them here.
+    // this should be safe since the "main" stack pointer is gone.
+    unsafe { cortex_m::interrupt::enable() };
+
+    error!("{:?}", p);
+
+    safe_outputs();
+
+    // Might as well take it, we're not going to resume.
+    let mut led = unsafe { LED_PCB.take().unwrap() };
+
+    loop {
+        led.clear();
+        delay(1);
+        led.set();
+        delay(1);
+    }
+}
+
+/// Drive the gates idle and zero the DAC, as far as we can after a panic.
+///
+/// The drivers owning the gate pins are unreachable, so the pins are cleared, or
+/// set for inverted gates, by writing the GPIO registers directly. Nothing else will
+/// touch them after this, so it doesn't matter that it goes behind the drivers' backs.
+///
+/// The DAC goes through a second driver instance sharing the I2C lock. That is
+/// best effort: if the panic happened mid transfer the bus may be stuck, in which
+/// case the write fails and we carry on to the blink loop regardless.
+fn safe_outputs() {
+    use imxrt_ral as ral;
+
+    // An idle inverted gate is high.
+    let invert = GATE_INVERT.load(Ordering::Relaxed);
+    let bit = |gate: u8, pin: u32| if invert & 1 << gate != 0 { 0 } else { 1 << pin };
+    let inv = |gate: u8, pin: u32| if invert & 1 << gate != 0 { 1 << pin } else { 0 };
+
+    unsafe {
+        // Gate 1 is p0, GPIO1_IO03. Gate 2 is p1, GPIO1_IO02.
+        ral::write_reg!(ral::gpio, ral::gpio::GPIO1, DR_CLEAR, bit(0, 3) | bit(1, 2));
+        ral::write_reg!(ral::gpio, ral::gpio::GPIO1, DR_SET, inv(0, 3) | inv(1, 2));
+        // Gate 3 is p2, GPIO4_IO04. Gate 4 is p3, GPIO4_IO05.
+        ral::write_reg!(ral::gpio, ral::gpio::GPIO4, DR_CLEAR, bit(2, 4) | bit(3, 5));
+        ral::write_reg!(ral::gpio, ral::gpio::GPIO4, DR_SET, inv(2, 4) | inv(3, 5));
+    }
+
+    if let Some(dac) = unsafe { DAC_PANIC.as_mut() } {
+        cortex_m::interrupt::free(|cs| {
+            dac.set_channels(&[Some(0); 4], cs).ok();
+        });
+    }
+}
+
+fn delay(factor: u32) {
+    for _ in 0..(factor * 50_000_000) {
+        core::hint::spin_loop();
+    }
+}
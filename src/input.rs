@@ -1,17 +1,37 @@
 use alg::clock::Time;
+use alg::encoder::BitmaskQuadratureSource;
+use alg::encoder::Encoder;
+use alg::encoder::EncoderAccelerator;
+use alg::input::BitmaskDigitalInput;
 use alg::input::DeltaInput;
 use alg::input::DigitalInput;
 use alg::input::Edge;
 use alg::input::EdgeInput;
+#[cfg(not(test))]
 use alg::input::HiLo;
+#[cfg(not(test))]
 use bsp::hal::gpio::{Input, GPIO};
+#[cfg(not(test))]
 use imxrt_hal::iomuxc::gpio::Pin;
+#[cfg(not(test))]
 use teensy4_bsp as bsp;
 
 use crate::state::Oper;
 use crate::state::OperQueue;
 use crate::CPU_SPEED;
 
+/// Default minimum time the reset must be active to be honored. Shorter pulses
+/// are considered spikes. Legitimate reset pulses are typically a few ms.
+pub const RESET_MIN_WIDTH: Time<{ CPU_SPEED }> = Time::from_micros(500);
+
+/// How long a button must be held to be a long press rather than a click.
+const LONG_PRESS: Time<{ CPU_SPEED }> = Time::from_millis(800);
+
+/// How long after a click further presses of the same button are ignored. A bouncy
+/// button can otherwise register twice and skip past the intended menu. Any double
+/// click detection must use a window longer than this.
+const CLICK_LOCKOUT: Time<{ CPU_SPEED }> = Time::from_millis(150);
+
 /// Holder of all hardware input.
 ///
 /// The type parameters here looks rather nuts. The reason is that we want to hide all
@@ -19,6 +39,7 @@ use crate::CPU_SPEED;
 pub struct Inputs<
     Digi1,
     Digi2,
+    Digi3,
     RSeed,
     RSeedBtn,
     RLen,
@@ -43,12 +64,45 @@ pub struct Inputs<
     pub clock: Digi1,
     pub clock_last: Option<Time<{ CPU_SPEED }>>,
     pub reset: Digi2,
+    /// When the currently active reset started, if not yet honored.
+    pub reset_start: Option<Time<{ CPU_SPEED }>>,
+    /// Minimum time the reset must be active to be honored.
+    pub reset_min_width: Time<{ CPU_SPEED }>,
+    /// Clock tick held back while waiting to see if a reset is honored.
+    pub reset_held_tick: Option<Oper>,
+    /// Manual step advance.
+    pub advance: Digi3,
 
     pub seed: RSeed,
     pub seed_btn: RSeedBtn,
+    /// Whether the seed button is held down. Used for combos.
+    pub seed_held: bool,
+    /// Whether releasing the seed button clicks. Not after a shift combo, or when the
+    /// press was locked out.
+    pub seed_click: bool,
+    /// Ignores the bounce of a seed click.
+    pub seed_lockout: ClickLockout,
+    /// Speeds up sustained fast turns of the seed.
+    pub seed_curve: SeedCurve,
 
     pub length: RLen,
     pub length_btn: RLenBtn,
+    pub length_press: LongPress,
+    /// Whether the length button is physically down, for the freeze combo.
+    pub length_down: bool,
+
+    /// Click or long press of the offset buttons.
+    pub offs_press: [LongPress; 4],
+    /// Click or long press of the steps buttons.
+    pub step_press: [LongPress; 4],
+    /// When a steps button was pressed while holding seed, to tap in the gate length.
+    pub gate_tap: [Option<Time<{ CPU_SPEED }>>; 4],
+    /// Whether the offset buttons are physically down, long press or not.
+    pub offs_down: [bool; 4],
+    /// Whether the steps buttons are physically down, long press or not.
+    pub step_down: [bool; 4],
+    /// Whether the offset and steps buttons of a track are held together for the fill.
+    pub fill_held: [bool; 4],
 
     pub offs1: Roffs1,
     pub offs1_btn: Roffs1Btn,
@@ -74,6 +128,7 @@ pub struct Inputs<
 impl<
         Digi1,
         Digi2,
+        Digi3,
         RSeed,
         RSeedBtn,
         RLen,
@@ -98,6 +153,7 @@ impl<
     Inputs<
         Digi1,
         Digi2,
+        Digi3,
         RSeed,
         RSeedBtn,
         RLen,
@@ -122,6 +178,7 @@ impl<
 where
     Digi1: EdgeInput<{ CPU_SPEED }>,
     Digi2: EdgeInput<{ CPU_SPEED }>,
+    Digi3: EdgeInput<{ CPU_SPEED }>,
     RSeed: DeltaInput<{ CPU_SPEED }>,
     RSeedBtn: EdgeInput<{ CPU_SPEED }>,
     RLen: DeltaInput<{ CPU_SPEED }>,
@@ -143,29 +200,103 @@ where
     RStep4: DeltaInput<{ CPU_SPEED }>,
     RStep4Btn: EdgeInput<{ CPU_SPEED }>,
 {
+    /// Turn the hardware input into operations.
+    ///
+    /// The seed button is the shift. While it's held, the controls map to secondary
+    /// parameters:
+    ///
+    /// | Control      | Primary                | Shift held               |
+    /// |--------------|------------------------|--------------------------|
+    /// | Seed turn    | seed                   | seed in coarse steps     |
+    /// | Length turn  | pattern length / menu  | shift all offsets        |
+    /// | Length click | length menus           | panic                    |
+    /// | Offset turn  | track offset / menu    | burst chance             |
+    /// | Offset press | track menus            | clear performance        |
+    /// | Steps turn   | track steps / menu     | gate length              |
+    /// | Steps hold   | click or long press    | tap in the gate length   |
+    ///
+    /// Holding length and pressing seed toggles the freeze of the pattern.
     pub fn tick(&mut self, now: Time<{ CPU_SPEED }>, todo: &mut OperQueue, io_ext_change: bool) {
         // Reset input
         // Deliberately read reset before clock, since if we for some reason end up
         // reading both reset and clock in the same cycle, we must handle the reset
         // before the clock pulse.
+        let mut did_reset = false;
         {
             let x = self.reset.tick(now);
-            // falling since inverted
-            if let Some(Edge::Falling(_)) = x {
-                todo.push(Oper::Reset);
+            match x {
+                // falling since inverted
+                Some(Edge::Falling(start)) => {
+                    self.reset_start = Some(start);
+                }
+
+                // reset ended before it was honored, it's a spike.
+                Some(Edge::Rising(_)) => {
+                    if self.reset_start.take().is_some() {
+                        trace!("Reject reset spike");
+                        if let Some(tick) = self.reset_held_tick.take() {
+                            push(todo, tick);
+                        }
+                    }
+                }
+
+                _ => {}
+            }
+
+            if let Some(start) = self.reset_start {
+                if now - start >= self.reset_min_width {
+                    self.reset_start = None;
+                    push(todo, Oper::Reset);
+                    did_reset = true;
+                }
             }
         }
 
         // Clock input
         {
             let x = self.clock.tick(now);
-            // falling since inverted
-            if let Some(Edge::Falling(tick)) = x {
-                if let Some(last) = self.clock_last {
-                    let interval = tick - last;
-                    todo.push(Oper::Tick(interval));
+            match x {
+                // falling since inverted
+                Some(Edge::Falling(tick)) => {
+                    if let Some(last) = self.clock_last {
+                        // If reset and clock coincide, the interval to the pre-reset clock
+                        // is bogus and must not be fed to the tempo detection.
+                        let interval = if did_reset { None } else { Some(tick - last) };
+
+                        if self.reset_start.is_some() {
+                            // Hold the tick until we know whether the reset is honored.
+                            self.reset_held_tick = Some(Oper::Tick(tick, interval));
+                        } else {
+                            push(todo, Oper::Tick(tick, interval));
+                        }
+                    }
+                    self.clock_last = Some(tick);
                 }
-                self.clock_last = Some(tick);
+
+                // rising is the end of the clock pulse, which gives us the width.
+                Some(Edge::Rising(end)) => {
+                    if let Some(last) = self.clock_last {
+                        push(todo, Oper::ClockWidth(end - last));
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        // A held tick after an honored reset. The interval is bogus.
+        if did_reset {
+            if let Some(Oper::Tick(at, _)) = self.reset_held_tick.take() {
+                push(todo, Oper::Tick(at, None));
+            }
+        }
+
+        // Manual step advance
+        {
+            let x = self.advance.tick(now);
+            // falling since inverted
+            if let Some(Edge::Falling(_)) = x {
+                push(todo, Oper::Advance);
             }
         }
 
@@ -174,7 +305,13 @@ where
         {
             let x = self.seed.tick(now);
             if x != 0 {
-                todo.push(Oper::Seed(x));
+                if self.seed_held {
+                    self.seed_click = false;
+                    push(todo, Oper::SeedCoarse(x));
+                } else {
+                    let x = self.seed_curve.apply(now, x);
+                    push(todo, Oper::Seed(x));
+                }
             }
         }
 
@@ -183,57 +320,84 @@ where
         {
             let x = self.step1.tick(now);
             if x != 0 {
-                todo.push(Oper::Steps(0, x));
+                push(todo, self.steps_turn(0, x));
             }
 
             let e = self.step1_btn.tick(now);
-            if let Some(Edge::Rising(_)) = e {
-                todo.push(Oper::StepsClick(0));
-            }
+            self.step_button(0, now, e, todo);
         }
         {
             let x = self.step2.tick(now);
             if x != 0 {
-                todo.push(Oper::Steps(1, x));
+                push(todo, self.steps_turn(1, x));
             }
 
             let e = self.step2_btn.tick(now);
-            if let Some(Edge::Rising(_)) = e {
-                todo.push(Oper::StepsClick(1));
-            }
+            self.step_button(1, now, e, todo);
         }
         {
             let x = self.step3.tick(now);
             if x != 0 {
-                todo.push(Oper::Steps(2, x));
+                push(todo, self.steps_turn(2, x));
             }
 
             let e = self.step3_btn.tick(now);
-            if let Some(Edge::Rising(_)) = e {
-                todo.push(Oper::StepsClick(2));
-            }
+            self.step_button(2, now, e, todo);
         }
         {
             let x = self.step4.tick(now);
             if x != 0 {
-                todo.push(Oper::Steps(3, x));
+                push(todo, self.steps_turn(3, x));
             }
 
             let e = self.step4_btn.tick(now);
-            if let Some(Edge::Rising(_)) = e {
-                todo.push(Oper::StepsClick(3));
-            }
+            self.step_button(3, now, e, todo);
         }
 
-        // All below this line is about io_ext chip changes. Early return if there are no changes.
+        // All below this line is about io_ext chip changes. Early return if there are no changes,
+        // but long presses must be timed even when nothing changes.
         if !io_ext_change {
+            if let Some(Press::Long) = self.length_press.tick(now, None) {
+                push(todo, Oper::LengthDefault);
+            }
+            for i in 0..4 {
+                if let Some(Press::Long) = self.offs_press[i].tick(now, None) {
+                    push(todo, Oper::OffsetDefault(i));
+                }
+            }
             return;
         }
 
         {
             let e = self.seed_btn.tick(now);
-            if let Some(Edge::Rising(_)) = e {
-                todo.push(Oper::SeedClick);
+            match e {
+                // Holding length and pressing seed is the freeze combo. Neither button
+                // clicks on release.
+                Some(Edge::Rising(_)) if self.length_down => {
+                    self.seed_held = true;
+                    self.seed_click = false;
+                    self.length_press.cancel();
+                    push(todo, Oper::Shift(true));
+                    push(todo, Oper::Freeze);
+                }
+                Some(Edge::Rising(at)) => {
+                    // Held even if the click is locked out, since it still is pressed.
+                    self.seed_held = true;
+                    self.seed_click = self.seed_lockout.accept(at);
+                    push(todo, Oper::Shift(true));
+                }
+                Some(Edge::Falling(at)) => {
+                    self.seed_held = false;
+                    push(todo, Oper::Shift(false));
+
+                    // Clicks on release, since until then the press might become a combo.
+                    if self.seed_click {
+                        self.seed_click = false;
+                        self.seed_lockout.clicked(at);
+                        push(todo, Oper::SeedClick);
+                    }
+                }
+                _ => {}
             }
         }
 
@@ -241,14 +405,30 @@ where
         {
             let x = self.length.tick(now);
             if x != 0 {
-                todo.push(Oper::Length(x));
+                // Holding seed and turning length shifts all offsets.
+                if self.seed_held {
+                    self.seed_click = false;
+                    push(todo, Oper::OffsetAll(x));
+                } else {
+                    push(todo, Oper::Length(x));
+                }
             }
         }
 
         {
             let e = self.length_btn.tick(now);
-            if let Some(Edge::Rising(_)) = e {
-                todo.push(Oper::LengthClick);
+            track_down(&mut self.length_down, e);
+            match e {
+                // Holding seed and clicking length is the panic combo. Straight away on press.
+                Some(Edge::Rising(_)) if self.seed_held => {
+                    self.seed_click = false;
+                    push(todo, Oper::Panic);
+                }
+                _ => match self.length_press.tick(now, e) {
+                    Some(Press::Click) => push(todo, Oper::LengthClick),
+                    Some(Press::Long) => push(todo, Oper::LengthDefault),
+                    None => {}
+                },
             }
         }
 
@@ -256,54 +436,419 @@ where
         {
             let x = self.offs1.tick(now);
             if x != 0 {
-                todo.push(Oper::Offset(0, x));
+                push(todo, self.offset_turn(0, x));
             }
 
             let e = self.offs1_btn.tick(now);
-            if let Some(Edge::Rising(_)) = e {
-                todo.push(Oper::OffsetClick(0));
-            }
+            self.offset_button(0, now, e, todo);
         }
         {
             let x = self.offs2.tick(now);
             if x != 0 {
-                todo.push(Oper::Offset(1, x));
+                push(todo, self.offset_turn(1, x));
             }
 
             let e = self.offs2_btn.tick(now);
-            if let Some(Edge::Rising(_)) = e {
-                todo.push(Oper::OffsetClick(1));
-            }
+            self.offset_button(1, now, e, todo);
         }
         {
             let x = self.offs3.tick(now);
             if x != 0 {
-                todo.push(Oper::Offset(2, x));
+                push(todo, self.offset_turn(2, x));
             }
 
             let e = self.offs3_btn.tick(now);
-            if let Some(Edge::Rising(_)) = e {
-                todo.push(Oper::OffsetClick(2));
-            }
+            self.offset_button(2, now, e, todo);
         }
         {
             let x = self.offs4.tick(now);
             if x != 0 {
-                todo.push(Oper::Offset(3, x));
+                push(todo, self.offset_turn(3, x));
             }
 
             let e = self.offs4_btn.tick(now);
-            if let Some(Edge::Rising(_)) = e {
-                todo.push(Oper::OffsetClick(3));
+            self.offset_button(3, now, e, todo);
+        }
+    }
+
+    /// A turn of a track offset, burst chance with shift held.
+    fn offset_turn(&mut self, i: usize, x: i8) -> Oper {
+        if self.seed_held {
+            self.seed_click = false;
+            Oper::BurstChance(i, x)
+        } else {
+            Oper::Offset(i, x)
+        }
+    }
+
+    /// A turn of a track steps, gate length with shift held.
+    fn steps_turn(&mut self, i: usize, x: i8) -> Oper {
+        if self.seed_held {
+            self.seed_click = false;
+            Oper::GateLenDelta(i, x)
+        } else {
+            Oper::Steps(i, x)
+        }
+    }
+
+    /// Holding the offset and steps buttons of a track together plays its fill for
+    /// as long as both are held. Neither press then clicks or long presses. The other
+    /// button counts as held also after its long press fired.
+    ///
+    /// Returns whether the edge was taken by the fill.
+    fn fill_button(
+        &mut self,
+        i: usize,
+        other_down: bool,
+        e: Option<Edge<{ CPU_SPEED }>>,
+        todo: &mut OperQueue,
+    ) -> bool {
+        match e {
+            Some(Edge::Rising(_)) if other_down => {
+                self.offs_press[i].cancel();
+                self.step_press[i].cancel();
+                self.fill_held[i] = true;
+                push(todo, Oper::Fill(i, true));
+                true
             }
+            Some(Edge::Falling(_)) if self.fill_held[i] => {
+                self.fill_held[i] = false;
+                push(todo, Oper::Fill(i, false));
+                true
+            }
+            _ => false,
         }
     }
+
+    fn offset_button(
+        &mut self,
+        i: usize,
+        now: Time<{ CPU_SPEED }>,
+        e: Option<Edge<{ CPU_SPEED }>>,
+        todo: &mut OperQueue,
+    ) {
+        let other_down = self.step_down[i];
+        track_down(&mut self.offs_down[i], e);
+
+        // Holding seed and pressing any offset button clears the performance settings.
+        // The press is not fed on, so there is no click on release.
+        if let Some(Edge::Rising(_)) = e {
+            if self.seed_held {
+                self.seed_click = false;
+                push(todo, Oper::ClearPerformance);
+                return;
+            }
+        }
+
+        if self.fill_button(i, other_down, e, todo) {
+            return;
+        }
+
+        match self.offs_press[i].tick(now, e) {
+            Some(Press::Click) => push(todo, Oper::OffsetClick(i)),
+            Some(Press::Long) => push(todo, Oper::OffsetDefault(i)),
+            None => {}
+        }
+    }
+
+    fn step_button(
+        &mut self,
+        i: usize,
+        now: Time<{ CPU_SPEED }>,
+        e: Option<Edge<{ CPU_SPEED }>>,
+        todo: &mut OperQueue,
+    ) {
+        let other_down = self.offs_down[i];
+        track_down(&mut self.step_down[i], e);
+
+        // Holding seed and holding a steps button taps in the gate length as the time held.
+        match e {
+            Some(Edge::Rising(at)) if self.seed_held => {
+                self.seed_click = false;
+                self.gate_tap[i] = Some(at);
+                return;
+            }
+            Some(Edge::Falling(end)) => {
+                if let Some(start) = self.gate_tap[i].take() {
+                    push(todo, Oper::GateLen(i, end - start));
+                    return;
+                }
+            }
+            _ => {}
+        }
+
+        if self.fill_button(i, other_down, e, todo) {
+            return;
+        }
+
+        match self.step_press[i].tick(now, e) {
+            Some(Press::Click) => push(todo, Oper::StepsClick(i)),
+            Some(Press::Long) => push(todo, Oper::StepsDefault(i)),
+            None => {}
+        }
+    }
+}
+
+/// Follow the physical state of a button from its edges.
+fn track_down(down: &mut bool, e: Option<Edge<{ CPU_SPEED }>>) {
+    match e {
+        Some(Edge::Rising(_)) => *down = true,
+        Some(Edge::Falling(_)) => *down = false,
+        None => {}
+    }
+}
+
+/// Number of detents per revolution the parameter changes are tuned for.
+pub const REFERENCE_DETENTS: i16 = 24;
+
+/// Detents per revolution of the hardware encoders. Typical values are
+/// 12, 20 or 24. Deltas are scaled to give the same feel regardless.
+pub const ENCODER_DETENTS: i16 = 24;
+
+/// Encoder on an io expander. `pin_a` and `pin_b` are bitmasks in the expander read.
+pub fn encoder(read: &u16, pin_a: u16, pin_b: u16) -> impl DeltaInput<{ CPU_SPEED }> {
+    DetentScale::new(
+        Encoder::new(BitmaskQuadratureSource::new(read, pin_a, pin_b)),
+        ENCODER_DETENTS,
+    )
+}
+
+/// Accelerated encoder on an io expander. `pin_a` and `pin_b` are bitmasks in
+/// the expander read.
+pub fn accel_encoder(read: &u16, pin_a: u16, pin_b: u16) -> impl DeltaInput<{ CPU_SPEED }> {
+    DetentScale::new(
+        EncoderAccelerator::new(Encoder::new(BitmaskQuadratureSource::new(
+            read, pin_a, pin_b,
+        ))),
+        ENCODER_DETENTS,
+    )
+}
+
+/// Debounced button on an io expander. `pin` is a bitmask in the expander read.
+pub fn button(read: &u16, pin: u16) -> impl EdgeInput<{ CPU_SPEED }> {
+    BitmaskDigitalInput::new(read, pin).debounce().edge()
+}
+
+/// Turns within this time of each other count as one sustained turn.
+const SEED_CURVE_GAP: Time<{ CPU_SPEED }> = Time::from_millis(40);
+
+/// Seed sensitivity curve. Small twists move the seed by 1, while a sustained fast
+/// turn moves it by 10 and then 100 per detent, to cross the 0-9999 range quickly.
+#[derive(Debug, Default)]
+pub struct SeedCurve {
+    /// Last time the encoder moved.
+    last: Time<{ CPU_SPEED }>,
+    /// Detents in the current sustained turn.
+    run: u16,
+}
+
+impl SeedCurve {
+    /// Scale the delta of the encoder by the speed of the turn.
+    pub fn apply(&mut self, now: Time<{ CPU_SPEED }>, x: i8) -> i16 {
+        if now - self.last < SEED_CURVE_GAP {
+            self.run = self.run.saturating_add(x.unsigned_abs() as u16);
+        } else {
+            self.run = x.unsigned_abs() as u16;
+        }
+        self.last = now;
+
+        let mult = match self.run {
+            0..=11 => 1,
+            12..=35 => 10,
+            _ => 100,
+        };
+
+        x as i16 * mult
+    }
+}
+
+/// Largest merged delta of an encoder. The handlers add deltas to small i8 indexes
+/// and wrap them by adding the length once, which only holds for small deltas.
+const MERGE_MAX: i8 = 16;
+
+/// Push an operation without ever overflowing the queue.
+///
+/// Encoder deltas are merged into a queued delta for the same control, so a burst
+/// of input changes takes one slot per control. Merging stops at anything that
+/// isn't a delta, such as a click, to keep the order of turns and clicks. A merged
+/// delta never grows past `MERGE_MAX`, beyond that the delta is queued on its own.
+/// Should the queue still be full, the operation is dropped, since a lost input is
+/// better than a panic.
+fn push(todo: &mut OperQueue, oper: Oper) {
+    for o in todo.iter_mut().rev() {
+        let acc = match (o, &oper) {
+            // The only wider delta, merged on its own.
+            (Oper::Seed(a), Oper::Seed(b)) => {
+                *a = a.saturating_add(*b);
+                return;
+            }
+            (Oper::SeedCoarse(a), Oper::SeedCoarse(b)) => Some((a, b)),
+            (Oper::Length(a), Oper::Length(b)) => Some((a, b)),
+            (Oper::OffsetAll(a), Oper::OffsetAll(b)) => Some((a, b)),
+            (Oper::Offset(t1, a), Oper::Offset(t2, b)) if t1 == t2 => Some((a, b)),
+            (Oper::Steps(t1, a), Oper::Steps(t2, b)) if t1 == t2 => Some((a, b)),
+            (Oper::BurstChance(t1, a), Oper::BurstChance(t2, b)) if t1 == t2 => Some((a, b)),
+            (Oper::GateLenDelta(t1, a), Oper::GateLenDelta(t2, b)) if t1 == t2 => Some((a, b)),
+            // Deltas of other controls can be passed over.
+            (Oper::Seed(_), _)
+            | (Oper::SeedCoarse(_), _)
+            | (Oper::Length(_), _)
+            | (Oper::OffsetAll(_), _)
+            | (Oper::Offset(_, _), _)
+            | (Oper::Steps(_, _), _)
+            | (Oper::BurstChance(_, _), _)
+            | (Oper::GateLenDelta(_, _), _) => continue,
+            _ => break,
+        };
+
+        if let Some((a, b)) = acc {
+            let n = *a as i16 + *b as i16;
+
+            if n.abs() > MERGE_MAX as i16 {
+                break;
+            }
+
+            *a = n as i8;
+            return;
+        }
+    }
+
+    if let Err(e) = todo.try_push(oper) {
+        warn!("Operation queue full, dropping: {:?}", e.element());
+    }
+}
+
+/// Result of a button press.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Press {
+    Click,
+    Long,
+}
+
+/// Tells a click from a long press of a button.
+///
+/// A click is emitted when the button is released, since until then it might become
+/// a long press. A long press is emitted as soon as the button has been held for
+/// `LONG_PRESS`, without waiting for the release.
+#[derive(Debug, Default)]
+pub struct LongPress {
+    /// When the button went down, until it is either released or a long press.
+    down: Option<Time<{ CPU_SPEED }>>,
+    /// Ignores the bounce of a click.
+    lockout: ClickLockout,
+}
+
+impl LongPress {
+    /// Forget the current press, so that it neither clicks nor long presses.
+    pub fn cancel(&mut self) {
+        self.down = None;
+    }
+
+    /// Feed the edge of the button, if any. Must also be called without edges to
+    /// time the long press.
+    pub fn tick(
+        &mut self,
+        now: Time<{ CPU_SPEED }>,
+        edge: Option<Edge<{ CPU_SPEED }>>,
+    ) -> Option<Press> {
+        match edge {
+            Some(Edge::Rising(at)) => {
+                // A press locked out leaves down empty, so its release is no click.
+                if self.lockout.accept(at) {
+                    self.down = Some(at);
+                }
+            }
+            Some(Edge::Falling(at)) => {
+                // No click after a long press, since that already took down.
+                if self.down.take().is_some() {
+                    self.lockout.clicked(at);
+                    return Some(Press::Click);
+                }
+            }
+            None => {}
+        }
+
+        if let Some(at) = self.down {
+            if now - at >= LONG_PRESS {
+                self.down = None;
+                return Some(Press::Long);
+            }
+        }
+
+        None
+    }
+}
+
+/// Ignores presses straight after a click, such as a bouncy button registering
+/// twice. See `CLICK_LOCKOUT`.
+#[derive(Debug, Default)]
+pub struct ClickLockout {
+    clicked_at: Option<Time<{ CPU_SPEED }>>,
+}
+
+impl ClickLockout {
+    /// Whether a press at the time is a new press, rather than the bounce of a click.
+    pub fn accept(&self, at: Time<{ CPU_SPEED }>) -> bool {
+        self.clicked_at
+            .map(|c| at - c >= CLICK_LOCKOUT)
+            .unwrap_or(true)
+    }
+
+    pub fn clicked(&mut self, at: Time<{ CPU_SPEED }>) {
+        self.clicked_at = Some(at);
+    }
+}
+
+/// Scales the deltas of an encoder to give the same feel per revolution
+/// regardless of the number of detents of the hardware encoder.
+///
+/// Sub-detent changes are accumulated and emitted as whole units.
+///
+/// Typical values for `detents` are 12, 20 or 24. A 24 detent encoder gives
+/// one unit per detent, a 12 detent encoder two units per detent.
+pub struct DetentScale<D> {
+    inner: D,
+    detents: i16,
+    acc: i16,
+}
+
+impl<D> DetentScale<D> {
+    pub fn new(inner: D, detents: i16) -> Self {
+        assert!(detents > 0);
+        DetentScale {
+            inner,
+            detents,
+            acc: 0,
+        }
+    }
+}
+
+impl<D, const CLK: u32> DeltaInput<CLK> for DetentScale<D>
+where
+    D: DeltaInput<CLK>,
+{
+    fn tick(&mut self, now: Time<CLK>) -> i8 {
+        let x = self.inner.tick(now);
+
+        if x == 0 {
+            return 0;
+        }
+
+        self.acc += x as i16 * REFERENCE_DETENTS;
+
+        let n = self.acc / self.detents;
+        self.acc -= n * self.detents;
+
+        n.max(i8::MIN as i16).min(i8::MAX as i16) as i8
+    }
 }
 
 /// Wrapper type because we're not allowed to do:
 /// impl<P> DigitalInput<{ CPU_SPEED }> for GPIO<P, Input> {}
+#[cfg(not(test))]
 pub struct PinDigitalIn<P>(pub GPIO<P, Input>);
 
+#[cfg(not(test))]
 impl<P, const CLK: u32> DigitalInput<CLK> for PinDigitalIn<P>
 where
     P: Pin,
@@ -316,3 +861,348 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use alg::input::HiLo;
+
+    use super::*;
+
+    /// Level of an input, shared with the test changing it between ticks.
+    #[derive(Clone, Default)]
+    struct Level(Rc<Cell<bool>>);
+
+    impl Level {
+        fn set(&self, hi: bool) {
+            self.0.set(hi);
+        }
+    }
+
+    impl DigitalInput<{ CPU_SPEED }> for Level {
+        fn tick(&mut self, now: Time<{ CPU_SPEED }>) -> HiLo<{ CPU_SPEED }> {
+            if self.0.get() {
+                HiLo::Hi(now)
+            } else {
+                HiLo::Lo(now)
+            }
+        }
+    }
+
+    /// Encoder that is never turned.
+    struct Still;
+
+    impl DeltaInput<{ CPU_SPEED }> for Still {
+        fn tick(&mut self, _: Time<{ CPU_SPEED }>) -> i8 {
+            0
+        }
+    }
+
+    /// The jacks and buttons of the inputs. The jacks are inverted, high when idle.
+    #[derive(Default)]
+    struct Pins {
+        clock: Level,
+        reset: Level,
+        seed: Level,
+        length: Level,
+        offs: [Level; 4],
+        step: [Level; 4],
+    }
+
+    impl Pins {
+        fn new() -> Self {
+            let pins = Pins::default();
+            pins.clock.set(true);
+            pins.reset.set(true);
+            pins
+        }
+    }
+
+    /// Inputs reading the pins. A macro, since the types of the edges can't be named.
+    macro_rules! inputs {
+        ($pins:expr) => {
+            Inputs {
+                clock: $pins.clock.clone().edge(),
+                clock_last: None,
+                reset: $pins.reset.clone().edge(),
+                reset_start: None,
+                reset_min_width: RESET_MIN_WIDTH,
+                reset_held_tick: None,
+                advance: Level::default().edge(),
+                seed: Still,
+                seed_btn: $pins.seed.clone().edge(),
+                seed_held: false,
+                seed_click: false,
+                seed_lockout: ClickLockout::default(),
+                seed_curve: SeedCurve::default(),
+                length: Still,
+                length_btn: $pins.length.clone().edge(),
+                length_press: LongPress::default(),
+                length_down: false,
+                offs_press: Default::default(),
+                step_press: Default::default(),
+                gate_tap: [None; 4],
+                offs_down: [false; 4],
+                step_down: [false; 4],
+                fill_held: [false; 4],
+                offs1: Still,
+                offs1_btn: $pins.offs[0].clone().edge(),
+                step1: Still,
+                step1_btn: $pins.step[0].clone().edge(),
+                offs2: Still,
+                offs2_btn: $pins.offs[1].clone().edge(),
+                step2: Still,
+                step2_btn: $pins.step[1].clone().edge(),
+                offs3: Still,
+                offs3_btn: $pins.offs[2].clone().edge(),
+                step3: Still,
+                step3_btn: $pins.step[2].clone().edge(),
+                offs4: Still,
+                offs4_btn: $pins.offs[3].clone().edge(),
+                step4: Still,
+                step4_btn: $pins.step[3].clone().edge(),
+            }
+        };
+    }
+
+    fn us(v: i64) -> Time<{ CPU_SPEED }> {
+        let mut t = Time::default();
+        t.count = v * (CPU_SPEED / 1_000_000) as i64;
+        t
+    }
+
+    #[test]
+    fn reset_and_clock_together() {
+        let pins = Pins::new();
+        let mut inputs = inputs!(pins);
+        let mut todo = OperQueue::new();
+
+        // Honor the reset as soon as it's seen.
+        inputs.reset_min_width = us(0);
+
+        inputs.tick(us(0), &mut todo, false);
+        pins.clock.set(false);
+        inputs.tick(us(1000), &mut todo, false);
+        pins.clock.set(true);
+        inputs.tick(us(1100), &mut todo, false);
+        todo.clear();
+
+        pins.clock.set(false);
+        pins.reset.set(false);
+        inputs.tick(us(2000), &mut todo, false);
+
+        assert_eq!(todo.len(), 2);
+        assert!(matches!(todo[0], Oper::Reset));
+        assert!(matches!(todo[1], Oper::Tick(at, None) if at == us(2000)));
+    }
+
+    #[test]
+    fn clock_held_for_reset() {
+        let pins = Pins::new();
+        let mut inputs = inputs!(pins);
+        let mut todo = OperQueue::new();
+
+        inputs.tick(us(0), &mut todo, false);
+        pins.clock.set(false);
+        inputs.tick(us(1000), &mut todo, false);
+        pins.clock.set(true);
+        inputs.tick(us(1100), &mut todo, false);
+        todo.clear();
+
+        // The clock waits for the reset to be long enough.
+        pins.clock.set(false);
+        pins.reset.set(false);
+        inputs.tick(us(2000), &mut todo, false);
+        assert!(todo.is_empty());
+
+        inputs.tick(us(2600), &mut todo, false);
+
+        assert_eq!(todo.len(), 2);
+        assert!(matches!(todo[0], Oper::Reset));
+        assert!(matches!(todo[1], Oper::Tick(at, None) if at == us(2000)));
+    }
+
+    #[test]
+    fn reset_spike_rejected() {
+        let pins = Pins::new();
+        let mut inputs = inputs!(pins);
+        let mut todo = OperQueue::new();
+
+        inputs.tick(us(0), &mut todo, false);
+        pins.clock.set(false);
+        inputs.tick(us(1000), &mut todo, false);
+        pins.clock.set(true);
+        inputs.tick(us(1100), &mut todo, false);
+        todo.clear();
+
+        // A 200µs spike with a clock in the middle of it.
+        pins.reset.set(false);
+        inputs.tick(us(2000), &mut todo, false);
+        pins.clock.set(false);
+        inputs.tick(us(2100), &mut todo, false);
+        assert!(todo.is_empty());
+
+        pins.reset.set(true);
+        inputs.tick(us(2200), &mut todo, false);
+
+        // No reset, and the held clock goes on with its interval.
+        assert_eq!(todo.len(), 1);
+        assert!(matches!(todo[0], Oper::Tick(at, Some(i)) if at == us(2100) && i == us(1100)));
+    }
+
+    #[test]
+    fn reset_long_enough() {
+        let pins = Pins::new();
+        let mut inputs = inputs!(pins);
+        let mut todo = OperQueue::new();
+
+        inputs.tick(us(0), &mut todo, false);
+
+        pins.reset.set(false);
+        inputs.tick(us(1000), &mut todo, false);
+        inputs.tick(us(1400), &mut todo, false);
+        assert!(todo.is_empty());
+
+        inputs.tick(us(1500), &mut todo, false);
+        assert_eq!(todo.len(), 1);
+        assert!(matches!(todo[0], Oper::Reset));
+
+        // Honored once, however long it's held.
+        inputs.tick(us(5000), &mut todo, false);
+        pins.reset.set(true);
+        inputs.tick(us(6000), &mut todo, false);
+        assert_eq!(todo.len(), 1);
+    }
+
+    #[test]
+    fn push_merges_deltas() {
+        let mut todo = OperQueue::new();
+
+        push(&mut todo, Oper::Length(10));
+        push(&mut todo, Oper::Offset(0, 1));
+        push(&mut todo, Oper::Length(3));
+        assert_eq!(todo.len(), 2);
+        assert!(matches!(todo[0], Oper::Length(13)));
+
+        // Capped, the rest is queued on its own.
+        push(&mut todo, Oper::Length(10));
+        assert_eq!(todo.len(), 3);
+        assert!(matches!(todo[2], Oper::Length(10)));
+
+        // Not merged past a click, to keep the order.
+        push(&mut todo, Oper::LengthClick);
+        push(&mut todo, Oper::Length(1));
+        assert_eq!(todo.len(), 5);
+    }
+
+    #[test]
+    fn push_flood() {
+        let mut todo = OperQueue::new();
+
+        for i in 0..200 {
+            push(&mut todo, Oper::OffsetClick(i % 4));
+            push(&mut todo, Oper::Seed(100));
+        }
+
+        assert!(todo.is_full());
+    }
+
+    #[test]
+    fn seed_curve_speeds() {
+        let mut curve = SeedCurve::default();
+
+        // Small twists, far apart.
+        assert_eq!(curve.apply(us(1_000_000), 1), 1);
+        assert_eq!(curve.apply(us(1_100_000), -1), -1);
+
+        // A sustained fast turn speeds up by the detents in the run.
+        let mut t = 2_000_000;
+        let steps: Vec<i16> = (0..20)
+            .map(|_| {
+                t += 10_000;
+                curve.apply(us(t), 2)
+            })
+            .collect();
+        assert_eq!(steps[0], 2);
+        assert_eq!(steps[4], 2);
+        assert_eq!(steps[5], 20);
+        assert_eq!(steps[16], 20);
+        assert_eq!(steps[17], 200);
+
+        // A pause starts over.
+        t += 100_000;
+        assert_eq!(curve.apply(us(t), -1), -1);
+    }
+
+    #[test]
+    fn seed_and_offset_clears_performance() {
+        let pins = Pins::new();
+        let mut inputs = inputs!(pins);
+        let mut todo = OperQueue::new();
+
+        inputs.tick(us(0), &mut todo, true);
+
+        pins.seed.set(true);
+        inputs.tick(us(10_000), &mut todo, true);
+        pins.offs[2].set(true);
+        inputs.tick(us(20_000), &mut todo, true);
+        pins.offs[2].set(false);
+        inputs.tick(us(30_000), &mut todo, true);
+        pins.seed.set(false);
+        inputs.tick(us(40_000), &mut todo, true);
+
+        // Neither the offset nor the seed clicks.
+        assert_eq!(todo.len(), 3);
+        assert!(matches!(todo[0], Oper::Shift(true)));
+        assert!(matches!(todo[1], Oper::ClearPerformance));
+        assert!(matches!(todo[2], Oper::Shift(false)));
+    }
+
+    #[test]
+    fn length_and_seed_freezes() {
+        let pins = Pins::new();
+        let mut inputs = inputs!(pins);
+        let mut todo = OperQueue::new();
+
+        inputs.tick(us(0), &mut todo, true);
+
+        pins.length.set(true);
+        inputs.tick(us(10_000), &mut todo, true);
+        pins.seed.set(true);
+        inputs.tick(us(20_000), &mut todo, true);
+        pins.seed.set(false);
+        inputs.tick(us(30_000), &mut todo, true);
+        pins.length.set(false);
+        inputs.tick(us(40_000), &mut todo, true);
+
+        // Neither the length nor the seed clicks.
+        assert_eq!(todo.len(), 3);
+        assert!(matches!(todo[0], Oper::Shift(true)));
+        assert!(matches!(todo[1], Oper::Freeze));
+        assert!(matches!(todo[2], Oper::Shift(false)));
+    }
+
+    #[test]
+    fn long_press_and_click_lockout() {
+        let mut press = LongPress::default();
+        let ms = |v: i64| us(v * 1000);
+
+        assert_eq!(press.tick(ms(0), Some(Edge::Rising(ms(0)))), None);
+        assert_eq!(
+            press.tick(ms(100), Some(Edge::Falling(ms(100)))),
+            Some(Press::Click)
+        );
+
+        // The bounce of the click.
+        assert_eq!(press.tick(ms(200), Some(Edge::Rising(ms(200)))), None);
+        assert_eq!(press.tick(ms(250), Some(Edge::Falling(ms(250)))), None);
+
+        // Held long enough, released without a click.
+        assert_eq!(press.tick(ms(300), Some(Edge::Rising(ms(300)))), None);
+        assert_eq!(press.tick(ms(1099), None), None);
+        assert_eq!(press.tick(ms(1100), None), Some(Press::Long));
+        assert_eq!(press.tick(ms(1200), Some(Edge::Falling(ms(1200)))), None);
+    }
+}
@@ -0,0 +1,13 @@
+//! Mapping of io expander pins to bitmasks in the 16-bit reads.
+//!
+//! The MCP23S17 reads both banks in one go, organized as `[A7..A0, B7..B0]`.
+
+/// Bitmask for pin 0-7 in bank A.
+pub const fn bank_a(pin: u8) -> u16 {
+    1 << (8 + pin)
+}
+
+/// Bitmask for pin 0-7 in bank B.
+pub const fn bank_b(pin: u8) -> u16 {
+    1 << pin
+}
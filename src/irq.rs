@@ -1,35 +1,96 @@
+//! Interrupt driven reads of the io expanders.
+//!
+//! This path is not wired up. The firmware polls the expanders from the main loop
+//! (see `firmware.rs`), so nothing queues reads and nothing can overflow.
+//! The module is only built for the tests of the read queue, kept for when the
+//! interrupts are taken into use.
+
 use arrayvec::ArrayVec;
+#[cfg(not(test))]
 use bsp::interrupt;
+use core::sync::atomic::{AtomicU32, Ordering};
+#[cfg(not(test))]
 use imxrt_hal::gpio::Input;
+#[cfg(not(test))]
+use imxrt_hal::gpio::Output;
+#[cfg(not(test))]
 use imxrt_hal::gpio::GPIO;
+#[cfg(not(test))]
 use imxrt_hal::iomuxc::prelude::consts;
+#[cfg(not(test))]
 use imxrt_hal::spi::SPI;
+#[cfg(not(test))]
 use teensy4_bsp as bsp;
 
+#[cfg(not(test))]
 use crate::inter::Interrupt;
+#[cfg(not(test))]
 use crate::inter::InterruptConfiguration;
+#[cfg(not(test))]
 use crate::lock::Lock;
+#[cfg(not(test))]
 use crate::mcp23s17::Mcp23S17;
 
 // B1_00 - GPIO2_IO16 - ALT5
+#[cfg(not(test))]
 type IoExt1InterruptPin = GPIO<bsp::common::P8, Input>;
 // B1_01 - GPIO2_IO17 - ALT5
+#[cfg(not(test))]
 type IoExt2InterruptPin = GPIO<bsp::common::P7, Input>;
 
 pub type IoExtReads = ArrayVec<u16, 64>;
 
+/// Number of reads dropped because the main loop didn't drain the queue in time.
+pub static IO_EXT_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Push a read, dropping the oldest if the queue is full. A stale read is less
+/// useful than the latest, and panicking in the ISR takes the whole module down.
+///
+/// At 64 entries the queue holds far more transitions than an encoder produces
+/// between two main loop iterations, so this only happens if the loop stalls.
+fn push_read(reads: &mut IoExtReads, x: u16) {
+    if reads.is_full() {
+        reads.remove(0);
+        IO_EXT_DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    reads.push(x);
+}
+
+/// Push the state at the interrupt and the state after, skipping repeats.
+///
+/// A transition between the capture and the read of the inputs shows up as the
+/// two differing, and is pushed as a read of its own. Skipping repeats means an
+/// interrupt for a change already seen doesn't count as another step of the
+/// encoder decoding.
+fn push_reads(reads: &mut IoExtReads, cap: u16, now: u16) {
+    if reads.last() != Some(&cap) {
+        push_read(reads, cap);
+    }
+
+    if now != cap {
+        push_read(reads, now);
+    }
+}
+
+/// Number of reads dropped since start.
+pub fn io_ext_dropped() -> u32 {
+    IO_EXT_DROPPED.load(Ordering::Relaxed)
+}
+
+#[cfg(not(test))]
 pub fn setup_gpio_interrupts(
     mut pin1: IoExt1InterruptPin,
     mut pin2: IoExt2InterruptPin,
-    io_ext1: Mcp23S17<SPI<consts::U4>, bsp::common::P10>,
-    io_ext2: Mcp23S17<SPI<consts::U4>, bsp::common::P9>,
+    io_ext1: Mcp23S17<SPI<consts::U4>, GPIO<bsp::common::P10, Output>>,
+    io_ext2: Mcp23S17<SPI<consts::U4>, GPIO<bsp::common::P9, Output>>,
     io_ext_reads: Lock<(IoExtReads, IoExtReads)>,
 ) {
     static mut INT: Option<(
         IoExt1InterruptPin,
         IoExt2InterruptPin,
-        Mcp23S17<SPI<consts::U4>, bsp::common::P10>,
-        Mcp23S17<SPI<consts::U4>, bsp::common::P9>,
+        Mcp23S17<SPI<consts::U4>, GPIO<bsp::common::P10, Output>>,
+        Mcp23S17<SPI<consts::U4>, GPIO<bsp::common::P9, Output>>,
         Lock<(IoExtReads, IoExtReads)>,
     )> = None;
 
@@ -42,31 +103,15 @@ pub fn setup_gpio_interrupts(
 
             if pin1.is_interrupt_status() {
                 pin1.clear_interrupt_status();
-                let x = !io_ext1.read_int_cap(cs).unwrap();
-                let y = !io_ext1.read_inputs(cs).unwrap();
-
-                let did_change = reads.0.last().map(|l| *l == x).unwrap_or(true);
-                if did_change {
-                    reads.0.push(x);
-                }
-
-                if y != x {
-                    reads.0.push(y);
+                if let (Ok(x), Ok(y)) = (io_ext1.read_int_cap(cs), io_ext1.read_inputs(cs)) {
+                    push_reads(&mut reads.0, !x, !y);
                 }
             }
 
             if pin2.is_interrupt_status() {
                 pin2.clear_interrupt_status();
-                let x = !io_ext2.read_int_cap(cs).unwrap();
-                let y = !io_ext2.read_inputs(cs).unwrap();
-
-                let did_change = reads.1.last().map(|l| *l == x).unwrap_or(true);
-                if did_change {
-                    reads.1.push(x);
-                }
-
-                if y != x {
-                    reads.1.push(y);
+                if let (Ok(x), Ok(y)) = (io_ext2.read_int_cap(cs), io_ext2.read_inputs(cs)) {
+                    push_reads(&mut reads.1, !x, !y);
                 }
             }
         });
@@ -90,3 +135,53 @@ pub fn setup_gpio_interrupts(
         unsafe { cortex_m::peripheral::NVIC::unmask(bsp::interrupt::GPIO2_Combined_16_31) };
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queue_drops_oldest() {
+        let mut reads = IoExtReads::new();
+
+        for x in 0..64 {
+            push_read(&mut reads, x);
+        }
+
+        let dropped = io_ext_dropped();
+        push_read(&mut reads, 64);
+
+        assert_eq!(reads.len(), 64);
+        assert_eq!(reads[0], 1);
+        assert_eq!(reads[63], 64);
+        assert_eq!(io_ext_dropped(), dropped + 1);
+    }
+
+    #[test]
+    fn push_reads_skips_repeats() {
+        let mut reads = IoExtReads::new();
+
+        push_reads(&mut reads, 0b01, 0b01);
+        assert_eq!(&reads[..], &[0b01]);
+
+        // An interrupt for a change already seen.
+        push_reads(&mut reads, 0b01, 0b01);
+        assert_eq!(&reads[..], &[0b01]);
+
+        push_reads(&mut reads, 0b11, 0b11);
+        assert_eq!(&reads[..], &[0b01, 0b11]);
+    }
+
+    #[test]
+    fn push_reads_transition_after_capture() {
+        let mut reads = IoExtReads::new();
+
+        // Changed again between the capture and the read of the inputs.
+        push_reads(&mut reads, 0b01, 0b11);
+        assert_eq!(&reads[..], &[0b01, 0b11]);
+
+        // The interrupt of that second change captures what was already read.
+        push_reads(&mut reads, 0b11, 0b11);
+        assert_eq!(&reads[..], &[0b01, 0b11]);
+    }
+}
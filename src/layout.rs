@@ -0,0 +1,252 @@
+//! Panel layouts, the wiring of the controls to the io expanders.
+//!
+//! The wiring is specific to a board revision. Supporting a new revision means adding
+//! a layout here, rather than editing `do_run`. Logical roles (seed, length, ...) can
+//! trade physical controls with `Layout::swap_roles`.
+
+use crate::io_pin::{bank_a, bank_b};
+
+/// The wiring of the board being built for.
+const BOARD: Layout = REV_A;
+
+/// The board as fitted with encoders.
+#[cfg(not(feature = "reverse_encoders"))]
+const FITTED: Layout = BOARD;
+
+/// The board as fitted with encoders of the opposite quadrature phase.
+#[cfg(feature = "reverse_encoders")]
+const FITTED: Layout = BOARD.reverse_encoders();
+
+/// The layout of the board being built for.
+#[cfg(not(feature = "swap_seed_length"))]
+pub const LAYOUT: Layout = FITTED;
+
+/// The layout of the board being built for, with the seed and length controls
+/// trading places.
+#[cfg(feature = "swap_seed_length")]
+pub const LAYOUT: Layout = FITTED.swap_roles(Role::Seed, Role::Length);
+
+// Fails the build if a layout wires two controls to the same pin.
+const _: () = assert!(REV_A.is_valid(), "RevA shares a pin");
+const _: () = assert!(BOARD.reverse_encoders().is_valid(), "Reversed shares a pin");
+const _: () = assert!(LAYOUT.is_valid(), "Layout shares a pin");
+
+/// Which io expander a control is wired to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ext {
+    Ext1 = 0,
+    Ext2 = 1,
+}
+
+/// An encoder with a push button. Pins are bitmasks in the expander read.
+#[derive(Debug, Clone, Copy)]
+pub struct EncoderPins {
+    pub ext: Ext,
+    pub pin_a: u16,
+    pub pin_b: u16,
+    pub btn: u16,
+    /// Bitmask of the encoder's built-in LED on the LED expander.
+    pub led: u16,
+}
+
+/// A logical role on the panel. The physical control doing the role is decided
+/// by the layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Seed,
+    Length,
+    Offset(usize),
+    Steps(usize),
+}
+
+/// Wiring of all the controls on the panel.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub seed: EncoderPins,
+    pub length: EncoderPins,
+    pub offs: [EncoderPins; 4],
+    pub step: [EncoderPins; 4],
+}
+
+impl Layout {
+    /// All controls in one list.
+    const fn controls(&self) -> [EncoderPins; 10] {
+        let (o, s) = (&self.offs, &self.step);
+        [
+            self.seed,
+            self.length,
+            o[0],
+            o[1],
+            o[2],
+            o[3],
+            s[0],
+            s[1],
+            s[2],
+            s[3],
+        ]
+    }
+
+    /// The physical control doing a role.
+    pub const fn control(&self, role: Role) -> EncoderPins {
+        match role {
+            Role::Seed => self.seed,
+            Role::Length => self.length,
+            Role::Offset(i) => self.offs[i],
+            Role::Steps(i) => self.step[i],
+        }
+    }
+
+    /// Assign a physical control to a role.
+    const fn assign(mut self, role: Role, e: EncoderPins) -> Layout {
+        match role {
+            Role::Seed => self.seed = e,
+            Role::Length => self.length = e,
+            Role::Offset(i) => self.offs[i] = e,
+            Role::Steps(i) => self.step[i] = e,
+        }
+        self
+    }
+
+    /// Let two roles trade physical controls. Each control keeps its own
+    /// wiring and LED, so turning what was the seed encoder now edits the length.
+    pub const fn swap_roles(self, a: Role, b: Role) -> Layout {
+        let (ea, eb) = (self.control(a), self.control(b));
+        self.assign(a, eb).assign(b, ea)
+    }
+
+    /// The layout fitted with encoders of the opposite quadrature phase, which
+    /// would otherwise turn backwards.
+    pub const fn reverse_encoders(self) -> Layout {
+        let (o, s) = (&self.offs, &self.step);
+        Layout {
+            seed: swap(self.seed),
+            length: swap(self.length),
+            offs: [swap(o[0]), swap(o[1]), swap(o[2]), swap(o[3])],
+            step: [swap(s[0]), swap(s[1]), swap(s[2]), swap(s[3])],
+        }
+    }
+
+    /// Check that no two controls share a pin within one expander.
+    pub const fn is_valid(&self) -> bool {
+        let c = self.controls();
+
+        // Pins used so far, per expander.
+        let mut used = [0_u16; 2];
+
+        let mut i = 0;
+        while i < c.len() {
+            let pins = [c[i].pin_a, c[i].pin_b, c[i].btn];
+            let e = c[i].ext as usize;
+
+            let mut j = 0;
+            while j < pins.len() {
+                if used[e] & pins[j] != 0 {
+                    return false;
+                }
+                used[e] |= pins[j];
+
+                j += 1;
+            }
+
+            i += 1;
+        }
+
+        true
+    }
+}
+
+const fn enc(ext: Ext, pin_a: u16, pin_b: u16, btn: u16, led: u16) -> EncoderPins {
+    EncoderPins {
+        ext,
+        pin_a,
+        pin_b,
+        btn,
+        led,
+    }
+}
+
+/// The original board.
+pub const REV_A: Layout = Layout {
+    // ext1 b4 - pin_a, ext1 a3 - pin_b, button ext1 a4
+    seed: enc(Ext::Ext1, bank_b(4), bank_a(3), bank_a(4), 1 << 0),
+
+    // ext2 b1 - pin_a, ext2 b0 - pin_b, button ext2 b2
+    length: enc(Ext::Ext2, bank_b(1), bank_b(0), bank_b(2), 1 << 1),
+
+    offs: [
+        // ext1 a1 - pin_a, ext1 b5 - pin_b, button ext1 a2
+        enc(Ext::Ext1, bank_a(1), bank_b(5), bank_a(2), 1 << 2),
+        // ext1 b2 - pin_a, ext1 b0 - pin_b, button ext1 b1
+        enc(Ext::Ext1, bank_b(2), bank_b(0), bank_b(1), 1 << 3),
+        // ext2 b5 - pin_a, ext2 b4 - pin_b, button ext2 a1
+        enc(Ext::Ext2, bank_b(5), bank_b(4), bank_a(1), 1 << 4),
+        // ext2 a2 - pin_a, ext2 a3 - pin_b, button ext2 a4
+        enc(Ext::Ext2, bank_a(2), bank_a(3), bank_a(4), 1 << 5),
+    ],
+
+    step: [
+        // ext1 b7 - pin_a, ext1 a0 - pin_b, button ext1 b6
+        enc(Ext::Ext1, bank_b(7), bank_a(0), bank_b(6), 1 << 6),
+        // ext1 a5 - pin_a, ext1 a6 - pin_b, button ext1 a7
+        enc(Ext::Ext1, bank_a(5), bank_a(6), bank_a(7), 1 << 7),
+        // ext2 b7 - pin_a, ext2 b6 - pin_b, button ext2 a0
+        enc(Ext::Ext2, bank_b(7), bank_b(6), bank_a(0), 1 << 8),
+        // ext2 a5 - pin_a, ext2 a6 - pin_b, button ext2 a7
+        enc(Ext::Ext2, bank_a(5), bank_a(6), bank_a(7), 1 << 9),
+    ],
+};
+
+/// Swap pin a and b of an encoder.
+const fn swap(e: EncoderPins) -> EncoderPins {
+    enc(e.ext, e.pin_b, e.pin_a, e.btn, e.led)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swap_roles() {
+        let l = REV_A.swap_roles(Role::Seed, Role::Length);
+
+        assert_eq!(l.seed.pin_a, REV_A.length.pin_a);
+        assert_eq!(l.seed.led, REV_A.length.led);
+        assert_eq!(l.length.btn, REV_A.seed.btn);
+        assert_eq!(l.offs[0].pin_a, REV_A.offs[0].pin_a);
+        assert!(l.is_valid());
+    }
+
+    #[test]
+    fn swap_roles_twice() {
+        let l = REV_B.swap_roles(Role::Offset(1), Role::Steps(2));
+
+        assert_eq!(l.control(Role::Offset(1)).btn, REV_B.step[2].btn);
+        assert_eq!(l.control(Role::Steps(2)).led, REV_B.offs[1].led);
+
+        // Swapping back restores the board.
+        let l = l.swap_roles(Role::Steps(2), Role::Offset(1));
+
+        for (r, a) in l.controls().iter().zip(REV_B.controls().iter()) {
+            assert_eq!((r.ext, r.pin_a, r.pin_b), (a.ext, a.pin_a, a.pin_b));
+            assert_eq!((r.btn, r.led), (a.btn, a.led));
+        }
+    }
+
+    #[test]
+    fn reverse_encoders() {
+        let l = REV_A.reverse_encoders();
+
+        for (r, a) in l.controls().iter().zip(REV_A.controls().iter()) {
+            assert_eq!((r.pin_a, r.pin_b), (a.pin_b, a.pin_a));
+            assert_eq!((r.ext, r.btn, r.led), (a.ext, a.btn, a.led));
+        }
+    }
+
+    #[test]
+    fn shared_pin_is_invalid() {
+        let mut l = REV_A;
+        l.step[0].btn = l.offs[0].btn;
+
+        assert!(!l.is_valid());
+    }
+}
@@ -0,0 +1,141 @@
+//! LEDs built into the rotary encoders.
+//!
+//! For builds using encoders with an embedded LED, the LEDs are driven by a third
+//! MCP23S17 where all pins are configured as outputs. The LED of the encoder currently
+//! being edited is lit.
+
+use core::fmt::Debug;
+use cortex_m::interrupt::CriticalSection;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::error::Error;
+use crate::layout::LAYOUT;
+use crate::mcp23s17::Mcp23S17;
+use crate::state::InputMode;
+use crate::state::State;
+
+// The LEDs follow the physical controls, which the layout assigns to roles.
+
+/// Pin for the seed encoder LED.
+const LED_SEED: u16 = LAYOUT.seed.led;
+/// Pin for the length encoder LED.
+const LED_LENGTH: u16 = LAYOUT.length.led;
+/// Pins for the offset encoder LEDs, one per track.
+const LED_OFFS: [u16; 4] = [
+    LAYOUT.offs[0].led,
+    LAYOUT.offs[1].led,
+    LAYOUT.offs[2].led,
+    LAYOUT.offs[3].led,
+];
+/// Pins for the steps encoder LEDs, one per track.
+const LED_STEP: [u16; 4] = [
+    LAYOUT.step[0].led,
+    LAYOUT.step[1].led,
+    LAYOUT.step[2].led,
+    LAYOUT.step[3].led,
+];
+
+pub struct EncoderLeds<I, C> {
+    io_ext: Mcp23S17<I, C>,
+    /// The last written output value. None until first write.
+    last: Option<u16>,
+}
+
+impl<I, C, E> EncoderLeds<I, C>
+where
+    I: Transfer<u16, Error = E>,
+    I: Write<u16, Error = E>,
+    C: OutputPin,
+    C::Error: Debug,
+{
+    pub fn new(io_ext: Mcp23S17<I, C>) -> Self {
+        EncoderLeds { io_ext, last: None }
+    }
+
+    /// Tells whether the LEDs need an update for the given state.
+    pub fn needs_update(&self, state: &State) -> bool {
+        self.last != Some(leds(state))
+    }
+
+    /// Update the LEDs for the given state. Only writes on change.
+    pub fn update(&mut self, state: &State, cs: &CriticalSection) -> Result<(), Error> {
+        let x = leds(state);
+
+        if self.last != Some(x) {
+            self.io_ext.write_outputs(x, cs)?;
+            self.last = Some(x);
+        }
+
+        Ok(())
+    }
+}
+
+/// The LED pins to light for the state. An override with LEDs of its own lights
+/// those, the rest keep the LEDs of the input mode.
+pub fn leds(state: &State) -> u16 {
+    match led_for(state.display_mode()) {
+        0 => led_for(state.input_mode()),
+        x => x,
+    }
+}
+
+/// Map the input mode to the LED pins to light.
+pub fn led_for(mode: &InputMode) -> u16 {
+    match mode {
+        InputMode::Run => 0,
+        InputMode::Seed => LED_SEED,
+        InputMode::Fate => LED_SEED,
+        InputMode::Length => LED_LENGTH,
+        InputMode::LengthMode => LED_LENGTH,
+        InputMode::Settings => LED_LENGTH,
+        InputMode::Transport => LED_LENGTH,
+        InputMode::InputPpqn => LED_LENGTH,
+        InputMode::ClockMult => LED_LENGTH,
+        InputMode::ClockStop => LED_LENGTH,
+        InputMode::ClockQuantize => LED_LENGTH,
+        InputMode::Swing => LED_LENGTH,
+        InputMode::SwingResolution => LED_LENGTH,
+        InputMode::LfoSeedHold => LED_LENGTH,
+        InputMode::SeedRange => LED_LENGTH,
+        InputMode::SeedCoarse => LED_LENGTH,
+        InputMode::LinkRole => LED_LENGTH,
+        InputMode::FateEnabled => LED_LENGTH,
+        InputMode::OffsetAbsolute => LED_LENGTH,
+        InputMode::NoEmpty => LED_LENGTH,
+        InputMode::Overview => LED_LENGTH,
+        InputMode::Offset(tr) => LED_OFFS[*tr],
+        InputMode::Lfo(tr) => LED_OFFS[*tr],
+        InputMode::LfoReset(tr) => LED_OFFS[*tr],
+        InputMode::Algo(tr) => LED_OFFS[*tr],
+        InputMode::GateMode(tr) => LED_OFFS[*tr],
+        InputMode::GateRole(tr) => LED_OFFS[*tr],
+        InputMode::GateInvert(tr) => LED_OFFS[*tr],
+        InputMode::ChannelRole(tr) => LED_OFFS[*tr],
+        InputMode::CvBehavior(tr) => LED_OFFS[*tr],
+        InputMode::Anchor(tr) => LED_OFFS[*tr],
+        InputMode::Burst(tr) => LED_OFFS[*tr],
+        InputMode::Humanize(tr) => LED_OFFS[*tr],
+        InputMode::TrackDelay(tr) => LED_OFFS[*tr],
+        InputMode::Warmup(tr) => LED_OFFS[*tr],
+        InputMode::LfoGate(tr) => LED_OFFS[*tr],
+        InputMode::GateLenMod(tr) => LED_OFFS[*tr],
+        InputMode::StepEdit(tr) => LED_OFFS[*tr],
+        InputMode::StepValue(tr) => LED_OFFS[*tr],
+        InputMode::Steps(tr) => LED_STEP[*tr],
+        InputMode::TrackSync(tr) => LED_STEP[*tr],
+        InputMode::SyncWidth(tr) => LED_STEP[*tr],
+        // Overrides showing a value, which keep the LEDs of the input mode.
+        InputMode::Mute(_) => 0,
+        InputMode::Frozen(_) => 0,
+        InputMode::FactoryReset => 0,
+        InputMode::ClearPerformance => 0,
+        InputMode::LfoRetrigger(_) => 0,
+        InputMode::LfoCoupling(_) => 0,
+        InputMode::LfoAudition(_) => 0,
+        InputMode::LfoDepth(_) => 0,
+        InputMode::GateLen(_) => 0,
+        InputMode::OffsetAll => 0,
+        InputMode::ParamDefault => 0,
+    }
+}
@@ -2,11 +2,26 @@
 
 use core::fmt::Debug;
 
+use alg::clock::Time;
 use alg::geom::{sin, tri};
 use alg::rnd::Rnd;
 
 use crate::max6958::Seg;
 use crate::max6958::Segs;
+use crate::CPU_SPEED;
+
+/// Full depth, the entire 12-bit range.
+pub const DEPTH_MAX: u16 = 0xfff;
+
+/// How often the drunk walk takes a step.
+const WALK_INTERVAL: Time<{ CPU_SPEED }> = Time::from_millis(5);
+
+/// Smallest and largest step of the drunk walk. The size within is seeded.
+const WALK_STEP_MIN: u16 = 16;
+const WALK_STEP_MAX: u16 = 96;
+
+/// Most steps of the drunk walk caught up in one tick, after a long pause.
+const WALK_CATCH_UP: i64 = 16;
 
 #[derive(Debug, Clone)]
 /// A 12-bit LFO.
@@ -16,11 +31,29 @@ pub struct Lfo {
     pub mode: Mode,
     gate_high: bool,
 
+    /// How the LFO is triggered by the gate.
+    pub coupling: Coupling,
+    /// Restart the phase on each gate rise.
+    pub retrigger: bool,
+    /// Amplitude around the center, 0 (flat) to `DEPTH_MAX` (full range).
+    pub depth: u16,
+    /// Offset at the last retrigger.
+    phase_base: u32,
+
     rnd: RndStep,
     length: u8,
 
     last: u16,
     next: Option<u16>,
+
+    /// Current value of the drunk walk, 0 to `DEPTH_MAX`.
+    walk: u16,
+    /// Largest change of the drunk walk per step.
+    walk_step: u16,
+    /// Random state of the drunk walk.
+    walk_rnd: u32,
+    /// Time of the last drunk walk step.
+    walk_at: Option<Time<{ CPU_SPEED }>>,
 }
 
 #[derive(Clone)]
@@ -39,10 +72,18 @@ impl Default for Lfo {
             prev: 0,
             mode: Mode::Random,
             gate_high: false,
+            coupling: Coupling::Rise,
+            retrigger: false,
+            depth: DEPTH_MAX,
+            phase_base: 0,
             rnd: RndStep([0; 64]),
             length: 2,
             last: 0,
             next: None,
+            walk: DEPTH_MAX / 2,
+            walk_step: WALK_STEP_MIN,
+            walk_rnd: 1,
+            walk_at: None,
         }
     }
 }
@@ -62,38 +103,88 @@ impl Lfo {
             self.rnd.0[i] = rnd.next();
         }
 
+        self.walk_rnd = rnd.next();
+        self.walk_step =
+            WALK_STEP_MIN + (rnd.next() % (WALK_STEP_MAX - WALK_STEP_MIN) as u32) as u16;
+
         self.update(false);
     }
 
     pub fn set_mode(&mut self, d: i8) {
-        let mut n = self.mode as i8 + d;
+        let n = (self.mode as i16 + d as i16).rem_euclid(Mode::len() as i16);
 
-        if n < 0 {
-            n += Mode::len() as i8;
-        }
+        self.mode = (n as i8).into();
+
+        self.update(false);
+    }
 
-        self.mode = n.into();
+    pub fn set_retrigger(&mut self, retrigger: bool) {
+        self.retrigger = retrigger;
+        self.phase_base = 0;
 
         self.update(false);
     }
 
+    /// Restart a retriggered LFO from the offset, as if the gate rose at offset 0.
+    /// Without retrigger the phase already follows the offset.
+    pub fn reset_phase(&mut self) {
+        self.phase_base = 0;
+
+        self.update(false);
+    }
+
+    pub fn set_depth(&mut self, depth: u16) {
+        self.depth = depth.min(DEPTH_MAX);
+
+        self.update(false);
+    }
+
+    pub fn set_coupling(&mut self, d: i8) {
+        let n = (self.coupling as i16 + d as i16).rem_euclid(Coupling::len() as i16);
+
+        self.coupling = (n as i8).into();
+    }
+
     pub fn set_gate_high(&mut self, high: bool) {
+        let high = match self.coupling {
+            Coupling::Rise => high,
+            // Inverted so we trigger on the gate falling.
+            Coupling::Fall => !high,
+            Coupling::Off => return,
+        };
+
         if self.gate_high != high {
-            self.update(!self.gate_high && high);
+            let gate_rise = !self.gate_high && high;
+
+            if gate_rise && self.retrigger {
+                self.phase_base = self.offset;
+            }
+
+            self.update(gate_rise);
             self.gate_high = high;
         }
     }
 
+    /// The phase used for the output. Free running off the offset, unless
+    /// retriggered by the gate. Random mode is always sampled on the offset.
+    fn phase(&self) -> u32 {
+        if self.retrigger && self.mode != Mode::Random {
+            self.offset.wrapping_sub(self.phase_base)
+        } else {
+            self.offset
+        }
+    }
+
     fn update(&mut self, gate_rise: bool) {
-        if self.mode == Mode::Random {
+        if self.mode == Mode::Random && self.coupling != Coupling::Off {
             // Random mode is tied to gate changing to high.
             if !gate_rise {
                 return;
             }
-            let n = self.mode.output(self.offset, &self.rnd.0, self.length);
+            let n = self.output();
             self.next = Some(n);
         } else {
-            let n = self.mode.output(self.offset, &self.rnd.0, self.length);
+            let n = self.output();
 
             if n != self.last {
                 self.last = n;
@@ -102,9 +193,86 @@ impl Lfo {
         }
     }
 
-    pub fn tick(&mut self) -> Option<u16> {
+    fn output(&self) -> u16 {
+        let raw = match self.mode {
+            // Free running, regardless of the clock.
+            Mode::Drunk => self.walk,
+            _ => self
+                .mode
+                .hack_adjust_output_for_hw_bug(self.phase(), &self.rnd.0, self.length),
+        };
+
+        scale_output(raw, self.depth)
+    }
+
+    pub fn tick(&mut self, now: Time<{ CPU_SPEED }>) -> Option<u16> {
+        if self.mode == Mode::Drunk {
+            self.walk(now);
+        }
+
         self.next.take()
     }
+
+    /// Advance the drunk walk by the steps due since the last one. Each step moves
+    /// a seeded random amount up to `walk_step`, stopping at the ends of the range.
+    fn walk(&mut self, now: Time<{ CPU_SPEED }>) {
+        let at = *self.walk_at.get_or_insert(now);
+        let steps = (now - at).count() / WALK_INTERVAL.count();
+
+        if steps <= 0 {
+            return;
+        }
+
+        let mut next_at = at;
+        next_at.count += steps * WALK_INTERVAL.count();
+        self.walk_at = Some(next_at);
+
+        for _ in 0..steps.min(WALK_CATCH_UP) {
+            let mut rnd = Rnd::new(self.walk_rnd);
+            self.walk_rnd = rnd.next();
+
+            let span = 2 * self.walk_step as u32 + 1;
+            let d = (self.walk_rnd % span) as i32 - self.walk_step as i32;
+
+            self.walk = (self.walk as i32 + d).max(0).min(DEPTH_MAX as i32) as u16;
+        }
+
+        self.update(false);
+    }
+}
+
+/// How the LFO is coupled to the gate of the track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coupling {
+    /// Triggered by the gate rising.
+    Rise = 0,
+    /// Triggered by the gate falling.
+    Fall = 1,
+    /// Ignores the gate. Random mode instead samples on each new step.
+    Off = 2,
+}
+
+impl Coupling {
+    pub const fn len() -> usize {
+        3
+    }
+}
+
+impl From<i8> for Coupling {
+    fn from(mut v: i8) -> Self {
+        use Coupling::*;
+
+        while v < 0 {
+            v += Coupling::len() as i8;
+        }
+
+        match v % (Coupling::len() as i8) {
+            0 => Rise,
+            1 => Fall,
+            2 => Off,
+            _ => panic!("Unhandled Coupling number"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -121,6 +289,8 @@ pub enum Mode {
     Square = 9,
     Square90 = 10,
     Square180 = 11,
+    /// A smooth random value wandering in small steps, independent of the clock.
+    Drunk = 12,
 }
 
 impl Default for Mode {
@@ -131,11 +301,7 @@ impl Default for Mode {
 
 impl Mode {
     pub const fn len() -> usize {
-        12
-    }
-
-    fn output(&self, offset: u32, rnd: &[u32], length: u8) -> u16 {
-        (68 * (self.hack_adjust_output_for_hw_bug(offset, rnd, length) as u32) / 100) as u16
+        13
     }
 
     fn hack_adjust_output_for_hw_bug(&self, offset: u32, rnd: &[u32], length: u8) -> u16 {
@@ -164,10 +330,23 @@ impl Mode {
             Mode::Square => sqr_12(offset),
             Mode::Square90 => sqr_12(offset.wrapping_add(u32::MAX / 4)),
             Mode::Square180 => sqr_12(offset.wrapping_add(u32::MAX / 2)),
+
+            // Not driven by the offset, see `Lfo::walk()`.
+            Mode::Drunk => DEPTH_MAX / 2,
         }
     }
 }
 
+/// Scale a raw 12-bit output around the center by the depth.
+pub fn scale_output(raw: u16, depth: u16) -> u16 {
+    let n = raw as i32;
+
+    let center = (DEPTH_MAX as i32 + 1) / 2;
+    let n = center + (n - center) * depth as i32 / DEPTH_MAX as i32;
+
+    (68 * n as u32 / 100) as u16
+}
+
 fn sin_12(offset: u32) -> u16 {
     ((0x8000 + sin(offset) as i32) >> 4) as u16
 }
@@ -194,8 +373,13 @@ fn sqr_12(offset: u32) -> u16 {
 }
 
 impl From<i8> for Mode {
-    fn from(v: i8) -> Self {
+    fn from(mut v: i8) -> Self {
         use Mode::*;
+
+        while v < 0 {
+            v += Mode::len() as i8;
+        }
+
         match v % (Mode::len() as i8) {
             0 => Random,
             1 => SawUp,
@@ -209,6 +393,7 @@ impl From<i8> for Mode {
             9 => Square,
             10 => Square90,
             11 => Square180,
+            12 => Drunk,
             _ => panic!("Unhandled Mode number"),
         }
     }
@@ -0,0 +1,288 @@
+//! Link between two units over a serial port.
+//!
+//! A leading unit sends its transport (tick/reset) and seed to a following unit,
+//! which mirrors the transport and optionally adopts the seed. The seed only
+//! feeds the generation, so the patterns are correlated but not identical,
+//! since the tracks of each unit have their own settings.
+//!
+//! Each event is a frame of 7 bytes:
+//!
+//! ```ignore
+//! [SYNC, kind, payload0, payload1, payload2, payload3, checksum]
+//! ```
+//!
+//! The payload is a little endian u32, used for the seed. The checksum is the xor
+//! of kind and payload.
+
+use alg::clock::Time;
+use arrayvec::ArrayVec;
+use embedded_hal::serial::{Read, Write};
+
+use crate::state::Oper;
+use crate::state::OperQueue;
+use crate::CPU_SPEED;
+
+/// Start of every frame.
+const SYNC: u8 = 0xa5;
+
+/// Length of a frame.
+const FRAME_LEN: usize = 7;
+
+/// How long without frames before a following unit considers the link lost
+/// and falls back to its own clock. The lead sends a keep-alive well within this.
+const LINK_TIMEOUT: Time<{ CPU_SPEED }> = Time::from_secs(2);
+
+/// How often the lead sends a keep-alive.
+const KEEP_ALIVE: Time<{ CPU_SPEED }> = Time::from_millis(500);
+
+/// Role of this unit in the link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkRole {
+    /// No link, standalone.
+    Off = 0,
+    /// Sends transport and seed.
+    Lead = 1,
+    /// Follows the transport.
+    Follow = 2,
+    /// Follows the transport and the seed.
+    FollowSeed = 3,
+}
+
+impl LinkRole {
+    pub const fn len() -> usize {
+        4
+    }
+}
+
+impl Default for LinkRole {
+    fn default() -> Self {
+        LinkRole::Off
+    }
+}
+
+impl From<i8> for LinkRole {
+    fn from(mut x: i8) -> Self {
+        use LinkRole::*;
+
+        while x < 0 {
+            x += Self::len() as i8;
+        }
+
+        match x % (Self::len() as i8) {
+            0 => Off,
+            1 => Lead,
+            2 => Follow,
+            3 => FollowSeed,
+            _ => panic!("Unhandled LinkRole number"),
+        }
+    }
+}
+
+/// Events sent over the link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frame {
+    KeepAlive,
+    Tick,
+    Reset,
+    /// The internal seed.
+    Seed(u32),
+}
+
+impl Frame {
+    fn encode(&self) -> [u8; FRAME_LEN] {
+        let (kind, payload) = match self {
+            Frame::KeepAlive => (0, 0),
+            Frame::Tick => (1, 0),
+            Frame::Reset => (2, 0),
+            Frame::Seed(s) => (3, *s),
+        };
+
+        let p = payload.to_le_bytes();
+        let checksum = kind ^ p[0] ^ p[1] ^ p[2] ^ p[3];
+
+        [SYNC, kind, p[0], p[1], p[2], p[3], checksum]
+    }
+
+    fn decode(buf: &[u8]) -> Option<Frame> {
+        if buf.len() != FRAME_LEN || buf[0] != SYNC {
+            return None;
+        }
+
+        let checksum = buf[1] ^ buf[2] ^ buf[3] ^ buf[4] ^ buf[5];
+        if checksum != buf[6] {
+            return None;
+        }
+
+        let payload = u32::from_le_bytes([buf[2], buf[3], buf[4], buf[5]]);
+
+        Some(match buf[1] {
+            0 => Frame::KeepAlive,
+            1 => Frame::Tick,
+            2 => Frame::Reset,
+            3 => Frame::Seed(payload),
+            _ => return None,
+        })
+    }
+}
+
+pub struct Link<S> {
+    port: S,
+    /// Bytes waiting to be written.
+    tx: ArrayVec<u8, 64>,
+    /// Partially received frame.
+    rx: ArrayVec<u8, FRAME_LEN>,
+    /// Last time a frame was received.
+    last_rx: Option<Time<{ CPU_SPEED }>>,
+    /// Last time a frame was sent.
+    last_tx: Time<{ CPU_SPEED }>,
+    /// Last tick received, for the interval.
+    last_tick: Option<Time<{ CPU_SPEED }>>,
+    /// Last seed sent, to only send changes.
+    last_seed: Option<u32>,
+}
+
+impl<S> Link<S>
+where
+    S: Read<u8> + Write<u8>,
+{
+    pub fn new(port: S) -> Self {
+        Link {
+            port,
+            tx: ArrayVec::new(),
+            rx: ArrayVec::new(),
+            last_rx: None,
+            last_tx: Time::default(),
+            last_tick: None,
+            last_seed: None,
+        }
+    }
+
+    /// Whether frames have been received recently.
+    pub fn is_alive(&self, now: Time<{ CPU_SPEED }>) -> bool {
+        self.last_rx
+            .map(|l| now - l < LINK_TIMEOUT)
+            .unwrap_or(false)
+    }
+
+    /// Queue a frame for sending. Dropped if the port can't keep up.
+    fn send(&mut self, now: Time<{ CPU_SPEED }>, frame: Frame) {
+        let bytes = frame.encode();
+
+        if self.tx.remaining_capacity() >= bytes.len() {
+            self.tx.try_extend_from_slice(&bytes).ok();
+            self.last_tx = now;
+        }
+    }
+
+    /// Exchange frames for the role. Call every loop, before the operations are applied.
+    ///
+    /// A leading unit sends its transport and seed. A following unit turns received
+    /// frames into operations, and while the link is alive, drops the transport from
+    /// its own jacks.
+    pub fn tick(
+        &mut self,
+        now: Time<{ CPU_SPEED }>,
+        role: LinkRole,
+        opers: &mut OperQueue,
+        seed: u32,
+    ) {
+        match role {
+            LinkRole::Off => {}
+            LinkRole::Lead => self.lead(now, opers, seed),
+            LinkRole::Follow => self.follow(now, opers, false),
+            LinkRole::FollowSeed => self.follow(now, opers, true),
+        }
+
+        // Write what we can without blocking, the rest goes next loop.
+        while let Some(b) = self.tx.first().copied() {
+            if self.port.write(b).is_err() {
+                break;
+            }
+            self.tx.remove(0);
+        }
+    }
+
+    fn lead(&mut self, now: Time<{ CPU_SPEED }>, opers: &OperQueue, seed: u32) {
+        for o in opers {
+            match o {
+                Oper::Tick(_, _) => self.send(now, Frame::Tick),
+                Oper::Reset => self.send(now, Frame::Reset),
+                _ => {}
+            }
+        }
+
+        if self.last_seed != Some(seed) {
+            self.last_seed = Some(seed);
+            self.send(now, Frame::Seed(seed));
+        }
+
+        if now - self.last_tx > KEEP_ALIVE {
+            self.send(now, Frame::KeepAlive);
+        }
+    }
+
+    fn follow(&mut self, now: Time<{ CPU_SPEED }>, opers: &mut OperQueue, follow_seed: bool) {
+        let was_alive = self.is_alive(now);
+
+        // Transport from our own jacks, to be dropped while the link is alive.
+        let local = opers.len();
+
+        while let Some(frame) = self.receive() {
+            self.last_rx = Some(now);
+
+            let oper = match frame {
+                Frame::KeepAlive => None,
+                Frame::Tick => {
+                    let interval = self.last_tick.map(|l| now - l);
+                    self.last_tick = Some(now);
+                    Some(Oper::Tick(now, interval))
+                }
+                Frame::Reset => Some(Oper::Reset),
+                Frame::Seed(s) if follow_seed => Some(Oper::LinkSeed(s)),
+                Frame::Seed(_) => None,
+            };
+
+            if let Some(o) = oper {
+                opers.try_push(o).ok();
+            }
+        }
+
+        if self.is_alive(now) {
+            let mut i = 0;
+            opers.retain(|o| {
+                i += 1;
+                i > local
+                    || match o {
+                        Oper::Tick(_, _) | Oper::Reset | Oper::ClockWidth(_) => false,
+                        _ => true,
+                    }
+            });
+        } else if was_alive {
+            info!("Link lost, using own clock");
+            self.last_tick = None;
+        }
+    }
+
+    /// Read a received frame, if any.
+    fn receive(&mut self) -> Option<Frame> {
+        while let Ok(b) = self.port.read() {
+            if self.rx.is_empty() && b != SYNC {
+                // Out of sync, wait for the start of a frame.
+                continue;
+            }
+
+            self.rx.push(b);
+
+            if self.rx.is_full() {
+                let frame = Frame::decode(&self.rx);
+                self.rx.clear();
+
+                if frame.is_some() {
+                    return frame;
+                }
+            }
+        }
+
+        None
+    }
+}
@@ -13,6 +13,24 @@ const FILTERS: &[bsp::usb::Filter] = &[
     // ("i2c", Some(LevelFilter::Info)),
 ];
 
+/// How long to wait after init so the host doesn't miss the first log messages.
+/// Only used when USB is connected.
+pub const STARTUP_DELAY_MS: u32 = 1000;
+
+/// Initialize the logging, returning the reader of what the host sends when active.
+///
+/// Without USB, the max log level is turned off to avoid the overhead
+/// of formatting log messages that go nowhere.
+pub fn init_active() -> Option<bsp::usb::Reader> {
+    let reader = init().ok();
+
+    if reader.is_none() {
+        log::set_max_level(LevelFilter::Off);
+    }
+
+    reader
+}
+
 /// Initialize the USB logging system, and prepares the
 /// USB ISR with the poller
 ///
@@ -3,10 +3,13 @@
 //! Driver for Max6958/Max6959 segment LED controller.
 //! Datasheet here: <https://datasheets.maximintegrated.com/en/ds/MAX6958-MAX6958.pdf>
 
+use alg::clock::Time;
 use cortex_m::interrupt::CriticalSection;
 use embedded_hal::blocking::i2c::{Write, WriteRead};
 
+use crate::display::SegmentDisplay;
 use crate::lock::Lock;
+use crate::CPU_SPEED;
 
 // At power-up, the MAX6958/ MAX6959 are initially set to scan four digits,
 // do not decode data in the digit registers or scan key switches,
@@ -49,7 +52,11 @@ where
 
         info!("current config {:?}", config_r);
 
-        let config = config_r | if shutdown { 0 } else { 1 };
+        let config = if shutdown {
+            config_r & !1
+        } else {
+            config_r | 1
+        };
 
         self.write_register(Register::Configuration, config, cs)?;
         Ok(())
@@ -87,6 +94,11 @@ where
         self.write_register(digit.as_reg(), value, cs)
     }
 
+    /// Set the decimal points. Bit 0-3 corresponds to digit 0-3.
+    pub fn set_dp(&mut self, dp: u8, cs: &CriticalSection) -> Result<(), E> {
+        self.write_register(Register::Segments, dp & 0x0f, cs)
+    }
+
     pub fn set_segs<const X: usize>(&mut self, s: Segs<X>, cs: &CriticalSection) -> Result<(), E> {
         let mut buf = s.0;
         buf[0] = Register::Digit0.addr();
@@ -108,6 +120,79 @@ where
     }
 }
 
+impl<I, E> SegmentDisplay for Max6958<I>
+where
+    I: Write<Error = E>,
+    I: WriteRead<Error = E>,
+{
+    type Error = E;
+
+    fn show(&mut self, segs: Segs4, dp: u8, cs: &CriticalSection) -> Result<(), E> {
+        self.set_segs(segs, cs)?;
+        self.set_dp(dp, cs)
+    }
+
+    fn set_intensity(&mut self, intensity: u8, cs: &CriticalSection) -> Result<(), E> {
+        Max6958::set_intensity(self, intensity, cs)
+    }
+
+    fn set_shutdown(&mut self, shutdown: bool, cs: &CriticalSection) -> Result<(), E> {
+        Max6958::set_shutdown(self, shutdown, cs)
+    }
+}
+
+/// Fades the intensity up from 0 when the display is enabled.
+///
+/// Lighting all segments at full intensity at once causes a current spike. The ramp
+/// spreads it out over a number of `set_intensity` writes, paced by the main loop
+/// rather than a blocking delay.
+pub struct IntensityRamp {
+    target: u8,
+    duration: Time<{ CPU_SPEED }>,
+    start: Option<Time<{ CPU_SPEED }>>,
+    last: Option<u8>,
+}
+
+impl IntensityRamp {
+    pub fn new(target: u8, duration: Time<{ CPU_SPEED }>) -> Self {
+        assert!(target <= 0x3f, "Intensity range 0x00-0x3f");
+        IntensityRamp {
+            target,
+            duration,
+            start: None,
+            last: None,
+        }
+    }
+
+    /// Start ramping from 0.
+    pub fn start(&mut self, now: Time<{ CPU_SPEED }>) {
+        self.start = Some(now);
+        self.last = None;
+    }
+
+    /// The intensity to write, if it changed since the last tick.
+    pub fn tick(&mut self, now: Time<{ CPU_SPEED }>) -> Option<u8> {
+        let start = self.start?;
+
+        let lapsed = (now - start).count();
+        let duration = self.duration.count();
+
+        let intensity = if lapsed >= duration {
+            self.start = None;
+            self.target
+        } else {
+            (self.target as i64 * lapsed.max(0) / duration) as u8
+        };
+
+        if self.last == Some(intensity) {
+            return None;
+        }
+
+        self.last = Some(intensity);
+        Some(intensity)
+    }
+}
+
 /// Translation from numbers/chars to segments.
 ///
 /// ```ignore
@@ -194,6 +279,24 @@ impl<const X: usize> Segs<X> {
         // first byte is reserved for the command
         &mut self.0[1..]
     }
+
+    /// A number right aligned over the `width` rightmost digits, the others blank.
+    /// Digits that don't fit are cut off. With `suppress_leading_zeros`, leading
+    /// zeros are blank too, though 0 itself still shows a single digit.
+    pub fn from_right_aligned(mut n: u32, width: usize, suppress_leading_zeros: bool) -> Self {
+        let mut buf = [0; X];
+
+        for i in 1..=width.min(X - 1) {
+            if suppress_leading_zeros && n == 0 && i > 1 {
+                break;
+            }
+
+            buf[i] = Seg::from((n % 10) as u8) as u8;
+            n /= 10;
+        }
+
+        Segs(buf)
+    }
 }
 
 /// Type for sending 4 chars in one go. Can be converted from a &str or number.
@@ -356,3 +459,21 @@ impl Register {
         *self as u8
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn right_aligned() {
+        let n = Segs4::from_right_aligned;
+
+        assert_eq!(n(42, 4, true), Segs4::from("  42"));
+        assert_eq!(n(42, 4, false), Segs4::from("0042"));
+        assert_eq!(n(0, 4, true), Segs4::from("   0"));
+        assert_eq!(n(7, 2, false), Segs4::from("  07"));
+
+        // Cut off when it doesn't fit.
+        assert_eq!(n(12345, 4, true), Segs4::from("2345"));
+    }
+}
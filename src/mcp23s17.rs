@@ -15,21 +15,25 @@
 // spi.enable_chip_select_0(pins.p10);
 
 use alg::SetBit;
-use bsp::hal::gpio::{Output, GPIO};
 use core::fmt::Debug;
 use cortex_m::interrupt::CriticalSection;
 use embedded_hal::blocking::spi::{Transfer, Write};
 use embedded_hal::digital::v2::OutputPin;
-use imxrt_hal::iomuxc::gpio::Pin;
-use teensy4_bsp as bsp;
 
 use crate::error::Error;
 use crate::lock::Lock;
 
-/// 16-bit I/O expander.
-pub struct Mcp23S17<I, P> {
+/// IOCON with mirrored interrupt pins and active high interrupt.
+const IOCON: u16 = 0b0100_0010_0100_0010;
+
+/// How many times to apply the configuration before giving up. An SPI glitch
+/// during power-up can leave the expander misconfigured, with dead inputs.
+const CONFIG_ATTEMPTS: usize = 3;
+
+/// 16-bit I/O expander, selected by the chip select pin `C`.
+pub struct Mcp23S17<I, C> {
     spi_lock: Lock<I>,
-    cs: GPIO<P, Output>,
+    cs: C,
     params: Builder,
 }
 
@@ -42,11 +46,12 @@ pub fn builder() -> Builder {
     }
 }
 
-impl<I, P, E> Mcp23S17<I, P>
+impl<I, C, E> Mcp23S17<I, C>
 where
     I: Transfer<u16, Error = E>,
     I: Write<u16, Error = E>,
-    P: Pin,
+    C: OutputPin,
+    C::Error: Debug,
 {
     fn configure(&mut self, params: Builder, cs: &CriticalSection) -> Result<(), Error> {
         // high when not active.
@@ -58,7 +63,7 @@ where
         // interrupt pins mirror each other.
         //
         // also set interrupt high. because... why would it be inverted.
-        self.transfer(address(true, 0x0a), 0b0100_0010_0100_0010, cs)?;
+        self.transfer(address(true, 0x0a), IOCON, cs)?;
 
         self.transfer(address(true, 0x00), params.dir, cs)?;
         self.transfer(address(true, 0x02), params.pol, cs)?;
@@ -70,36 +75,54 @@ where
         Ok(())
     }
 
-    pub fn verify_config(&mut self, cs: &CriticalSection) -> Result<(), Error> {
-        let x = self.transfer(address(false, 0x0a), 0, cs)?;
-        assert_eq!(x, 0b0100_0010_0100_0010, "Mirror and INTPOL");
-
-        let x = self.transfer(address(false, 0x00), 0, cs)?;
-        if x != self.params.dir {
-            error!("Incorrect direction: {:0x?}", x);
-        }
-        let x = self.transfer(address(false, 0x02), 0, cs)?;
-        if x != self.params.pol {
-            error!("Incorrect polarity: {:0x?}", x);
-        }
-        let x = self.transfer(address(false, 0x04), 0, cs)?;
-        if x != self.params.int {
-            error!("Incorrect interrupt: {:0x?}", x);
-        }
-        let x = self.transfer(address(false, 0x06), 0, cs)?;
-        if x != self.params.def {
-            error!("Incorrect default value: {:0x?}", x);
-        }
-        let x = self.transfer(address(false, 0x08), 0, cs)?;
-        if x != self.params.con {
-            error!("Incorrect config: {:0x?}", x);
+    /// Apply and verify the configuration, retrying up to `CONFIG_ATTEMPTS` times.
+    fn configure_verified(&mut self, cs: &CriticalSection) -> Result<(), Error> {
+        let mut attempt = 1;
+
+        loop {
+            let result = self
+                .configure(self.params.clone(), cs)
+                .and_then(|_| self.verify_config(cs));
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < CONFIG_ATTEMPTS => {
+                    warn!("Retry mcp23s17 configuration ({}): {:?}", attempt, e);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
         }
-        let x = self.transfer(address(false, 0x0c), 0, cs)?;
-        if x != self.params.pul {
-            error!("Incorrect pull-up: {:0x?}", x);
+    }
+
+    /// Read back the configuration, logging each register that doesn't match.
+    /// Errors if any register is off.
+    pub fn verify_config(&mut self, cs: &CriticalSection) -> Result<(), Error> {
+        let expected = [
+            ("mirror and interrupt polarity", 0x0a, IOCON),
+            ("direction", 0x00, self.params.dir),
+            ("polarity", 0x02, self.params.pol),
+            ("interrupt", 0x04, self.params.int),
+            ("default value", 0x06, self.params.def),
+            ("config", 0x08, self.params.con),
+            ("pull-up", 0x0c, self.params.pul),
+        ];
+
+        let mut ok = true;
+
+        for &(name, reg, value) in expected.iter() {
+            let x = self.transfer(address(false, reg), 0, cs)?;
+            if x != value {
+                error!("Incorrect {}: {:0x?}", name, x);
+                ok = false;
+            }
         }
 
-        Ok(())
+        if ok {
+            Ok(())
+        } else {
+            Err(Error::Other("MCP23S17 config mismatch"))
+        }
     }
 
     fn transfer(&mut self, addr: u16, value: u16, cs: &CriticalSection) -> Result<u16, Error> {
@@ -133,6 +156,14 @@ where
     pub fn read_int_cap(&mut self, cs: &CriticalSection) -> Result<u16, Error> {
         self.transfer(address(false, 0x10), 0, cs)
     }
+
+    /// Write the output latches. Data organization is: `[A7..A0, B7..B0]`
+    ///
+    /// Only pins configured as outputs are affected.
+    pub fn write_outputs(&mut self, value: u16, cs: &CriticalSection) -> Result<(), Error> {
+        self.transfer(address(true, 0x14), value, cs)?;
+        Ok(())
+    }
 }
 
 fn address(write: bool, addr: u8) -> u16 {
@@ -162,22 +193,20 @@ pub struct Builder {
 }
 
 impl Builder {
-    pub fn build<I, E, P>(
-        self,
-        spi_lock: Lock<I>,
-        cs: GPIO<P, Output>,
-    ) -> Result<Mcp23S17<I, P>, Error>
+    pub fn build<I, E, C>(self, spi_lock: Lock<I>, cs: C) -> Result<Mcp23S17<I, C>, Error>
     where
         I: Transfer<u16, Error = E>,
         I: Write<u16, Error = E>,
-        P: Pin,
+        C: OutputPin,
+        C::Error: Debug,
     {
         let mut m = Mcp23S17 {
             spi_lock,
             cs,
-            params: self.clone(),
+            params: self,
         };
-        cortex_m::interrupt::free(|cs| m.configure(self, cs))?;
+
+        cortex_m::interrupt::free(|cs| m.configure_verified(cs))?;
 
         Ok(m)
     }
@@ -201,6 +230,14 @@ impl Builder {
         self
     }
 
+    /// Configure all pins as outputs.
+    pub fn set_all_output(mut self) -> Self {
+        for pin in 0..=15 {
+            self = self.output(pin);
+        }
+        self
+    }
+
     /// Configure an input pin. Pins are enumerated from 0. Where pin 0 is
     /// bank A and pin 8 is the first in bank B.
     ///
@@ -259,3 +296,88 @@ pub enum InterruptMode {
     CompareAgainstPrevious,
     CompareAgainst(bool),
 }
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use super::*;
+
+    /// Expander on the SPI bus, garbling the first `glitches` writes of the direction.
+    #[derive(Default)]
+    struct TestChip {
+        regs: [u16; 0x16],
+        glitches: usize,
+    }
+
+    impl Transfer<u16> for TestChip {
+        type Error = Infallible;
+
+        fn transfer<'w>(&mut self, words: &'w mut [u16]) -> Result<&'w [u16], Infallible> {
+            let reg = (words[0] & 0xff) as usize;
+
+            if words[0] & (1 << 8) == 0 {
+                let x = words[1];
+                self.regs[reg] = if reg == 0x00 && self.glitches > 0 {
+                    self.glitches -= 1;
+                    !x
+                } else {
+                    x
+                };
+            } else {
+                words[1] = self.regs[reg];
+            }
+
+            Ok(words)
+        }
+    }
+
+    impl Write<u16> for TestChip {
+        type Error = Infallible;
+
+        fn write(&mut self, _: &[u16]) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    struct TestCs;
+
+    impl OutputPin for TestCs {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    fn expander(glitches: usize) -> Mcp23S17<TestChip, TestCs> {
+        Mcp23S17 {
+            spi_lock: Lock::new(TestChip {
+                glitches,
+                ..Default::default()
+            }),
+            cs: TestCs,
+            params: builder().set_all_pull_up(true),
+        }
+    }
+
+    #[test]
+    fn configure_retries() {
+        let cs = unsafe { CriticalSection::new() };
+
+        let mut m = expander(0);
+        assert!(m.configure_verified(&cs).is_ok());
+        assert_eq!(m.spi_lock.read().regs[0x0c], 0xffff);
+
+        let mut m = expander(CONFIG_ATTEMPTS - 1);
+        assert!(m.configure_verified(&cs).is_ok());
+        assert_eq!(m.spi_lock.read().regs[0x00], 0xffff);
+
+        let mut m = expander(CONFIG_ATTEMPTS);
+        assert!(m.configure_verified(&cs).is_err());
+    }
+}
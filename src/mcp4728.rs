@@ -3,6 +3,7 @@
 //! Driver for MCP4728 4 channel 12-bit DAC.
 //! Datasheet here: <https://ww1.microchip.com/downloads/en/DeviceDoc/22187E.pdf>
 
+use arrayvec::ArrayVec;
 use cortex_m::interrupt::CriticalSection;
 use embedded_hal::blocking::i2c::{Read, Write};
 
@@ -13,7 +14,16 @@ const ADDRESS: u8 = 0b1100_000;
 
 pub struct Mcp4728<I> {
     i2c: Lock<I>,
+    /// Values currently output.
     values: [u16; 4],
+    /// Values we are slewing towards.
+    targets: [u16; 4],
+    /// Max change per update, in LSB. 0 means instant.
+    slew: [u16; 4],
+    /// Values last written to the chip. None before the first write.
+    written: Option<[u16; 4]>,
+    /// Whether to only write changed channels.
+    partial_write: bool,
 }
 
 impl<I, E> Mcp4728<I>
@@ -25,9 +35,41 @@ where
         Mcp4728 {
             i2c,
             values: [0; 4],
+            targets: [0; 4],
+            slew: [0; 4],
+            written: None,
+            partial_write: false,
         }
     }
 
+    /// Only transmit the changed channels, using the "multi-write" command.
+    ///
+    /// The "single write" command also writes the EEPROM, which makes the chip
+    /// busy for ~50ms and is likely why it seemed broken. "Multi-write" only
+    /// writes the input registers, 3 bytes per channel. Provided LDAC is held
+    /// low, the outputs update immediately.
+    ///
+    /// At 400KHz, a byte is ~22.5µS on the bus. A "fast write" of all channels
+    /// is 9 bytes (~200µS), while updating a single channel is 4 bytes (~90µS).
+    pub fn set_partial_write(&mut self, partial_write: bool) {
+        self.partial_write = partial_write;
+    }
+
+    /// Limit how much a channel can change per `set_channels` call, in LSB.
+    /// 0 means no limit (the default).
+    ///
+    /// This is applied on top of whatever the LFO outputs. An LFO that is already
+    /// smooth gains nothing from this and a slow slew here will double-smooth it.
+    /// It's intended for abrupt jumps, such as the random mode.
+    pub fn set_slew(&mut self, channel: usize, lsb_per_update: u16) {
+        self.slew[channel] = lsb_per_update;
+    }
+
+    /// Whether any channel has not yet reached its target.
+    pub fn is_slewing(&self) -> bool {
+        self.values != self.targets
+    }
+
     /// Set the output values for all 4 channels.
     pub fn set_channels(
         &mut self,
@@ -37,13 +79,58 @@ where
         for (i, u) in update.iter().enumerate() {
             if let Some(u) = u {
                 assert!(*u <= 4095);
-                self.values[i] = *u;
+                self.targets[i] = *u;
             }
         }
 
-        // Always write all 4 channels. The "single write" command seems broken in this ADC.
-        let mut i2c = self.i2c.get(cs);
+        // Advance each channel towards the target.
+        for i in 0..4 {
+            let (v, t, s) = (self.values[i], self.targets[i], self.slew[i]);
+
+            self.values[i] = if s == 0 {
+                t
+            } else if t > v {
+                v + (t - v).min(s)
+            } else {
+                v - (v - t).min(s)
+            };
+        }
+
         let v = &self.values;
+
+        let changed = match &self.written {
+            Some(w) => (0..4).filter(|i| w[*i] != v[*i]).count(),
+            None => 4,
+        };
+
+        if changed == 0 {
+            return Ok(());
+        }
+
+        let mut i2c = self.i2c.get(cs);
+
+        // From 3 changed channels, the fast write of all 4 is shorter.
+        if self.partial_write && changed < 3 {
+            let w = self.written.unwrap_or_default();
+            let mut bytes = ArrayVec::<u8, 6>::new();
+
+            for i in 0..4 {
+                if w[i] != v[i] {
+                    // [0 1 0 0 0 DAC1 DAC0 UDAC], [VREF PD1 PD0 Gx D11 D10 D9 D8],
+                    // [D7 D6 D5 D4 D3 D2 D1 D0]. VREF, PD and gain are 0 as for the fast write.
+                    bytes.push(0b0100_0000 | ((i as u8) << 1));
+                    bytes.push((v[i] >> 8) as u8);
+                    bytes.push((v[i] & 0xff) as u8);
+                }
+            }
+
+            i2c.write(ADDRESS, &bytes)?;
+            self.written = Some(*v);
+
+            return Ok(());
+        }
+
+        // Write all 4 channels using "fast write".
         let bytes = &[
             // [0 0 PD1 PD0 D11 D10 D9 D8], [D7 D6 D5 D4 D3 D2 D1 D0] // for PD1 and PD0 we use 0
             (v[0] >> 8) as u8,
@@ -57,6 +144,7 @@ where
         ];
 
         i2c.write(ADDRESS, bytes)?;
+        self.written = Some(*v);
 
         Ok(())
     }
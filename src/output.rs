@@ -1,14 +1,34 @@
 use alg::clock::Time;
+#[cfg(not(test))]
 use bsp::hal::gpio::{Output, GPIO};
+#[cfg(not(test))]
 use imxrt_hal::iomuxc::gpio::Pin;
+#[cfg(not(test))]
 use teensy4_bsp as bsp;
 
+use crate::state::GateMode;
+use crate::state::GateRole;
 use crate::state::State;
 use crate::state::TRACK_COUNT;
 use crate::CPU_SPEED;
 
+/// Shortest gate length in percent of the clock interval when modulated or tapped.
+pub const GATE_LEN_MIN: i64 = 5;
+
+/// Longest gate length in percent of the clock interval when modulated or tapped.
+pub const GATE_LEN_MAX: i64 = 95;
+
+/// Minimum low time between a gate clear and the next set. Some envelope
+/// generators need this to retrigger.
+const GATE_MIN_GAP: Time<{ CPU_SPEED }> = Time::from_millis(1);
+
+/// Max value of a 12-bit LFO.
+const LFO_MAX: i64 = 0xfff;
+
 pub struct Outputs<P1, P2, P3, P4> {
     pub playhead_last: usize,
+    /// Whether the LFO was above the threshold of a gate following it.
+    pub lfo_above: [bool; TRACK_COUNT],
     pub gate1: Gate<P1>,
     pub gate2: Gate<P2>,
     pub gate3: Gate<P3>,
@@ -32,29 +52,251 @@ where
         if playhead != self.playhead_last {
             self.playhead_last = playhead;
 
-            let pats = &state.generated.patterns;
+            for i in 0..TRACK_COUNT {
+                gs[i] = match state.gate_role[i] {
+                    GateRole::Track(tr) => track_gate_set(state, tr),
+                    GateRole::Clock => Set,
+                    GateRole::Reset => pulse_if(state.playhead == 0),
+                    GateRole::BarStart => pulse_if(playhead == 0),
+                    GateRole::EndOfCycle => {
+                        pulse_if(playhead == state.playing().pattern_length as usize - 1)
+                    }
+                    GateRole::Metronome => pulse_if(state.is_beat()),
+                    GateRole::Coincidence(a, b) => pulse_if(hits(state, a) && hits(state, b)),
+                    // Resolved below, once the others are known.
+                    GateRole::Mirror(_) => Retain,
+                    // Follows the other gates after they are ticked.
+                    GateRole::AnyActive(_) => Retain,
+                };
+            }
+
+            let base = gs;
 
             for i in 0..TRACK_COUNT {
-                gs[i] = if state.mute[i] {
-                    Retain
-                } else {
-                    pats[i][state.track_playhead[i]].into()
+                if let GateRole::Mirror(j) = state.gate_role[i] {
+                    // A mirror of a mirror does nothing, to avoid chains.
+                    gs[i] = base[j];
+                }
+            }
+        }
+
+        // A gate following its LFO goes high when crossing above the threshold, and
+        // low when crossing below, regardless of the pattern.
+        for i in 0..TRACK_COUNT {
+            let tr = match state.gate_role[i] {
+                GateRole::Track(tr) => tr,
+                _ => continue,
+            };
+
+            if let Some(threshold) = state.gate_from_lfo[tr] {
+                let above = state.lfo_value[tr] > threshold;
+
+                gs[i] = match (self.lfo_above[i], above) {
+                    (false, true) => Hold,
+                    (true, false) => Clear,
+                    _ => Retain,
                 };
+
+                self.lfo_above[i] = above;
             }
         }
 
+        // A channel without a gate output stays low.
+        for i in 0..TRACK_COUNT {
+            if !state.channel_role[i].has_gate() {
+                gs[i] = Clear;
+            }
+        }
+
+        // Only track gates are modulated. The rest are sync pulses of a fixed width.
+        let track = |i: usize| match state.gate_role[i] {
+            GateRole::Track(tr) => Some(tr),
+            GateRole::Mirror(j) => match state.gate_role[j] {
+                GateRole::Track(tr) => Some(tr),
+                _ => None,
+            },
+            _ => None,
+        };
+        let len = |i| track(i).map(|tr| state.gate_len[tr]).unwrap_or(50);
+        let len_mod = |i| track(i).and_then(|tr| state.gate_len_mod(tr));
+        let width = |i| match track(i) {
+            Some(tr) => state.gate_width(tr),
+            // The downbeat is accented with a click of double width.
+            None if state.gate_role[i] == GateRole::Metronome && playhead == 0 => {
+                let mut w = state.sync_width();
+                w.count *= 2;
+                Some(w)
+            }
+            None => Some(state.sync_width()),
+        };
+        let delay = |i| match track(i) {
+            Some(tr) => state.step_delay(tr),
+            None => state.grid_delay(),
+        };
+        let burst = |i: usize| match gs[i] {
+            Set => track(i).map(|tr| state.burst_count(tr)).unwrap_or(0),
+            _ => 0,
+        };
+
+        self.gate1.set_duty_percent(len(0));
+        self.gate2.set_duty_percent(len(1));
+        self.gate3.set_duty_percent(len(2));
+        self.gate4.set_duty_percent(len(3));
+
+        self.gate1.set_duty_mod(len_mod(0));
+        self.gate2.set_duty_mod(len_mod(1));
+        self.gate3.set_duty_mod(len_mod(2));
+        self.gate4.set_duty_mod(len_mod(3));
+
+        self.gate1.set_duty_width(width(0));
+        self.gate2.set_duty_width(width(1));
+        self.gate3.set_duty_width(width(2));
+        self.gate4.set_duty_width(width(3));
+
+        self.gate1.set_burst(burst(0));
+        self.gate2.set_burst(burst(1));
+        self.gate3.set_burst(burst(2));
+        self.gate4.set_burst(burst(3));
+
+        self.gate1.set_invert(state.gate_invert[0]);
+        self.gate2.set_invert(state.gate_invert[1]);
+        self.gate3.set_invert(state.gate_invert[2]);
+        self.gate4.set_invert(state.gate_invert[3]);
+
+        self.gate1.set_delay(delay(0));
+        self.gate2.set_delay(delay(1));
+        self.gate3.set_delay(delay(2));
+        self.gate4.set_delay(delay(3));
+
         self.gate1.tick(now, gs[0], &state.predicted);
         self.gate2.tick(now, gs[1], &state.predicted);
         self.gate3.tick(now, gs[2], &state.predicted);
         self.gate4.tick(now, gs[3], &state.predicted);
+
+        // An OR output follows the union of the high periods of its gates.
+        let high = self.gate_mask();
+
+        for i in 0..TRACK_COUNT {
+            let mask = match state.gate_role[i] {
+                GateRole::AnyActive(mask) => mask,
+                _ => continue,
+            };
+
+            let any = (0..TRACK_COUNT).any(|j| {
+                j != i
+                    && mask & 1 << j != 0
+                    && high & 1 << j != 0
+                    && !matches!(state.gate_role[j], GateRole::AnyActive(_))
+            });
+
+            let on = any && state.channel_role[i].has_gate();
+
+            match i {
+                0 => self.gate1.follow(now, on),
+                1 => self.gate2.follow(now, on),
+                2 => self.gate3.follow(now, on),
+                _ => self.gate4.follow(now, on),
+            }
+        }
+    }
+}
+
+impl<P1, P2, P3, P4> Outputs<P1, P2, P3, P4>
+where
+    P1: HiLo,
+    P2: HiLo,
+    P3: HiLo,
+    P4: HiLo,
+{
+    /// The logical state of all gates, bit 0-3 for gate 1-4. Set means the gate is high.
+    pub fn gate_mask(&self) -> u8 {
+        (self.gate1.is_high() as u8)
+            | (self.gate2.is_high() as u8) << 1
+            | (self.gate3.is_high() as u8) << 2
+            | (self.gate4.is_high() as u8) << 3
+    }
+
+    /// The inverted gates, bit 0-3 for gate 1-4. Set means the gate is low when on.
+    pub fn invert_mask(&self) -> u8 {
+        (self.gate1.is_inverted() as u8)
+            | (self.gate2.is_inverted() as u8) << 1
+            | (self.gate3.is_inverted() as u8) << 2
+            | (self.gate4.is_inverted() as u8) << 3
+    }
+
+    /// Drive the gates directly, bypassing the state.
+    pub fn tick_gates(
+        &mut self,
+        now: Time<{ CPU_SPEED }>,
+        gs: [GateSet; 4],
+        predicted: &Time<{ CPU_SPEED }>,
+    ) {
+        self.gate1.tick(now, gs[0], predicted);
+        self.gate2.tick(now, gs[1], predicted);
+        self.gate3.tick(now, gs[2], predicted);
+        self.gate4.tick(now, gs[3], predicted);
+    }
+
+    /// Immediately drive all gates low.
+    pub fn panic(&mut self, now: Time<{ CPU_SPEED }>) {
+        let predicted = Time::default();
+        self.gate1.tick(now, GateSet::Clear, &predicted);
+        self.gate2.tick(now, GateSet::Clear, &predicted);
+        self.gate3.tick(now, GateSet::Clear, &predicted);
+        self.gate4.tick(now, GateSet::Clear, &predicted);
+    }
+}
+
+/// The gate set for a track at the current playhead.
+fn track_gate_set(state: &State, tr: usize) -> GateSet {
+    use GateSet::*;
+
+    if state.mute[tr] || state.in_warmup(tr) || state.one_shot_done[tr] {
+        return Retain;
+    }
+
+    let set: GateSet = state.played_step_at(tr, state.track_playhead[tr]).into();
+
+    // Keep the gate high if the next step is also active.
+    let hold = state.gate_mode[tr] == GateMode::Hold
+        && state.played_step_at(tr, state.track_next_step(tr)) != 0;
+
+    match set {
+        Set if hold => Hold,
+        _ => set,
+    }
+}
+
+/// Whether a track has a hit in the current incoming tick. A muted track, or one
+/// held silent, has no hit.
+fn hits(state: &State, tr: usize) -> bool {
+    matches!(track_gate_set(state, tr), GateSet::Set | GateSet::Hold)
+}
+
+/// Pulse on the step if the condition holds, otherwise go low.
+fn pulse_if(cond: bool) -> GateSet {
+    if cond {
+        GateSet::Set
+    } else {
+        GateSet::Clear
     }
 }
 
 pub struct Gate<H> {
     pin: H,
     duty_percent: i64,
+    duty_mod: Option<i64>,
+    duty_width: Option<Time<{ CPU_SPEED }>>,
+    delay: Option<Time<{ CPU_SPEED }>>,
+    burst: u8,
+    burst_left: u8,
+    burst_interval: Option<Time<{ CPU_SPEED }>>,
     clear_at: Option<Time<{ CPU_SPEED }>>,
+    cleared_at: Option<Time<{ CPU_SPEED }>>,
+    set_at: Option<(GateSet, Time<{ CPU_SPEED }>)>,
+    min_gap: Time<{ CPU_SPEED }>,
     high: bool,
+    invert: bool,
 }
 
 impl<H> Gate<H>
@@ -65,15 +307,85 @@ where
         Gate {
             pin,
             duty_percent: duty_percent as i64,
+            duty_mod: None,
+            duty_width: None,
+            delay: None,
+            burst: 0,
+            burst_left: 0,
+            burst_interval: None,
             clear_at: None,
+            cleared_at: None,
+            set_at: None,
+            min_gap: GATE_MIN_GAP,
             high: false,
+            invert: false,
         }
     }
 
+    /// The logical state of the gate, regardless of inversion.
     pub fn is_high(&self) -> bool {
         self.high
     }
 
+    pub fn is_inverted(&self) -> bool {
+        self.invert
+    }
+
+    /// Invert the pin, so that it's low when the gate is high. Takes effect
+    /// straight away.
+    pub fn set_invert(&mut self, invert: bool) {
+        if invert != self.invert {
+            self.invert = invert;
+            self.pin.set_hilo(self.high != invert);
+        }
+    }
+
+    /// Modulate the duty cycle from an LFO value. The modulated duty cycle is
+    /// bounded by the min/max gate length. `None` reverts to the configured duty.
+    pub fn set_duty_mod(&mut self, lfo: Option<u16>) {
+        self.duty_mod =
+            lfo.map(|v| GATE_LEN_MIN + ((GATE_LEN_MAX - GATE_LEN_MIN) * v as i64) / LFO_MAX);
+    }
+
+    /// Gate length in percent of the step, unless modulated or of a fixed width.
+    pub fn set_duty_percent(&mut self, percent: u8) {
+        self.duty_percent = percent as i64;
+    }
+
+    /// Use a fixed width for the duty cycle. `None` reverts to the duty percent.
+    pub fn set_duty_width(&mut self, width: Option<Time<{ CPU_SPEED }>>) {
+        self.duty_width = width;
+    }
+
+    /// Fire a burst of `count` triggers spread over the step on the next set.
+    /// 0 or 1 is a plain gate.
+    pub fn set_burst(&mut self, count: u8) {
+        self.burst = count;
+    }
+
+    /// Delay the next gate set, such as for swing. `None` sets straight away.
+    pub fn set_delay(&mut self, delay: Option<Time<{ CPU_SPEED }>>) {
+        self.delay = delay;
+    }
+
+    /// Set the minimum low time between a gate clear and the next set.
+    pub fn set_min_gap(&mut self, min_gap: Time<{ CPU_SPEED }>) {
+        self.min_gap = min_gap;
+    }
+
+    /// Drive the gate to a level, such as the union of other gates. No duty
+    /// cycle, delay or retrigger gap applies.
+    pub fn follow(&mut self, now: Time<{ CPU_SPEED }>, on: bool) {
+        if on && !self.high {
+            self.pin.set_hilo(!self.invert);
+            self.high = true;
+            self.clear_at.take();
+            self.set_at.take();
+        } else if !on && self.high {
+            self.clear(now);
+        }
+    }
+
     /// Tick to drive the gates. Whether to set, clear or retain the gate state.
     ///
     /// The predicted time next clock tick is happening.
@@ -83,35 +395,140 @@ where
         set: GateSet,
         predicted: &Time<{ CPU_SPEED }>,
     ) {
+        if let GateSet::Set | GateSet::Hold = set {
+            self.start_burst(set, predicted);
+        }
+
         match set {
             GateSet::Retain => {
+                if let Some((set, set_at)) = self.set_at {
+                    if now >= set_at {
+                        self.set_at.take();
+                        self.set_with_gap(now, set, predicted);
+                    }
+                }
+
                 if let Some(clear_at) = self.clear_at {
                     if now >= clear_at {
-                        self.clear_at.take();
-                        self.pin.set_hilo(false);
-                        self.high = false;
+                        self.clear(now);
                     }
                 }
             }
 
-            GateSet::Set => {
-                self.pin.set_hilo(true);
-                self.high = true;
-
-                let duty_count = (predicted.count() * self.duty_percent) / 100;
+            GateSet::Set | GateSet::Hold if self.delay.is_some() => {
+                let mut set_at = now;
+                set_at.count += self.delay.map(|d| d.count()).unwrap_or(0);
+                self.set_at = Some((set, set_at));
+            }
 
-                let mut clear_at = now.clone();
-                clear_at.count += duty_count;
-                self.clear_at = Some(clear_at);
+            GateSet::Set | GateSet::Hold => {
+                self.set_with_gap(now, set, predicted);
             }
 
             GateSet::Clear => {
-                self.pin.set_hilo(false);
-                self.high = false;
+                if self.high {
+                    self.clear(now);
+                }
+
+                self.set_at.take();
+                self.burst_left = 0;
+            }
+        }
+    }
+
+    /// Prepare the burst, if any, for a new step. A held gate can't burst.
+    fn start_burst(&mut self, set: GateSet, predicted: &Time<{ CPU_SPEED }>) {
+        match set {
+            GateSet::Set if self.burst > 1 => {
+                let mut interval = Time::default();
+                interval.count = predicted.count() / self.burst as i64;
+
+                self.burst_interval = Some(interval);
+                self.burst_left = self.burst - 1;
+            }
+            _ => {
+                self.burst_interval = None;
+                self.burst_left = 0;
+            }
+        }
+    }
+
+    /// Set the gate, unless that would come too soon after the last clear. Then the
+    /// set is postponed until the gap has passed.
+    fn set_with_gap(
+        &mut self,
+        now: Time<{ CPU_SPEED }>,
+        set: GateSet,
+        predicted: &Time<{ CPU_SPEED }>,
+    ) {
+        // A gate still in its duty cycle would merge with this one.
+        let merging = self.high && self.clear_at.is_some();
+
+        let recently_cleared = self
+            .cleared_at
+            .map(|c| !self.high && now - c < self.min_gap)
+            .unwrap_or(false);
+
+        if merging {
+            // Force low for the gap, so downstream sees a retrigger.
+            self.clear(now);
+        }
+
+        if merging || recently_cleared {
+            // Already low, the gap counts from the clear.
+            let mut set_at = self.cleared_at.unwrap_or(now);
+            set_at.count += self.min_gap.count();
+            self.set_at = Some((set, set_at));
+        } else {
+            self.set(now, set, predicted);
+        }
+    }
 
-                self.clear_at.take();
+    fn set(&mut self, now: Time<{ CPU_SPEED }>, set: GateSet, predicted: &Time<{ CPU_SPEED }>) {
+        self.pin.set_hilo(!self.invert);
+        self.high = true;
+
+        if let Some(interval) = self.burst_interval {
+            // Half the burst interval high, and schedule the next trigger.
+            let mut clear_at = now;
+            clear_at.count += interval.count() / 2;
+            self.clear_at = Some(clear_at);
+
+            if self.burst_left > 0 {
+                self.burst_left -= 1;
+
+                let mut next = now;
+                next.count += interval.count();
+                self.set_at = Some((GateSet::Set, next));
             }
+
+            return;
         }
+
+        if let GateSet::Hold = set {
+            // No duty cycle, the gate is cleared by the next rest.
+            self.clear_at.take();
+            return;
+        }
+
+        let duty_count = if let Some(w) = self.duty_width {
+            w.count()
+        } else {
+            let duty_percent = self.duty_mod.unwrap_or(self.duty_percent);
+            (predicted.count() * duty_percent) / 100
+        };
+
+        let mut clear_at = now.clone();
+        clear_at.count += duty_count;
+        self.clear_at = Some(clear_at);
+    }
+
+    fn clear(&mut self, now: Time<{ CPU_SPEED }>) {
+        self.pin.set_hilo(self.invert);
+        self.high = false;
+
+        self.clear_at.take();
+        self.cleared_at = Some(now);
     }
 }
 
@@ -121,6 +538,8 @@ pub enum GateSet {
     Retain,
     /// Set the gate and keep it high for the duty cycle.
     Set,
+    /// Set the gate and keep it high until cleared.
+    Hold,
     /// Clear the gate and clear any pending duty cycle.
     Clear,
 }
@@ -139,6 +558,7 @@ pub trait HiLo {
     fn set_hilo(&mut self, hi: bool);
 }
 
+#[cfg(not(test))]
 impl<P> HiLo for GPIO<P, Output>
 where
     P: Pin,
@@ -151,3 +571,294 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pin remembering the level it was last driven to.
+    #[derive(Default)]
+    struct TestPin {
+        hi: bool,
+    }
+
+    impl HiLo for TestPin {
+        fn set_hilo(&mut self, hi: bool) {
+            self.hi = hi;
+        }
+    }
+
+    type TestOutputs = Outputs<TestPin, TestPin, TestPin, TestPin>;
+
+    fn outputs() -> TestOutputs {
+        Outputs {
+            playhead_last: 0,
+            lfo_above: [false; 4],
+            gate1: Gate::new(TestPin::default(), 50),
+            gate2: Gate::new(TestPin::default(), 50),
+            gate3: Gate::new(TestPin::default(), 50),
+            gate4: Gate::new(TestPin::default(), 50),
+        }
+    }
+
+    /// The levels of the pins, bit 0-3 for gate 1-4.
+    fn pin_mask(o: &TestOutputs) -> u8 {
+        (o.gate1.pin.hi as u8)
+            | (o.gate2.pin.hi as u8) << 1
+            | (o.gate3.pin.hi as u8) << 2
+            | (o.gate4.pin.hi as u8) << 3
+    }
+
+    fn ms(v: i64) -> Time<{ CPU_SPEED }> {
+        us(v * 1000)
+    }
+
+    fn us(v: i64) -> Time<{ CPU_SPEED }> {
+        let mut t = Time::default();
+        t.count = v * (CPU_SPEED / 1_000_000) as i64;
+        t
+    }
+
+    #[test]
+    fn gate_duty_cycle() {
+        let mut gate = Gate::new(TestPin::default(), 50);
+
+        gate.tick(ms(0), GateSet::Set, &ms(10));
+        assert!(gate.pin.hi);
+
+        gate.tick(ms(4), GateSet::Retain, &ms(10));
+        assert!(gate.pin.hi);
+
+        gate.tick(ms(5), GateSet::Retain, &ms(10));
+        assert!(!gate.pin.hi);
+        assert!(!gate.is_high());
+    }
+
+    #[test]
+    fn gate_duty_mod_clamps() {
+        let mut gate = Gate::new(TestPin::default(), 50);
+
+        // Bottom of the LFO is the shortest gate.
+        gate.set_duty_mod(Some(0));
+        gate.tick(ms(0), GateSet::Set, &ms(100));
+        gate.tick(ms(4), GateSet::Retain, &ms(100));
+        assert!(gate.pin.hi);
+        gate.tick(ms(5), GateSet::Retain, &ms(100));
+        assert!(!gate.pin.hi);
+
+        // Top of the LFO is the longest gate.
+        gate.set_duty_mod(Some(0xfff));
+        gate.tick(ms(100), GateSet::Set, &ms(100));
+        gate.tick(ms(194), GateSet::Retain, &ms(100));
+        assert!(gate.pin.hi);
+        gate.tick(ms(195), GateSet::Retain, &ms(100));
+        assert!(!gate.pin.hi);
+    }
+
+    #[test]
+    fn gate_inverted() {
+        let mut gate = Gate::new(TestPin::default(), 50);
+
+        gate.set_invert(true);
+        assert!(gate.pin.hi);
+
+        gate.tick(ms(0), GateSet::Set, &ms(10));
+        assert!(gate.is_high());
+        assert!(!gate.pin.hi);
+
+        gate.tick(ms(5), GateSet::Retain, &ms(10));
+        assert!(!gate.is_high());
+        assert!(gate.pin.hi);
+
+        // Reverting the inversion takes effect straight away.
+        gate.set_invert(false);
+        assert!(!gate.pin.hi);
+    }
+
+    #[test]
+    fn gate_gap_after_clear() {
+        let mut gate = Gate::new(TestPin::default(), 50);
+
+        gate.tick(ms(0), GateSet::Set, &ms(10));
+        gate.tick(ms(5), GateSet::Retain, &ms(10));
+        assert!(!gate.pin.hi);
+
+        // Too soon after the clear, postponed until the gap has passed.
+        gate.tick(us(5500), GateSet::Set, &ms(10));
+        assert!(!gate.pin.hi);
+        gate.tick(us(5900), GateSet::Retain, &ms(10));
+        assert!(!gate.pin.hi);
+        gate.tick(ms(6), GateSet::Retain, &ms(10));
+        assert!(gate.pin.hi);
+    }
+
+    #[test]
+    fn gate_gap_when_merging() {
+        let mut gate = Gate::new(TestPin::default(), 50);
+
+        gate.tick(ms(0), GateSet::Set, &ms(10));
+
+        // A set in the duty cycle is forced low for the gap, to retrigger.
+        gate.tick(ms(3), GateSet::Set, &ms(10));
+        assert!(!gate.pin.hi);
+        gate.tick(us(3900), GateSet::Retain, &ms(10));
+        assert!(!gate.pin.hi);
+        gate.tick(ms(4), GateSet::Retain, &ms(10));
+        assert!(gate.pin.hi);
+    }
+
+    #[test]
+    fn gate_gap_when_delayed() {
+        let mut gate = Gate::new(TestPin::default(), 50);
+        gate.set_delay(Some(ms(2)));
+        gate.set_duty_width(Some(us(9500)));
+
+        gate.tick(ms(0), GateSet::Set, &ms(10));
+        assert!(!gate.pin.hi);
+        gate.tick(ms(2), GateSet::Retain, &ms(10));
+        assert!(gate.pin.hi);
+
+        // The next step is delayed to 12ms, only 0.5ms after the clear.
+        gate.tick(ms(10), GateSet::Set, &ms(10));
+        gate.tick(us(11500), GateSet::Retain, &ms(10));
+        assert!(!gate.pin.hi);
+        gate.tick(ms(12), GateSet::Retain, &ms(10));
+        assert!(!gate.pin.hi);
+        gate.tick(us(12500), GateSet::Retain, &ms(10));
+        assert!(gate.pin.hi);
+    }
+
+    #[test]
+    fn gate_mask() {
+        use GateSet::*;
+
+        let mut o = outputs();
+
+        o.tick_gates(ms(0), [Set, Clear, Hold, Clear], &ms(10));
+        assert_eq!(o.gate_mask(), 0b0101);
+
+        // The mask is the logical state, regardless of inversion.
+        o.gate3.set_invert(true);
+        assert_eq!(o.gate_mask(), 0b0101);
+        assert_eq!(pin_mask(&o), 0b0001);
+
+        o.panic(ms(1));
+        assert_eq!(o.gate_mask(), 0);
+    }
+
+    #[test]
+    fn gate_invert_from_state() {
+        let mut o = outputs();
+        let mut st = State::new();
+
+        st.gate_invert[1] = true;
+        st.gate_invert[3] = true;
+        o.tick(ms(0), &st);
+
+        // Idle inverted gates are high.
+        assert_eq!(o.gate_mask(), 0);
+        assert_eq!(pin_mask(&o), 0b1010);
+        assert_eq!(o.invert_mask(), 0b1010);
+
+        st.gate_invert[1] = false;
+        o.tick(ms(1), &st);
+        assert_eq!(pin_mask(&o), 0b1000);
+        assert_eq!(o.invert_mask(), 0b1000);
+    }
+
+    #[test]
+    fn gate_follows_lfo() {
+        let mut o = outputs();
+        let mut st = State::new();
+
+        st.gate_from_lfo[1] = Some(0x800);
+        st.lfo_value[1] = 0x100;
+        o.tick(ms(0), &st);
+        assert_eq!(o.gate_mask(), 0);
+
+        // High for as long as the LFO stays above.
+        st.lfo_value[1] = 0x900;
+        o.tick(ms(1), &st);
+        assert_eq!(o.gate_mask(), 0b0010);
+        st.lfo_value[1] = 0xfff;
+        o.tick(ms(500), &st);
+        assert_eq!(o.gate_mask(), 0b0010);
+
+        st.lfo_value[1] = 0x700;
+        o.tick(ms(501), &st);
+        assert_eq!(o.gate_mask(), 0);
+    }
+
+    #[test]
+    fn gate_len_from_lfo() {
+        let mut o = outputs();
+        let mut st = State::new();
+
+        // Track 1 gate length follows the LFO of track 3.
+        st.gate_len_mod_src[0] = Some(2);
+        st.lfo_value[2] = 0;
+        o.tick(ms(0), &st);
+        assert_eq!(o.gate1.duty_mod, Some(GATE_LEN_MIN));
+        assert_eq!(o.gate2.duty_mod, None);
+
+        st.lfo_value[2] = 0xfff;
+        o.tick(ms(1), &st);
+        assert_eq!(o.gate1.duty_mod, Some(GATE_LEN_MAX));
+
+        // A gate mirroring the track is modulated alike, a sync pulse isn't.
+        st.gate_role[1] = GateRole::Mirror(0);
+        st.gate_role[2] = GateRole::Clock;
+        st.gate_len_mod_src[2] = Some(2);
+        o.tick(ms(2), &st);
+        assert_eq!(o.gate2.duty_mod, Some(GATE_LEN_MAX));
+        assert_eq!(o.gate3.duty_mod, None);
+    }
+
+    #[test]
+    fn gate_any_active() {
+        let mut o = outputs();
+        let mut st = State::new();
+
+        // Gates 1 and 2 follow their LFOs, gate 4 is their union.
+        st.gate_from_lfo[0] = Some(0x800);
+        st.gate_from_lfo[1] = Some(0x800);
+        st.gate_role[3] = GateRole::AnyActive(0b1011);
+
+        let mut step = |o: &mut TestOutputs, t, a, b| {
+            st.lfo_value[0] = a;
+            st.lfo_value[1] = b;
+            o.tick(ms(t), &st);
+            o.gate_mask()
+        };
+
+        assert_eq!(step(&mut o, 0, 0, 0), 0);
+        assert_eq!(step(&mut o, 1, 0xfff, 0), 0b1001);
+        assert_eq!(step(&mut o, 2, 0xfff, 0xfff), 0b1011);
+        assert_eq!(step(&mut o, 3, 0, 0xfff), 0b1010);
+        assert_eq!(step(&mut o, 4, 0, 0), 0);
+    }
+
+    #[test]
+    fn gate_coincidence() {
+        let mut o = outputs();
+        let mut st = State::new();
+        st.gate_role[3] = GateRole::Coincidence(0, 1);
+
+        // Track 1 hits on step 1 and 2, track 2 on step 2 and 3.
+        for step in 1..=4 {
+            st.overrides.set(0, step, step == 1 || step == 2);
+            st.overrides.set(1, step, step == 2 || step == 3);
+        }
+
+        let mut pulses = vec![];
+
+        for step in 1..=4 {
+            st.playhead = step as u64;
+            st.track_playhead = [step; TRACK_COUNT];
+            o.tick(ms(100 * step as i64), &st);
+            pulses.push(o.gate4.is_high());
+        }
+
+        assert_eq!(pulses, [false, true, false, false]);
+    }
+}
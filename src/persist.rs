@@ -0,0 +1,845 @@
+//! Serialized layout of the user settings.
+//!
+//! There is no storage yet, the settings go to and from a computer over USB. The
+//! layout is versioned so that settings saved by an older firmware still load. New
+//! fields are appended at the end with a new version, and loading an older version
+//! leaves them at their defaults. Loading refuses any field out of its range, rather
+//! than guessing what was meant.
+//!
+//! Version 1:
+//!
+//! ```ignore
+//! [MAGIC, version]
+//! global: seed (u32 le), extended seed range, pattern length, length mode, transport,
+//!         input ppqn, clock mult, swing, swing resolution, lfo seed hold, link role,
+//!         sync width
+//! per track: length, steps, offset, lfo mode, lfo coupling, lfo retrigger,
+//!            lfo depth (u16 le), track sync, mute, algo, gate mode, gate role,
+//!            anchor (0xff for none), burst chance, humanize, track div (unused, 1)
+//! ```
+//!
+//! Version 2 appends:
+//!
+//! ```ignore
+//! per track: gate length
+//! ```
+//!
+//! Version 3 appends:
+//!
+//! ```ignore
+//! global: fate enabled
+//! ```
+//!
+//! Version 4 appends:
+//!
+//! ```ignore
+//! per track: track delay
+//! ```
+//!
+//! Version 5 appends:
+//!
+//! ```ignore
+//! global: clock stop
+//! ```
+//!
+//! Version 6 appends:
+//!
+//! ```ignore
+//! per gate output: gate invert
+//! ```
+//!
+//! Version 7 appends:
+//!
+//! ```ignore
+//! per channel: channel role
+//! ```
+//!
+//! Version 8 appends:
+//!
+//! ```ignore
+//! per track: lfo gate threshold (u16 le, 0 for off)
+//! ```
+//!
+//! Version 9 appends:
+//!
+//! ```ignore
+//! global: clock quantize
+//! ```
+//!
+//! Version 10 appends:
+//!
+//! ```ignore
+//! per track: warmup
+//! ```
+//!
+//! Version 11 appends:
+//!
+//! ```ignore
+//! global: offset absolute
+//! ```
+//!
+//! Version 12 appends:
+//!
+//! ```ignore
+//! global: coarse seed step (u16 le)
+//! ```
+//!
+//! Version 13 appends:
+//!
+//! ```ignore
+//! global: no empty tracks
+//! ```
+//!
+//! Version 14 appends:
+//!
+//! ```ignore
+//! per channel: cv behavior
+//! ```
+//!
+//! Version 15 appends:
+//!
+//! ```ignore
+//! per track: step override of each of the MAX_LEN steps (0 none, 1 off, 2 on)
+//! per track: step value of each of the MAX_LEN steps (0xff for none)
+//! per dac channel: track
+//! ```
+//!
+//! Version 16 appends:
+//!
+//! ```ignore
+//! per track: lfo modulating the gate length (0xff for none)
+//! ```
+
+use alg::gen::SEED_BASE;
+
+use crate::algo::Algo;
+use crate::lfo::{Coupling, Mode, DEPTH_MAX};
+use crate::link::LinkRole;
+use crate::output::{GATE_LEN_MAX, GATE_LEN_MIN};
+use crate::state::State;
+use crate::state::{ChannelRole, ClockStop, CvBehavior, GateMode, GateRole};
+use crate::state::{DacMap, STEP_VALUE_MAX};
+use crate::state::{LengthMode, SwingResolution, TrackSync, Transport};
+use crate::state::{CLOCK_MULT_CHOICES, INPUT_PPQN_CHOICES, SEED_COARSE_CHOICES};
+use crate::state::{HUMANIZE_MAX, TRACK_DELAY_MAX, WARMUP_MAX};
+use crate::state::{MAX_LEN, SEED_MAX, SEED_MAX_EXTENDED, TRACK_COUNT};
+
+/// First byte of saved settings, to tell them apart from erased storage.
+const MAGIC: u8 = 0x5e;
+
+/// Current version of the layout.
+const VERSION: u8 = 16;
+
+/// Bytes of the header and global settings.
+const GLOBAL_LEN: usize = 2 + 4 + 11;
+
+/// Bytes per track.
+const TRACK_LEN: usize = 17;
+
+/// Bytes added in version 2.
+const V2_LEN: usize = TRACK_COUNT;
+
+/// Bytes added in version 3.
+const V3_LEN: usize = 1;
+
+/// Bytes added in version 4.
+const V4_LEN: usize = TRACK_COUNT;
+
+/// Bytes added in version 5.
+const V5_LEN: usize = 1;
+
+/// Bytes added in version 6.
+const V6_LEN: usize = TRACK_COUNT;
+
+/// Bytes added in version 7.
+const V7_LEN: usize = TRACK_COUNT;
+
+/// Bytes added in version 8.
+const V8_LEN: usize = TRACK_COUNT * 2;
+
+/// Bytes added in version 9.
+const V9_LEN: usize = 1;
+
+/// Bytes added in version 10.
+const V10_LEN: usize = TRACK_COUNT;
+
+/// Bytes added in version 11.
+const V11_LEN: usize = 1;
+
+/// Bytes added in version 12.
+const V12_LEN: usize = 2;
+
+/// Bytes added in version 13.
+const V13_LEN: usize = 1;
+
+/// Bytes added in version 14.
+const V14_LEN: usize = TRACK_COUNT;
+
+/// Bytes added in version 15.
+const V15_LEN: usize = TRACK_COUNT * MAX_LEN * 2 + TRACK_COUNT;
+
+/// Bytes added in version 16.
+const V16_LEN: usize = TRACK_COUNT;
+
+/// Total length of the current version.
+pub const LEN: usize = GLOBAL_LEN
+    + TRACK_COUNT * TRACK_LEN
+    + V2_LEN
+    + V3_LEN
+    + V4_LEN
+    + V5_LEN
+    + V6_LEN
+    + V7_LEN
+    + V8_LEN
+    + V9_LEN
+    + V10_LEN
+    + V11_LEN
+    + V12_LEN
+    + V13_LEN
+    + V14_LEN
+    + V15_LEN
+    + V16_LEN;
+
+/// Serialize the user settings of the state.
+pub fn save(st: &State) -> [u8; LEN] {
+    let mut w = Writer {
+        buf: [0; LEN],
+        pos: 0,
+    };
+
+    w.u8(MAGIC);
+    w.u8(VERSION);
+
+    w.u32(st.params.seed);
+    w.bool(st.seed_max == SEED_MAX_EXTENDED);
+    w.u8(st.params.pattern_length);
+    w.u8(st.length_mode as u8);
+    w.u8(st.transport as u8);
+    w.u8(st.input_ppqn);
+    w.u8(st.clock_mult as u8);
+    w.u8(st.swing);
+    w.u8(st.swing_resolution as u8);
+    w.bool(st.lfo_seed_hold);
+    w.u8(st.link_role as u8);
+    w.u8(st.sync_width_ms);
+
+    for tr in 0..TRACK_COUNT {
+        let t = &st.params.tracks[tr];
+        let lfo = &st.lfo[tr];
+
+        w.u8(t.length);
+        w.u8(t.steps);
+        w.u8(t.offset);
+        w.u8(lfo.mode as u8);
+        w.u8(lfo.coupling as u8);
+        w.bool(lfo.retrigger);
+        w.u16(lfo.depth);
+        w.u8(st.track_sync[tr] as u8);
+        w.bool(st.mute[tr]);
+        w.u8(st.algo[tr] as u8);
+        w.u8(st.gate_mode[tr] as u8);
+        w.u8(st.gate_role[tr].index() as u8);
+        w.u8(st.anchor[tr].unwrap_or(0xff));
+        w.u8(st.burst_chance[tr]);
+        w.u8(st.humanize[tr]);
+        // The track division is gone, the clock multiplier is global.
+        w.u8(1);
+    }
+
+    // Version 2
+    for tr in 0..TRACK_COUNT {
+        w.u8(st.gate_len[tr]);
+    }
+
+    // Version 3
+    w.bool(st.fate_enabled);
+
+    // Version 4
+    for tr in 0..TRACK_COUNT {
+        w.u8(st.track_delay[tr]);
+    }
+
+    // Version 5
+    w.u8(st.clock_stop as u8);
+
+    // Version 6
+    for i in 0..TRACK_COUNT {
+        w.bool(st.gate_invert[i]);
+    }
+
+    // Version 7
+    for i in 0..TRACK_COUNT {
+        w.u8(st.channel_role[i] as u8);
+    }
+
+    // Version 8
+    for tr in 0..TRACK_COUNT {
+        w.u16(st.gate_from_lfo[tr].unwrap_or(0));
+    }
+
+    // Version 9
+    w.bool(st.clock_quantize);
+
+    // Version 10
+    for tr in 0..TRACK_COUNT {
+        w.u8(st.warmup[tr]);
+    }
+
+    // Version 11
+    w.bool(st.offset_absolute);
+
+    // Version 12
+    w.u16(st.seed_coarse);
+
+    // Version 13
+    w.bool(st.no_empty);
+
+    // Version 14
+    for i in 0..TRACK_COUNT {
+        w.u8(st.cv_behavior[i] as u8);
+    }
+
+    // Version 15
+    for tr in 0..TRACK_COUNT {
+        for step in 0..MAX_LEN {
+            w.u8(match st.overrides.get(tr, step) {
+                None => 0,
+                Some(false) => 1,
+                Some(true) => 2,
+            });
+        }
+    }
+    for tr in 0..TRACK_COUNT {
+        for step in 0..MAX_LEN {
+            w.u8(st.step_values.get(tr, step).unwrap_or(0xff));
+        }
+    }
+    for ch in 0..TRACK_COUNT {
+        w.u8(st.dac_map.track(ch) as u8);
+    }
+
+    // Version 16
+    for tr in 0..TRACK_COUNT {
+        w.u8(st.gate_len_mod_src[tr].map(|s| s as u8).unwrap_or(0xff));
+    }
+
+    assert!(w.pos == LEN);
+
+    w.buf
+}
+
+/// Restore serialized settings onto a copy of the live state. Only the settings
+/// change, the tempo, playhead and LFO phases carry on. Settings of an older version
+/// load with the fields added since at their defaults. None if the bytes aren't saved
+/// settings, are from a newer firmware, or have any field out of range.
+pub fn load(buf: &[u8], live: &State) -> Option<State> {
+    let mut st = live.clone();
+
+    // The defaults first, for the fields an older version doesn't have.
+    read(&save(&State::new()), &mut st)?;
+    read(buf, &mut st)?;
+
+    st.regenerate();
+
+    Some(st)
+}
+
+/// Read the settings of any version onto the state.
+fn read(buf: &[u8], st: &mut State) -> Option<()> {
+    let mut r = Reader { buf, pos: 0 };
+
+    if r.u8()? != MAGIC {
+        return None;
+    }
+
+    let version = r.u8()?;
+
+    if version == 0 || version > VERSION {
+        return None;
+    }
+
+    // Version 1
+    st.params.seed = r.u32()?;
+    st.seed_max = if r.bool()? {
+        SEED_MAX_EXTENDED
+    } else {
+        SEED_MAX
+    };
+    let seed = st.params.seed.checked_sub(SEED_BASE as u32)?;
+    if seed > st.seed_max {
+        return None;
+    }
+    st.params.pattern_length = r.range(2, MAX_LEN as u8)?;
+    st.length_mode = r.enumeration(LengthMode::len())?.into();
+    st.transport = r.enumeration(Transport::len())?.into();
+    st.input_ppqn = r.choice(&INPUT_PPQN_CHOICES)?;
+    st.clock_mult = r.u8()? as i8;
+    if !CLOCK_MULT_CHOICES.contains(&st.clock_mult) {
+        return None;
+    }
+    st.swing = r.range(50, 75)?;
+    st.swing_resolution = r.enumeration(SwingResolution::len())?.into();
+    st.lfo_seed_hold = r.bool()?;
+    st.link_role = r.enumeration(LinkRole::len())?.into();
+    st.sync_width_ms = r.range(1, 50)?;
+
+    for tr in 0..TRACK_COUNT {
+        let t = &mut st.params.tracks[tr];
+        t.length = r.range(2, MAX_LEN as u8)?;
+        t.steps = r.range(0, t.length)?;
+        t.offset = r.range(0, t.length - 1)?;
+        let length = t.length;
+
+        let lfo = &mut st.lfo[tr];
+        let mode = r.enumeration(Mode::len())?;
+        lfo.set_mode(mode - lfo.mode as i8);
+        let coupling = r.enumeration(Coupling::len())?;
+        lfo.set_coupling(coupling - lfo.coupling as i8);
+        lfo.set_retrigger(r.bool()?);
+        let depth = r.u16()?;
+        if depth > DEPTH_MAX {
+            return None;
+        }
+        lfo.set_depth(depth);
+
+        st.track_sync[tr] = r.enumeration(TrackSync::len())?.into();
+        st.mute[tr] = r.bool()?;
+        st.algo[tr] = r.enumeration(Algo::len())?.into();
+        st.gate_mode[tr] = r.enumeration(GateMode::len())?.into();
+        st.gate_role[tr] = r.enumeration(GateRole::len())?.into();
+        st.anchor[tr] = match r.u8()? {
+            0xff => None,
+            a if a < length => Some(a),
+            _ => return None,
+        };
+        st.burst_chance[tr] = r.range(0, 100)?;
+        st.humanize[tr] = r.range(0, HUMANIZE_MAX as u8)?;
+        // The track division is gone, the clock multiplier is global.
+        r.range(1, u8::MAX)?;
+    }
+
+    if version >= 2 {
+        for tr in 0..TRACK_COUNT {
+            st.gate_len[tr] = r.range(GATE_LEN_MIN as u8, GATE_LEN_MAX as u8)?;
+        }
+    }
+
+    if version >= 3 {
+        st.fate_enabled = r.bool()?;
+    }
+
+    if version >= 4 {
+        for tr in 0..TRACK_COUNT {
+            st.track_delay[tr] = r.range(0, TRACK_DELAY_MAX as u8)?;
+        }
+    }
+
+    if version >= 5 {
+        st.clock_stop = r.enumeration(ClockStop::len())?.into();
+    }
+
+    if version >= 6 {
+        for i in 0..TRACK_COUNT {
+            st.gate_invert[i] = r.bool()?;
+        }
+    }
+
+    if version >= 7 {
+        for i in 0..TRACK_COUNT {
+            st.channel_role[i] = r.enumeration(ChannelRole::len())?.into();
+        }
+    }
+
+    if version >= 8 {
+        for tr in 0..TRACK_COUNT {
+            st.gate_from_lfo[tr] = match r.u16()? {
+                0 => None,
+                t if t <= DEPTH_MAX => Some(t),
+                _ => return None,
+            };
+        }
+    }
+
+    if version >= 9 {
+        st.clock_quantize = r.bool()?;
+    }
+
+    if version >= 10 {
+        for tr in 0..TRACK_COUNT {
+            st.warmup[tr] = r.range(0, WARMUP_MAX as u8)?;
+        }
+    }
+
+    if version >= 11 {
+        st.offset_absolute = r.bool()?;
+    }
+
+    if version >= 12 {
+        let c = r.u16()?;
+        if !SEED_COARSE_CHOICES.contains(&c) {
+            return None;
+        }
+        st.seed_coarse = c;
+    }
+
+    if version >= 13 {
+        st.no_empty = r.bool()?;
+    }
+
+    if version >= 14 {
+        for i in 0..TRACK_COUNT {
+            st.cv_behavior[i] = match r.u8()? {
+                0 => CvBehavior::Lfo,
+                1 => CvBehavior::StepValue,
+                _ => return None,
+            };
+        }
+    }
+
+    if version >= 15 {
+        for tr in 0..TRACK_COUNT {
+            st.overrides.clear(tr);
+            for step in 0..MAX_LEN {
+                match r.u8()? {
+                    0 => {}
+                    1 => st.overrides.set(tr, step, false),
+                    2 => st.overrides.set(tr, step, true),
+                    _ => return None,
+                }
+            }
+        }
+        for tr in 0..TRACK_COUNT {
+            st.step_values.clear(tr);
+            for step in 0..MAX_LEN {
+                match r.u8()? {
+                    0xff => {}
+                    v if v <= STEP_VALUE_MAX => st.step_values.set(tr, step, Some(v)),
+                    _ => return None,
+                }
+            }
+        }
+        let mut map = [0; TRACK_COUNT];
+        for m in map.iter_mut() {
+            *m = r.u8()? as usize;
+        }
+        st.dac_map = DacMap::new(map).ok()?;
+    }
+
+    if version >= 16 {
+        for tr in 0..TRACK_COUNT {
+            st.gate_len_mod_src[tr] = match r.u8()? {
+                0xff => None,
+                s if (s as usize) < TRACK_COUNT => Some(s as usize),
+                _ => return None,
+            };
+        }
+    }
+
+    // Trailing bytes are not settings of this version.
+    if r.pos != buf.len() {
+        return None;
+    }
+
+    Some(())
+}
+
+struct Writer {
+    buf: [u8; LEN],
+    pos: usize,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.buf[self.pos] = v;
+        self.pos += 1;
+    }
+
+    fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    fn u16(&mut self, v: u16) {
+        for b in v.to_le_bytes().iter() {
+            self.u8(*b);
+        }
+    }
+
+    fn u32(&mut self, v: u32) {
+        for b in v.to_le_bytes().iter() {
+            self.u8(*b);
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        let v = *self.buf.get(self.pos)?;
+        self.pos += 1;
+        Some(v)
+    }
+
+    /// A byte within `min..=max`.
+    fn range(&mut self, min: u8, max: u8) -> Option<u8> {
+        self.u8().filter(|v| *v >= min && *v <= max)
+    }
+
+    /// A byte that is one of the choices.
+    fn choice(&mut self, choices: &[u8]) -> Option<u8> {
+        self.u8().filter(|v| choices.contains(v))
+    }
+
+    /// The number of an enum variant, for `From<i8>`.
+    fn enumeration(&mut self, len: usize) -> Option<i8> {
+        self.range(0, len as u8 - 1).map(|v| v as i8)
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        self.range(0, 1).map(|v| v != 0)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes([
+            self.u8()?,
+            self.u8()?,
+            self.u8()?,
+            self.u8()?,
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start of the version 1 settings of a track.
+    fn track_at(tr: usize) -> usize {
+        GLOBAL_LEN + tr * TRACK_LEN
+    }
+
+    /// Start of the version 2 gate lengths.
+    const V2_AT: usize = GLOBAL_LEN + TRACK_COUNT * TRACK_LEN;
+
+    /// Start of the version 15 step overrides.
+    const V15_AT: usize = V16_AT - V15_LEN;
+
+    /// Start of the version 16 gate length LFOs.
+    const V16_AT: usize = LEN - V16_LEN;
+
+    fn custom() -> State {
+        let mut st = State::new();
+
+        st.params.seed = 1234 + SEED_BASE as u32;
+        st.params.pattern_length = 32;
+        st.input_ppqn = 24;
+        st.clock_mult = -2;
+        st.swing = 60;
+        st.transport = Transport::Manual;
+        st.seed_coarse = 1000;
+
+        let t = &mut st.params.tracks[1];
+        t.length = 12;
+        t.steps = 5;
+        t.offset = 3;
+
+        st.lfo[2].set_mode(3);
+        st.lfo[2].set_depth(0x800);
+        st.track_sync[3] = TrackSync::OneShot;
+        st.mute[0] = true;
+        st.algo[1] = Algo::Euclid;
+        st.gate_len[1] = 80;
+        st.gate_role[2] = GateRole::Coincidence(1, 3);
+        st.anchor[1] = Some(7);
+        st.gate_from_lfo[0] = Some(0x800);
+        st.warmup[3] = 16;
+        st.gate_invert[2] = true;
+        st.channel_role[1] = ChannelRole::CvOnly;
+        st.cv_behavior[3] = CvBehavior::StepValue;
+        st.overrides.set(1, 4, true);
+        st.overrides.set(2, 0, false);
+        st.step_values.set(3, 63, Some(STEP_VALUE_MAX));
+        st.step_values.set(0, 2, Some(0));
+        st.dac_map = DacMap::new([3, 2, 1, 0]).unwrap();
+        st.gate_len_mod_src[2] = Some(0);
+
+        st.regenerate();
+
+        st
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = save(&custom());
+        let st = load(&bytes, &State::new()).unwrap();
+
+        assert_eq!(&save(&st)[..], &bytes[..]);
+
+        assert_eq!(st.params.seed, 1234 + SEED_BASE as u32);
+        assert_eq!(st.params.pattern_length, 32);
+        assert_eq!(st.clock_mult, -2);
+        assert_eq!(st.transport, Transport::Manual);
+        assert_eq!(st.params.tracks[1].length, 12);
+        assert_eq!(st.params.tracks[1].steps, 5);
+        assert_eq!(st.params.tracks[1].offset, 3);
+        assert_eq!(st.lfo[2].mode, Mode::Sine);
+        assert_eq!(st.lfo[2].depth, 0x800);
+        assert_eq!(st.track_sync[3], TrackSync::OneShot);
+        assert!(st.mute[0]);
+        assert_eq!(st.algo[1], Algo::Euclid);
+        assert_eq!(st.gate_role[2], GateRole::Coincidence(1, 3));
+        assert_eq!(st.anchor[1], Some(7));
+        assert_eq!(st.gate_from_lfo[0], Some(0x800));
+        assert!(st.gate_invert[2]);
+        assert_eq!(st.cv_behavior[3], CvBehavior::StepValue);
+        assert_eq!(st.overrides.get(1, 4), Some(true));
+        assert_eq!(st.overrides.get(2, 0), Some(false));
+        assert_eq!(st.overrides.get(1, 5), None);
+        assert_eq!(st.step_values.get(3, 63), Some(STEP_VALUE_MAX));
+        assert_eq!(st.step_values.get(0, 2), Some(0));
+        assert_eq!(st.step_values.get(0, 3), None);
+        assert_eq!(st.dac_map, DacMap::new([3, 2, 1, 0]).unwrap());
+        assert_eq!(st.gate_len_mod_src[2], Some(0));
+        assert_eq!(st.gate_len_mod_src[0], None);
+    }
+
+    #[test]
+    fn load_keeps_live() {
+        let mut live = State::new();
+        live.playhead = 17;
+        live.panic = true;
+        live.lfo_value[2] = 0x123;
+        live.overrides.set(0, 1, true);
+
+        let st = load(&save(&custom()), &live).unwrap();
+
+        assert_eq!(st.playhead, 17);
+        assert!(st.panic);
+        assert_eq!(st.lfo_value[2], 0x123);
+        assert_eq!(st.params.seed, 1234 + SEED_BASE as u32);
+
+        // The step overrides are settings, they are replaced.
+        assert_eq!(st.overrides.get(0, 1), None);
+        assert_eq!(st.overrides.get(1, 4), Some(true));
+    }
+
+    #[test]
+    fn defaults_load() {
+        assert!(load(&save(&State::new()), &State::new()).is_some());
+    }
+
+    #[test]
+    fn older_version_defaults() {
+        let bytes = save(&custom());
+
+        // Version 15 is the current layout without the gate length LFOs.
+        let mut b = bytes;
+        b[1] = 15;
+        let st = load(&b[..V16_AT], &custom()).unwrap();
+        assert_eq!(st.gate_len_mod_src[2], None);
+        assert_eq!(st.dac_map, DacMap::new([3, 2, 1, 0]).unwrap());
+
+        // Version 14 is without the steps and dac map as well.
+        let mut b = bytes;
+        b[1] = 14;
+        let st = load(&b[..V15_AT], &State::new()).unwrap();
+        assert_eq!(st.cv_behavior[3], CvBehavior::StepValue);
+        assert_eq!(st.overrides.get(1, 4), None);
+        assert_eq!(st.step_values.get(3, 63), None);
+        assert_eq!(st.dac_map, DacMap::default());
+
+        // Version 13 is without the cv behavior as well.
+        let mut b = bytes;
+        b[1] = 13;
+        let st = load(&b[..V15_AT - V14_LEN], &State::new()).unwrap();
+        assert_eq!(st.cv_behavior[3], CvBehavior::Lfo);
+        assert_eq!(st.channel_role[1], ChannelRole::CvOnly);
+        assert_eq!(st.seed_coarse, 1000);
+
+        // Version 1 has only the first global and track settings. The later ones go
+        // back to their defaults, whatever the live state had.
+        let mut b = bytes;
+        b[1] = 1;
+        let st = load(&b[..V2_AT], &custom()).unwrap();
+        assert_eq!(st.params.tracks[1].length, 12);
+        assert_eq!(st.anchor[1], Some(7));
+        assert_eq!(st.gate_len[1], State::new().gate_len[1]);
+        assert_eq!(st.gate_from_lfo[0], None);
+        assert_eq!(st.warmup[3], 0);
+        assert!(!st.gate_invert[2]);
+
+        // An older version must not carry the bytes of a newer.
+        let mut b = bytes;
+        b[1] = 15;
+        assert!(load(&b, &State::new()).is_none(), "bytes past version 15");
+    }
+
+    #[test]
+    fn refuse_header() {
+        let bytes = save(&custom());
+
+        let mut b = bytes;
+        b[0] = 0xff;
+        assert!(load(&b, &State::new()).is_none(), "erased storage");
+
+        let mut b = bytes;
+        b[1] = VERSION + 1;
+        assert!(load(&b, &State::new()).is_none(), "newer version");
+
+        let mut b = bytes;
+        b[1] = 0;
+        assert!(load(&b, &State::new()).is_none(), "version 0");
+
+        assert!(
+            load(&bytes[..LEN - 1], &State::new()).is_none(),
+            "truncated"
+        );
+
+        let mut long = [0; LEN + 1];
+        long[..LEN].copy_from_slice(&bytes);
+        assert!(load(&long, &State::new()).is_none(), "trailing bytes");
+    }
+
+    #[test]
+    fn refuse_out_of_range() {
+        let bytes = save(&custom());
+        let t = track_at(1);
+
+        let corrupt = [
+            (7, 1, "pattern length"),
+            (7, 65, "pattern length"),
+            (11, 3, "clock mult"),
+            (13, 2, "swing resolution"),
+            (t, 1, "track length"),
+            (t + 1, 13, "steps past length"),
+            (t + 2, 12, "offset past length"),
+            (t + 3, Mode::len() as u8, "lfo mode"),
+            (t + 8, TrackSync::len() as u8, "track sync"),
+            (t + 12, GateRole::len() as u8, "gate role"),
+            (t + 13, 12, "anchor past length"),
+            (V2_AT + 1, GATE_LEN_MAX as u8 + 1, "gate length"),
+            (V15_AT, 3, "step override"),
+            (
+                V15_AT + TRACK_COUNT * MAX_LEN,
+                STEP_VALUE_MAX + 1,
+                "step value",
+            ),
+            (V16_AT - 1, 4, "dac channel track"),
+            (V16_AT - 1, 2, "dac map not a permutation"),
+            (V16_AT, 4, "gate length lfo"),
+        ];
+
+        for (at, v, what) in corrupt.iter() {
+            let mut b = bytes;
+            b[*at] = *v;
+            assert!(load(&b, &State::new()).is_none(), "{}", what);
+        }
+    }
+}
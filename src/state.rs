@@ -5,20 +5,108 @@ use alg::gen::{Generated, Params, SEED_BASE, STOKAST_PARAMS};
 use alg::rnd::Rnd;
 use alg::tempo::Tempo;
 use arrayvec::ArrayVec;
+use core::fmt::Debug;
 use cortex_m::peripheral::DWT;
 
+use crate::algo::Algo;
+use crate::error::Error;
 use crate::lfo::SAW_DN;
 use crate::lfo::SAW_UP;
 use crate::lfo::{self, Lfo};
+use crate::link::LinkRole;
 use crate::max6958::Seg;
 use crate::max6958::Segs4;
+use crate::output::{GATE_LEN_MAX, GATE_LEN_MIN};
 use crate::CPU_SPEED;
 
 pub const TRACK_COUNT: usize = 4;
 
+/// Max length of a track.
+pub const MAX_LEN: usize = 64;
+
 /// How long to wait before the screen resume the "normal" view.
 const INPUT_MODE_TIMEOUT: Time<{ CPU_SPEED }> = Time::from_secs(30);
 
+/// How long without any input or clock before going into standby.
+const STANDBY_TIMEOUT: Time<{ CPU_SPEED }> = Time::from_secs(300);
+
+/// Number of predicted intervals without a clock pulse before the clock is stopped.
+const CLOCK_STOP_INTERVALS: i64 = 4;
+
+/// Mixed into the track seed to draw the step values of `CvBehavior::StepValue`.
+const STEP_VALUE_SEED: u32 = 0x57e9;
+
+/// Largest stored step value. The values are quantized to 61 levels over the CV
+/// range, which are semitones should the range span 5 octaves.
+pub const STEP_VALUE_MAX: u8 = 60;
+
+/// Mixed into the track seed to draw the extra hits of the fill.
+const FILL_SEED: u32 = 0x5f11;
+
+/// Number of clock intervals kept for rejecting outliers.
+const INTERVAL_HISTORY: usize = 5;
+
+/// Predicted interval used for gate lengths when advancing manually.
+const MANUAL_STEP_INTERVAL: Time<{ CPU_SPEED }> = Time::from_millis(250);
+
+/// Largest user facing seed in the default range.
+const SEED_MAX: u32 = 9999;
+
+/// Largest user facing seed in the extended range. Shown in hexadecimal to fit 4 digits.
+pub const SEED_MAX_EXTENDED: u32 = 0xffff;
+
+/// Choices of the coarse seed step, a seed turn with shift held.
+pub const SEED_COARSE_CHOICES: [u16; 2] = [100, 1000];
+
+/// Selectable input clock pulses per sequencer step.
+pub const INPUT_PPQN_CHOICES: [u8; 4] = [1, 2, 4, 24];
+
+/// Selectable global clock multipliers. Negative values divide.
+pub const CLOCK_MULT_CHOICES: [i8; 5] = [-4, -2, 1, 2, 4];
+
+/// Sequencer steps per metronome beat, 16ths to quarter notes.
+const STEPS_PER_BEAT: usize = 4;
+
+/// Largest humanize jitter in ms.
+pub const HUMANIZE_MAX: i16 = 20;
+
+/// Largest fixed track delay in ms.
+pub const TRACK_DELAY_MAX: i16 = 50;
+
+/// Most steps a track can stay silent after a reset.
+pub const WARMUP_MAX: i16 = 128;
+
+/// Change in LFO depth per encoder detent.
+const LFO_DEPTH_STEP: i32 = 64;
+
+/// The global settings, in the order of the settings menu. These are set once for a
+/// rig, rather than played.
+pub const SETTINGS: [InputMode; 14] = [
+    InputMode::Transport,
+    InputMode::InputPpqn,
+    InputMode::ClockMult,
+    InputMode::ClockStop,
+    InputMode::ClockQuantize,
+    InputMode::Swing,
+    InputMode::SwingResolution,
+    InputMode::LfoSeedHold,
+    InputMode::SeedRange,
+    InputMode::SeedCoarse,
+    InputMode::LinkRole,
+    InputMode::FateEnabled,
+    InputMode::OffsetAbsolute,
+    InputMode::NoEmpty,
+];
+
+/// How long each track is shown in the overview.
+const OVERVIEW_INTERVAL: Time<{ CPU_SPEED }> = Time::from_millis(1500);
+
+/// How long each LFO mode plays when auditioning.
+const LFO_AUDITION_INTERVAL: Time<{ CPU_SPEED }> = Time::from_secs(4);
+
+/// How long to show the name of a menu when entering it.
+const MODE_LABEL_TIMEOUT: Time<{ CPU_SPEED }> = Time::from_millis(400);
+
 /// How long to show an override input.
 const OVERRIDE_INPUT_TIMEOUT: Time<{ CPU_SPEED }> = Time::from_millis(500);
 
@@ -35,30 +123,220 @@ pub struct State {
     /// instant user feedback that goes away straight away.
     pub override_input_mode: Option<InputModeAtTime<{ CPU_SPEED }>>,
 
+    /// When we entered a menu, while its name is shown.
+    pub mode_label_at: Option<Time<{ CPU_SPEED }>>,
+
     /// Generative parameters for generated.
     pub params: Params<{ TRACK_COUNT }>,
 
+    /// Largest user facing seed, either `SEED_MAX` or `SEED_MAX_EXTENDED`.
+    pub seed_max: u32,
+
+    /// Whether shift (the seed button) is held.
+    pub shift: bool,
+
+    /// Whether the seed button can enter fate. Off protects the patch from an
+    /// accidental randomize.
+    pub fate_enabled: bool,
+
+    /// Offset encoders select the start step directly, stopping at the ends, rather
+    /// than nudging the offset around the track length.
+    pub offset_absolute: bool,
+
+    /// Give a track that generates no hits a single hit on its first step.
+    pub no_empty: bool,
+
+    /// Seed change per detent of a coarse seed turn, one of `SEED_COARSE_CHOICES`.
+    pub seed_coarse: u16,
+
+    /// Size of the last seed change, shown as a decimal point while turning.
+    pub seed_step: u16,
+
+    /// When the seed last changed.
+    pub seed_step_at: Time<{ CPU_SPEED }>,
+
     /// Mute flags for the tracks.
     pub mute: [bool; TRACK_COUNT],
 
+    /// The parameters the pattern was frozen at, None when not frozen. Parameter
+    /// changes while frozen go to `params`, but play first on unfreeze.
+    pub frozen: Option<Params<{ TRACK_COUNT }>>,
+
+    /// If there are parameter changes to regenerate on unfreeze.
+    pub frozen_pending: bool,
+
     /// The generated tracks.
     pub generated: Generated<{ TRACK_COUNT }>,
 
+    /// Seeds derived at the last regenerate.
+    pub seeds: DerivedSeeds,
+
+    /// Keep the LFO seeds while the global seed is unchanged, so edits to the
+    /// tracks don't scramble the LFO random sequences.
+    pub lfo_seed_hold: bool,
+
+    /// The global seed the LFO seeds were derived for.
+    pub lfo_seed_for: Option<u32>,
+
+    /// Manual step overrides taking precedence over the generated tracks.
+    pub overrides: Overrides,
+
+    /// Stored step values, taking precedence over the drawn ones for melodic use.
+    pub step_values: StepValues,
+
+    /// Denser variant of each track, a bit per step, played while the fill is held.
+    pub fill_pattern: [u64; TRACK_COUNT],
+
+    /// Whether the fill of each track is playing.
+    pub fill: [bool; TRACK_COUNT],
+
+    /// Currently selected step when editing steps.
+    pub edit_step: usize,
+
+    /// Pattern algorithm for each track.
+    pub algo: [Algo; TRACK_COUNT],
+
+    /// Step of each track that is guaranteed a hit, if any.
+    pub anchor: [Option<u8>; TRACK_COUNT],
+
+    /// Gate length in percent of the step.
+    pub gate_len: [u8; TRACK_COUNT],
+
+    /// Chance in percent that an active step fires a burst of triggers.
+    pub burst_chance: [u8; TRACK_COUNT],
+
+    /// Max random delay in ms of each hit, for a looser feel. 0 is tight.
+    pub humanize: [u8; TRACK_COUNT],
+
+    /// Fixed delay in ms of each hit, for a laid-back track. 0 is on the beat.
+    pub track_delay: [u8; TRACK_COUNT],
+
+    /// Number of steps after a reset each track stays silent, so elements enter
+    /// one by one.
+    pub warmup: [u8; TRACK_COUNT],
+
     /// The LFOs.
     pub lfo: [Lfo; TRACK_COUNT],
 
+    /// Setting chosen in the settings menu, an index into `SETTINGS`.
+    pub setting: usize,
+
+    /// Track shown in the overview.
+    pub overview_track: usize,
+
+    /// When the overview last advanced to the next track.
+    pub overview_at: Time<{ CPU_SPEED }>,
+
+    /// When auditioning, the time the LFO mode last advanced.
+    pub lfo_audition: Option<Time<{ CPU_SPEED }>>,
+
+    /// Whether reset also restarts the phase of the LFO.
+    pub lfo_reset: [bool; TRACK_COUNT],
+
+    /// LFO threshold of each track that, when set, drives the gate instead of the
+    /// pattern. The gate is high while the LFO is above.
+    pub gate_from_lfo: [Option<u16>; TRACK_COUNT],
+
+    /// Most recent output of each LFO, as cached by `tick_lfo()`.
+    pub lfo_value: [u16; TRACK_COUNT],
+
+    /// Which track's LFO appears on which DAC channel.
+    pub dac_map: DacMap,
+
+    /// LFO modulating the gate length of each track, if any.
+    pub gate_len_mod_src: [Option<usize>; TRACK_COUNT],
+
     /// Track sync setting.
     pub track_sync: [TrackSync; TRACK_COUNT],
 
+    /// How synced tracks longer than the pattern length behave.
+    pub length_mode: LengthMode,
+
+    /// What advances the sequence.
+    pub transport: Transport,
+
+    /// Incoming clock pulses per sequencer step. 1 means every pulse is a step.
+    pub input_ppqn: u8,
+
+    /// Clock pulses into the current step, 0..input_ppqn.
+    pub input_pulse: u8,
+
+    /// Global clock multiplier applied before the sequencer sees the ticks. Negative
+    /// values divide, so -2 is half speed and 2 double.
+    pub clock_mult: i8,
+
+    /// Steps left to subdivide the current clock pulse into, when multiplying.
+    pub sub_ticks_left: u8,
+
+    /// Swing amount in percent, 50 (no swing) to 75.
+    pub swing: u8,
+
+    /// Which note length the swing applies to.
+    pub swing_resolution: SwingResolution,
+
+    /// What happens when the external clock stops.
+    pub clock_stop: ClockStop,
+
+    /// Snap early clock pulses onto the grid of the predicted tempo.
+    pub clock_quantize: bool,
+
+    /// How much the gates of the current step are delayed to land on the grid.
+    pub grid_delay: Time<{ CPU_SPEED }>,
+
+    /// Whether the supply rail is below the safe level, see supply.
+    pub supply_low: bool,
+
+    /// Whether the external clock has stopped, until the next pulse.
+    pub clock_stopped: bool,
+
+    /// Set when the gates should go low, until handled.
+    pub gates_clear: bool,
+
+    /// Role in a link with another unit.
+    pub link_role: LinkRole,
+
+    /// Gate mode setting.
+    pub gate_mode: [GateMode; TRACK_COUNT],
+
+    /// What each of the physical gate outputs is driven by.
+    pub gate_role: [GateRole; TRACK_COUNT],
+
+    /// Whether each of the physical gate outputs is inverted, low when the gate is on.
+    pub gate_invert: [bool; TRACK_COUNT],
+
+    /// Which outputs each channel (gate and DAC channel of the same number) uses.
+    pub channel_role: [ChannelRole; TRACK_COUNT],
+
+    /// What each DAC channel outputs, the LFO or a value latched on each gate.
+    pub cv_behavior: [CvBehavior; TRACK_COUNT],
+
+    /// Value per DAC channel latched on the last gate, for `CvBehavior::StepValue`.
+    pub cv_latch: [u16; TRACK_COUNT],
+
+    /// Set to send all LFO values to the DAC on the next `tick_lfo()`.
+    pub dac_refresh: bool,
+
+    /// Pulse width in ms of the gate outputs not driven by a track.
+    pub sync_width_ms: u8,
+
+    /// Show the first active step next to the offset. Otherwise the plain offset.
+    pub offset_show_first: bool,
+
     /// Current global playhead. Goes from 0..whenever external reset comes.
     pub playhead: u64,
 
     /// Ever increasing count of the clock tick. Never resets.
     pub tick_count: u64,
 
+    /// Steps since the last reset, or since start.
+    pub steps_since_reset: u32,
+
     /// Playhead for each track.
     pub track_playhead: [usize; TRACK_COUNT],
 
+    /// Whether a one-shot track has played through since the last reset.
+    pub one_shot_done: [bool; TRACK_COUNT],
+
     /// Amount between 0..u32::MAX that each tick increases.
     pub track_per_tick: [u64; TRACK_COUNT],
 
@@ -68,11 +346,26 @@ pub struct State {
     // BPM detection/prediction.
     pub tempo: Tempo<{ CPU_SPEED }>,
 
+    // Guards the tempo detection against missed or doubled clock edges.
+    pub interval_filter: IntervalFilter,
+
     // Last clock tick.
     pub last: Time<{ CPU_SPEED }>,
 
     // Interval to next predicted clock tick.
     pub predicted: Time<{ CPU_SPEED }>,
+
+    // Width of the last incoming clock pulse.
+    pub clock_width: Time<{ CPU_SPEED }>,
+
+    /// Whether we are in standby after a long idle.
+    pub standby: bool,
+
+    /// Set when a panic is requested, until handled.
+    pub panic: bool,
+
+    // Last time there was any input or clock.
+    pub last_activity: Time<{ CPU_SPEED }>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -81,18 +374,84 @@ pub enum InputMode {
     /// This is the default we go back to after showing something else.
     Run,
 
-    /// Seed showing 0-9999.
+    /// Seed showing 0-9999, or 0-ffff in the extended range.
     Seed,
     /// Show "fate" and wait for a knob twiddle.
     Fate,
 
     /// Length showing 2-32.
     Length,
+    /// Which length mode.
+    LengthMode,
+    /// Choosing one of the global settings.
+    Settings,
+    /// Which transport.
+    Transport,
+    /// Input clock pulses per step.
+    InputPpqn,
+    /// Global clock multiplier.
+    ClockMult,
+    /// What happens when the clock stops.
+    ClockStop,
+    /// Quantizing the clock to the grid.
+    ClockQuantize,
+    /// Swing amount.
+    Swing,
+    /// Swing resolution.
+    SwingResolution,
+    /// Whether the LFO seeds are held.
+    LfoSeedHold,
+    /// Range of the seed.
+    SeedRange,
+    /// Step of a coarse seed turn.
+    SeedCoarse,
+    /// Role in the link with another unit.
+    LinkRole,
+    /// Whether the seed button can enter fate.
+    FateEnabled,
+    /// Relative or absolute offset editing.
+    OffsetAbsolute,
+    /// Whether empty tracks get a hit.
+    NoEmpty,
+    /// Steps and length of each track in turn.
+    Overview,
 
     /// Offset showing 0-track length.
     Offset(usize),
     /// Which track lfo is currently active.
     Lfo(usize),
+    /// Whether reset restarts the LFO phase.
+    LfoReset(usize),
+    /// LFO threshold driving the gate.
+    LfoGate(usize),
+    /// LFO modulating the gate length.
+    GateLenMod(usize),
+    /// Which track pattern algorithm is currently active.
+    Algo(usize),
+    /// Which track gate mode.
+    GateMode(usize),
+    /// What drives the gate output.
+    GateRole(usize),
+    /// Polarity of the gate output.
+    GateInvert(usize),
+    /// Outputs used by the channel.
+    ChannelRole(usize),
+    /// What the DAC channel outputs.
+    CvBehavior(usize),
+    /// Which step is guaranteed a hit.
+    Anchor(usize),
+    /// Chance of a burst.
+    Burst(usize),
+    /// Timing jitter in ms.
+    Humanize(usize),
+    /// Fixed hit delay in ms.
+    TrackDelay(usize),
+    /// Steps silent after a reset.
+    Warmup(usize),
+    /// Manual editing of track steps.
+    StepEdit(usize),
+    /// Manual editing of the value of each step.
+    StepValue(usize),
 
     /// Track steps/length.
     Steps(usize), // (length, steps)
@@ -101,6 +460,77 @@ pub enum InputMode {
 
     /// If we are muting a channel.
     Mute(bool),
+
+    /// If we are freezing the generated pattern.
+    Frozen(bool),
+
+    /// Confirmation of a factory reset.
+    FactoryReset,
+    /// Confirmation of clearing the performance settings.
+    ClearPerformance,
+
+    /// If the LFO retriggers on gate.
+    LfoRetrigger(bool),
+    /// How the LFO is coupled to the gate.
+    LfoCoupling(lfo::Coupling),
+    /// If the LFO modes are auto-advancing.
+    LfoAudition(bool),
+    /// Depth of the LFO.
+    LfoDepth(u16),
+    /// Tapped gate length in percent.
+    GateLen(u8),
+    /// Pulse width of the sync outputs, set from the gate role of a track.
+    SyncWidth(usize),
+    /// Shifting all offsets.
+    OffsetAll,
+    /// Confirmation of a parameter reset to its default.
+    ParamDefault,
+}
+
+impl InputMode {
+    /// Name of the menu, for modes entered by clicking. Modes entered by turning
+    /// a knob show their value straight away.
+    pub fn label(&self) -> Option<&'static str> {
+        use InputMode::*;
+        Some(match self {
+            LengthMode => "ltyp",
+            Settings => "set ",
+            Transport => "tran",
+            InputPpqn => "puls",
+            ClockMult => "rate",
+            ClockStop => "stop",
+            ClockQuantize => "grid",
+            Swing => "shuf",
+            SwingResolution => "res ",
+            LfoSeedHold => "lrnd",
+            SeedRange => "rang",
+            SeedCoarse => "jump",
+            LinkRole => "unit",
+            FateEnabled => "fate",
+            OffsetAbsolute => "offs",
+            NoEmpty => "1hit",
+            Overview => "all ",
+            Lfo(_) => "lfo ",
+            Algo(_) => "algo",
+            GateMode(_) => "gtyp",
+            GateRole(_) => "role",
+            GateInvert(_) => "pol ",
+            ChannelRole(_) => "chan",
+            CvBehavior(_) => "cv  ",
+            Anchor(_) => "anch",
+            Burst(_) => "brst",
+            Humanize(_) => "jitr",
+            TrackDelay(_) => "dlay",
+            Warmup(_) => "intr",
+            LfoReset(_) => "lrst",
+            LfoGate(_) => "lgat",
+            GateLenMod(_) => "glen",
+            StepEdit(_) => "edit",
+            StepValue(_) => "note",
+            TrackSync(_) => "sync",
+            _ => return None,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -111,11 +541,13 @@ pub enum TrackSync {
     Free = 1,
     /// Track just keeps looping, ignoring both pattern length and reset.
     Loop = 2,
+    /// Track plays through once after reset, then stays silent until the next.
+    OneShot = 3,
 }
 
 impl TrackSync {
-    const fn len() -> usize {
-        3
+    pub const fn len() -> usize {
+        4
     }
 }
 
@@ -131,174 +563,1234 @@ impl From<i8> for TrackSync {
             0 => Sync,
             1 => Free,
             2 => Loop,
+            3 => OneShot,
             _ => panic!("Wot wot?"),
         }
     }
 }
 
-pub type OperQueue = ArrayVec<Oper, 64>;
+/// Mapping of DAC channels to tracks. The index is the DAC channel and the
+/// value is the track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DacMap([usize; TRACK_COUNT]);
 
-#[derive(Debug)]
-/// The operations that can be done on the state.
-pub enum Oper {
-    Tick(Time<{ CPU_SPEED }>),
-    Reset,
-    Seed(i8),
-    SeedClick,
-    Length(i8),
-    LengthClick,
-    Offset(usize, i8),
-    OffsetClick(usize),
-    Steps(usize, i8),
-    StepsClick(usize),
-}
+impl DacMap {
+    /// Create a new mapping. The mapping must be a permutation of the tracks.
+    pub fn new(map: [usize; TRACK_COUNT]) -> Result<Self, Error> {
+        let mut seen = [false; TRACK_COUNT];
 
-impl State {
-    pub fn new() -> Self {
-        let mut st = State {
-            params: STOKAST_PARAMS,
-            generated: Generated::new(STOKAST_PARAMS),
-            ..Default::default()
-        };
+        for tr in map.iter() {
+            if *tr >= TRACK_COUNT || seen[*tr] {
+                return Err(Error::Other("DAC map is not a permutation"));
+            }
+            seen[*tr] = true;
+        }
 
-        st.regenerate();
+        Ok(DacMap(map))
+    }
 
-        st
+    /// The track on a DAC channel.
+    pub fn track(&self, ch: usize) -> usize {
+        self.0[ch]
     }
 
-    pub fn update(&mut self, now: Time<{ CPU_SPEED }>, todo: impl Iterator<Item = Oper>) {
-        let mut input_mode = None;
-        let mut regenerate = false;
+    /// Reorder per track values into per DAC channel values.
+    pub fn apply<T: Copy>(&self, v: &[T; TRACK_COUNT]) -> [T; TRACK_COUNT] {
+        let mut out = *v;
 
-        for oper in todo {
-            trace!("Handle: {:?}", oper);
+        for (ch, tr) in self.0.iter().enumerate() {
+            out[ch] = v[*tr];
+        }
 
-            match oper {
-                Oper::Tick(interval) => {
-                    self.last = now;
-                    self.predicted = self.tempo.predict(interval);
-                    self.tick_count += 1;
+        out
+    }
+}
 
-                    self.playhead = if self.next_is_reset {
-                        self.next_is_reset = false;
+impl Default for DacMap {
+    fn default() -> Self {
+        DacMap([0, 1, 2, 3])
+    }
+}
 
-                        0
-                    } else {
-                        self.playhead + 1
-                    };
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Sequence advances on the clock input.
+    Clock = 0,
+    /// Sequence advances one step per pulse on the advance input.
+    Manual = 1,
+}
 
-                    trace!(
-                        "Tick playhead: {} tick_count: {}",
-                        self.playhead,
-                        self.tick_count
-                    );
-                    self.update_track_playhead();
-                }
+impl Transport {
+    pub const fn len() -> usize {
+        2
+    }
+}
 
-                Oper::Reset => {
-                    info!("Reset");
-                    // Reset might affect the tempo detection.
-                    self.tempo.reset();
+impl From<i8> for Transport {
+    fn from(mut x: i8) -> Self {
+        use Transport::*;
 
-                    // Whatever tick is coming next, it's going to reset back to 0.
-                    self.next_is_reset = true;
-                }
+        while x < 0 {
+            x += Self::len() as i8;
+        }
 
-                Oper::Seed(x) => {
-                    if self.input_mode == InputMode::Fate {
-                        // KABOOM randomize all the things.
-                        self.tonight_im_in_the_hands_of_fate();
-                        self.input_mode.1 = now;
-                    } else {
-                        let s = (self.params.seed - SEED_BASE as u32) as i32;
-                        let n = s + x as i32;
+        match x % (Self::len() as i8) {
+            0 => Clock,
+            1 => Manual,
+            _ => panic!("Unhandled Transport number"),
+        }
+    }
+}
 
-                        // Seed is 0-9999
-                        if n >= 0 && n <= 9999 {
-                            self.params.seed = (n + SEED_BASE) as u32;
-                            input_mode = Some(InputMode::Seed);
-                            regenerate = true;
-                        }
-                    }
-                }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthMode {
+    /// Synced tracks are clipped to the pattern length.
+    Clip = 0,
+    /// Synced tracks longer than the pattern length play their full length (polymeter).
+    Independent = 1,
+}
 
-                Oper::SeedClick => {
-                    if self.input_mode == InputMode::Fate {
-                        input_mode = Some(InputMode::Seed);
-                    } else {
-                        input_mode = Some(InputMode::Fate);
-                    }
-                }
+impl LengthMode {
+    pub const fn len() -> usize {
+        2
+    }
+}
 
-                Oper::Length(x) => {
-                    let s = self.params.pattern_length as i8;
-                    let n = s + x;
+impl From<i8> for LengthMode {
+    fn from(mut x: i8) -> Self {
+        use LengthMode::*;
 
-                    // Patterns must be 2-64.
-                    if n >= 2 && n <= 64 {
-                        self.params.pattern_length = n as u8;
-                        input_mode = Some(InputMode::Length);
-                        regenerate = true;
-                    }
-                }
+        while x < 0 {
+            x += Self::len() as i8;
+        }
 
-                Oper::LengthClick => {
-                    // what did I have planned here?!
-                }
+        match x % (Self::len() as i8) {
+            0 => Clip,
+            1 => Independent,
+            _ => panic!("Unhandled LengthMode number"),
+        }
+    }
+}
 
-                Oper::Offset(tr, x) => {
-                    if self.input_mode == InputMode::Lfo(tr) {
-                        self.lfo[tr].set_mode(x);
-                        self.input_mode.1 = now;
-                        regenerate = true;
-                    } else {
-                        let t = &mut self.params.tracks[tr];
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateMode {
+    /// Gate is high for the duty cycle.
+    Gate = 0,
+    /// Gate is held high until the next rest.
+    Hold = 1,
+    /// Gate follows the width of the incoming clock pulse.
+    Clock = 2,
+}
 
-                        let s = t.offset as i8;
-                        let l = t.length as i8;
-                        let mut n = s + x;
+impl GateMode {
+    pub const fn len() -> usize {
+        3
+    }
+}
 
-                        // Offset is 0 to step track length, wrapping around.
-                        while n < 0 {
-                            n += l;
-                        }
+impl From<i8> for GateMode {
+    fn from(mut x: i8) -> Self {
+        use GateMode::*;
 
-                        while n >= l {
-                            n -= l;
-                        }
+        while x < 0 {
+            x += Self::len() as i8;
+        }
 
-                        t.offset = n as u8;
-                        input_mode = Some(InputMode::Offset(tr));
-                        regenerate = true;
-                    }
-                }
+        match x % (Self::len() as i8) {
+            0 => Gate,
+            1 => Hold,
+            2 => Clock,
+            _ => panic!("Unhandled GateMode number"),
+        }
+    }
+}
 
-                Oper::OffsetClick(tr) => {
-                    if self.input_mode == InputMode::Lfo(tr) {
-                        input_mode = Some(InputMode::Offset(tr));
-                    } else {
-                        input_mode = Some(InputMode::Lfo(tr));
-                    }
-                }
+/// The note length swing is applied to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwingResolution {
+    Eighth = 0,
+    Sixteenth = 1,
+}
 
-                Oper::Steps(tr, x) => {
-                    if self.input_mode == InputMode::TrackSync(tr) {
-                        let mut n = self.track_sync[tr] as i8;
-                        n += x;
-                        self.track_sync[tr] = n.into();
-                        self.input_mode.1 = now;
-                        // no need to regenerate here.
-                    } else {
-                        let t = &mut self.params.tracks[tr];
+impl SwingResolution {
+    pub const fn len() -> usize {
+        2
+    }
+}
 
-                        let s1 = t.steps as i8;
+impl From<i8> for SwingResolution {
+    fn from(mut x: i8) -> Self {
+        use SwingResolution::*;
 
-                        let mut n1 = s1 + x;
-                        let mut n2 = t.length as i8;
+        while x < 0 {
+            x += Self::len() as i8;
+        }
 
-                        // Step cannot be longer than track length. Wrap around increases length.
-                        if n1 > n2 {
-                            n1 = 0;
+        match x % (Self::len() as i8) {
+            0 => Eighth,
+            1 => Sixteenth,
+            _ => panic!("Unhandled SwingResolution number"),
+        }
+    }
+}
+
+/// What happens when the external clock stops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockStop {
+    /// Leave the gates as they are and freeze the playhead.
+    Hold = 0,
+    /// Drive all gates low and freeze the playhead.
+    Clear = 1,
+    /// Keep running on the last tempo.
+    Run = 2,
+}
+
+impl ClockStop {
+    pub const fn len() -> usize {
+        3
+    }
+}
+
+impl From<i8> for ClockStop {
+    fn from(mut x: i8) -> Self {
+        use ClockStop::*;
+
+        while x < 0 {
+            x += Self::len() as i8;
+        }
+
+        match x % (Self::len() as i8) {
+            0 => Hold,
+            1 => Clear,
+            2 => Run,
+            _ => panic!("Unhandled ClockStop number"),
+        }
+    }
+}
+
+/// Which outputs a channel uses. A channel is the gate output and DAC channel
+/// of the same number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelRole {
+    /// Gate and CV.
+    Both = 0,
+    /// Only CV, a free modulator. The gate stays low.
+    CvOnly = 1,
+    /// Only gate. The CV stays at 0.
+    GateOnly = 2,
+}
+
+impl ChannelRole {
+    pub const fn len() -> usize {
+        3
+    }
+
+    pub fn has_gate(&self) -> bool {
+        *self != ChannelRole::CvOnly
+    }
+
+    pub fn has_cv(&self) -> bool {
+        *self != ChannelRole::GateOnly
+    }
+}
+
+impl From<i8> for ChannelRole {
+    fn from(mut x: i8) -> Self {
+        use ChannelRole::*;
+
+        while x < 0 {
+            x += Self::len() as i8;
+        }
+
+        match x % (Self::len() as i8) {
+            0 => Both,
+            1 => CvOnly,
+            2 => GateOnly,
+            _ => panic!("Unhandled ChannelRole number"),
+        }
+    }
+}
+
+/// What a DAC channel outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CvBehavior {
+    /// The LFO of the track, continuously.
+    Lfo = 0,
+    /// A value of the step, latched when the gate of the channel rises and held
+    /// until the next. With a drum on the gate, the CV is its pitch.
+    StepValue = 1,
+}
+
+/// What drives a physical gate output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateRole {
+    /// The gate of a track.
+    Track(usize),
+    /// A pulse on every step.
+    Clock,
+    /// A pulse on the first step after reset.
+    Reset,
+    /// A pulse at the start of the pattern.
+    BarStart,
+    /// A pulse on the last step of the pattern.
+    EndOfCycle,
+    /// Same as another gate output.
+    Mirror(usize),
+    /// A click on every beat, longer on the start of the pattern.
+    Metronome,
+    /// High while any of the other gate outputs in the mask is high, bit 0-3 for
+    /// gate 1-4. Outputs that are themselves an OR don't count.
+    AnyActive(u8),
+    /// A pulse on the steps where both tracks hit in the same incoming tick.
+    Coincidence(usize, usize),
+}
+
+/// Number of non-empty gate masks for `GateRole::AnyActive`.
+const ANY_ACTIVE_MASKS: usize = (1 << TRACK_COUNT) - 1;
+
+/// The track pairs of `GateRole::Coincidence`, in menu order.
+const TRACK_PAIRS: [(usize, usize); 6] = [(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)];
+
+impl GateRole {
+    pub const fn len() -> usize {
+        2 * TRACK_COUNT + 5 + ANY_ACTIVE_MASKS + TRACK_PAIRS.len()
+    }
+
+    /// Position in the list of roles, for cycling with an encoder.
+    pub fn index(&self) -> i8 {
+        let t = TRACK_COUNT as i8;
+
+        match self {
+            GateRole::Track(i) => *i as i8,
+            GateRole::Clock => t,
+            GateRole::Reset => t + 1,
+            GateRole::BarStart => t + 2,
+            GateRole::EndOfCycle => t + 3,
+            GateRole::Mirror(i) => t + 4 + *i as i8,
+            // Last, to keep the positions of saved roles.
+            GateRole::Metronome => 2 * t + 4,
+            GateRole::AnyActive(mask) => 2 * t + 4 + *mask as i8,
+            GateRole::Coincidence(a, b) => {
+                let p = TRACK_PAIRS.iter().position(|p| *p == (*a, *b)).unwrap_or(0);
+                2 * t + 5 + ANY_ACTIVE_MASKS as i8 + p as i8
+            }
+        }
+    }
+}
+
+impl From<i8> for GateRole {
+    fn from(mut x: i8) -> Self {
+        use GateRole::*;
+
+        while x < 0 {
+            x += Self::len() as i8;
+        }
+
+        let x = (x % (Self::len() as i8)) as usize;
+
+        match x {
+            _ if x < TRACK_COUNT => Track(x),
+            _ if x == TRACK_COUNT => Clock,
+            _ if x == TRACK_COUNT + 1 => Reset,
+            _ if x == TRACK_COUNT + 2 => BarStart,
+            _ if x == TRACK_COUNT + 3 => EndOfCycle,
+            _ if x < 2 * TRACK_COUNT + 4 => Mirror(x - TRACK_COUNT - 4),
+            _ if x == 2 * TRACK_COUNT + 4 => Metronome,
+            _ if x <= 2 * TRACK_COUNT + 4 + ANY_ACTIVE_MASKS => {
+                AnyActive((x - 2 * TRACK_COUNT - 4) as u8)
+            }
+            _ if x < Self::len() => {
+                let (a, b) = TRACK_PAIRS[x - 2 * TRACK_COUNT - 5 - ANY_ACTIVE_MASKS];
+                Coincidence(a, b)
+            }
+            _ => panic!("Unhandled GateRole number"),
+        }
+    }
+}
+
+/// Manual step overrides. `None` means the generated step is used.
+#[derive(Clone)]
+pub struct Overrides([[Option<bool>; MAX_LEN]; TRACK_COUNT]);
+
+impl Overrides {
+    pub fn get(&self, tr: usize, step: usize) -> Option<bool> {
+        self.0[tr][step]
+    }
+
+    pub fn set(&mut self, tr: usize, step: usize, on: bool) {
+        self.0[tr][step] = Some(on);
+    }
+
+    /// Clear all overrides of a track, returning it to fully generative.
+    pub fn clear(&mut self, tr: usize) {
+        self.0[tr] = [None; MAX_LEN];
+    }
+}
+
+impl Default for Overrides {
+    fn default() -> Self {
+        Overrides([[None; MAX_LEN]; TRACK_COUNT])
+    }
+}
+
+impl Debug for Overrides {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Overrides")
+    }
+}
+
+/// Stored values of each step, 0 to `STEP_VALUE_MAX`. `None` means the value is
+/// drawn from the seed.
+#[derive(Clone)]
+pub struct StepValues([[Option<u8>; MAX_LEN]; TRACK_COUNT]);
+
+impl StepValues {
+    pub fn get(&self, tr: usize, step: usize) -> Option<u8> {
+        self.0[tr][step]
+    }
+
+    pub fn set(&mut self, tr: usize, step: usize, value: Option<u8>) {
+        self.0[tr][step] = value.map(|v| v.min(STEP_VALUE_MAX));
+    }
+
+    /// Clear all stored values of a track, returning it to drawn values.
+    pub fn clear(&mut self, tr: usize) {
+        self.0[tr] = [None; MAX_LEN];
+    }
+}
+
+impl Default for StepValues {
+    fn default() -> Self {
+        StepValues([[None; MAX_LEN]; TRACK_COUNT])
+    }
+}
+
+impl Debug for StepValues {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "StepValues")
+    }
+}
+
+/// Rejects clock intervals far off the median of the last few, such as from a
+/// missed or doubled edge, before they reach the tempo detection.
+#[derive(Debug, Default, Clone)]
+pub struct IntervalFilter {
+    history: ArrayVec<i64, INTERVAL_HISTORY>,
+    /// Where the next interval goes once the history is full.
+    next: usize,
+}
+
+impl IntervalFilter {
+    /// Record an interval, returning it unless it's an outlier.
+    pub fn accept(&mut self, interval: Time<{ CPU_SPEED }>) -> Option<Time<{ CPU_SPEED }>> {
+        let x = interval.count;
+        let median = self.median();
+
+        // Outliers are recorded too, so that a real change of tempo wins
+        // once it makes up the majority of the history.
+        if self.history.is_full() {
+            self.history[self.next] = x;
+            self.next = (self.next + 1) % INTERVAL_HISTORY;
+        } else {
+            self.history.push(x);
+        }
+
+        match median {
+            // More than 50% off the median.
+            Some(m) if (x - m).abs() * 2 > m => {
+                debug!("Ignore clock interval {} (median {})", x, m);
+                None
+            }
+            _ => Some(interval),
+        }
+    }
+
+    /// Median of the history, None until there are enough intervals to tell.
+    fn median(&self) -> Option<i64> {
+        if self.history.len() < 3 {
+            return None;
+        }
+
+        let mut sorted = self.history.clone();
+        sorted.sort_unstable();
+
+        Some(sorted[sorted.len() / 2])
+    }
+
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.next = 0;
+    }
+}
+
+pub type OperQueue = ArrayVec<Oper, 64>;
+
+#[derive(Debug, Clone)]
+/// The operations that can be done on the state.
+pub enum Oper {
+    /// Clock tick at the time of the edge, with the interval to the previous tick.
+    /// The interval is None when it can't be trusted, such as a tick coinciding with reset.
+    ///
+    /// The edge time is captured in the clock interrupt, so neither the time nor the
+    /// interval carry the jitter of how long it took the main loop to see the edge.
+    Tick(Time<{ CPU_SPEED }>, Option<Time<{ CPU_SPEED }>>),
+    /// Width of the incoming clock pulse.
+    ClockWidth(Time<{ CPU_SPEED }>),
+    /// Manual step advance.
+    Advance,
+    /// Clear all gates and zero all CVs, without changing parameters or position.
+    Panic,
+    /// Toggle the freeze of the pattern, holding length and pressing seed.
+    Freeze,
+    /// Reset offsets, mutes, LFO modes and track sync, keeping seed, steps and lengths.
+    ClearPerformance,
+    Reset,
+    /// Seed delta, 1, 10 or 100 per detent depending on the speed of the turn.
+    Seed(i16),
+    /// Coarse seed delta in detents, a seed turn with shift held.
+    SeedCoarse(i8),
+    SeedClick,
+    /// Seed received from a leading unit over the link.
+    LinkSeed(u32),
+    /// Absolute user facing seed, from a computer.
+    SetSeed(u32),
+    /// Absolute pattern length, from a computer.
+    SetLength(u8),
+    /// Absolute steps of a track, from a computer.
+    SetSteps(usize, u8),
+    /// Absolute offset of a track, from a computer.
+    SetOffset(usize, u8),
+    /// LFO mode of a track, from a computer.
+    SetLfoMode(usize, u8),
+    /// Randomize all the things, as turning seed in the fate menu.
+    Fate,
+    Length(i8),
+    LengthClick,
+    /// Shift the offsets of all tracks together.
+    OffsetAll(i8),
+    Offset(usize, i8),
+    OffsetClick(usize),
+    Steps(usize, i8),
+    StepsClick(usize),
+    /// Gate length tapped in by holding a steps button, as the time held.
+    GateLen(usize, Time<{ CPU_SPEED }>),
+    /// Fill of a track held or released.
+    Fill(usize, bool),
+    /// Shift (the seed button) pressed or released.
+    Shift(bool),
+    /// Burst chance delta, an offset turn with shift held.
+    BurstChance(usize, i8),
+    /// Gate length delta in percent, a steps turn with shift held.
+    GateLenDelta(usize, i8),
+    /// Long press of length. Resets the parameter of the current menu to its default.
+    LengthDefault,
+    /// Long press of a track offset. Resets the parameter of the current menu to its default.
+    OffsetDefault(usize),
+    /// Long press of a track steps. Resets the parameter of the current menu to its default.
+    StepsDefault(usize),
+}
+
+impl State {
+    pub fn new() -> Self {
+        let mut st = State {
+            params: STOKAST_PARAMS,
+            seed_max: SEED_MAX,
+            seed_coarse: SEED_COARSE_CHOICES[0],
+            generated: Generated::new(STOKAST_PARAMS),
+            gate_role: [
+                GateRole::Track(0),
+                GateRole::Track(1),
+                GateRole::Track(2),
+                GateRole::Track(3),
+            ],
+            sync_width_ms: 10,
+            input_ppqn: 1,
+            clock_mult: 1,
+            fate_enabled: true,
+            gate_len: [50; TRACK_COUNT],
+            swing: 50,
+            offset_show_first: true,
+            ..Default::default()
+        };
+
+        st.regenerate();
+
+        st
+    }
+
+    /// Reconstruct the state deterministically from a seed (0-9999, or up to 0xffff
+    /// for the extended range) and an optional fate seed. The fate seed randomizes
+    /// the tracks, after which the seed is applied.
+    pub fn from_seeds(seed: u32, fate_seed: Option<u32>) -> Self {
+        let mut st = State::new();
+
+        if seed > SEED_MAX {
+            st.seed_max = SEED_MAX_EXTENDED;
+        }
+
+        if let Some(f) = fate_seed {
+            st.tonight_im_in_the_hands_of_fate(f);
+        }
+
+        st.params.seed = seed + SEED_BASE as u32;
+        st.regenerate();
+
+        st
+    }
+
+    /// Reset the parameters back to the factory defaults.
+    pub fn factory_reset(&mut self, now: Time<{ CPU_SPEED }>) {
+        self.params = STOKAST_PARAMS;
+        self.regenerate();
+
+        self.override_input_mode = Some(InputModeAtTime(InputMode::FactoryReset, now));
+    }
+
+    /// Reset the settings played with during a performance, to get a clean slate.
+    /// Unlike a factory reset, the seed, steps and lengths are kept. The caller
+    /// regenerates.
+    fn clear_performance(&mut self) {
+        for tr in 0..TRACK_COUNT {
+            self.params.tracks[tr].offset = 0;
+            self.mute[tr] = false;
+            self.track_sync[tr] = TrackSync::default();
+
+            let d = self.lfo[tr].mode as i8;
+            self.lfo[tr].set_mode(-d);
+        }
+    }
+
+    pub fn update(&mut self, now: Time<{ CPU_SPEED }>, todo: impl Iterator<Item = Oper>) {
+        let mut input_mode = None;
+        let mut regenerate = false;
+        // Bitmask of tracks with changes only affecting that track.
+        let mut regenerate_tracks: u8 = 0;
+
+        // Any operation, input or clock, wakes us up.
+        self.last_activity = now;
+        if self.standby {
+            info!("Wake from standby");
+            self.standby = false;
+        }
+
+        for oper in todo {
+            trace!("Handle: {:?}", oper);
+
+            match oper {
+                Oper::Tick(at, interval) => {
+                    if self.transport == Transport::Clock {
+                        if self.clock_stopped {
+                            info!("Clock resumed");
+                            self.clock_stopped = false;
+                        }
+
+                        let pulses = self.pulses_per_step();
+
+                        // Where the next step is expected, from the last step and tempo.
+                        let grid = self.last.count() + self.predicted.count();
+
+                        // An outlier keeps the previous prediction.
+                        let interval = interval.and_then(|i| self.interval_filter.accept(i));
+
+                        if let Some(interval) = interval {
+                            // The tempo is detected per pulse, but a step is a number of
+                            // pulses, or a fraction of one when multiplying.
+                            let mut predicted = self.tempo.predict(interval);
+                            predicted.count *= pulses as i64;
+                            if self.clock_mult > 1 {
+                                predicted.count /= self.clock_mult as i64;
+                            }
+                            self.predicted = predicted;
+                        }
+
+                        // Reset aligns the pulses to the step.
+                        if self.next_is_reset {
+                            self.input_pulse = 0;
+                        }
+
+                        if self.input_pulse == 0 {
+                            let at = self.quantize(at, grid);
+                            self.tick(at);
+
+                            // The remaining steps of the pulse are timed by update_time().
+                            // A pulse arriving early cuts the subdivision short.
+                            self.sub_ticks_left = (self.clock_mult.max(1) - 1) as u8;
+                        }
+
+                        self.input_pulse = (self.input_pulse + 1) % pulses;
+                    }
+                }
+
+                Oper::Advance => {
+                    if self.transport == Transport::Manual {
+                        self.predicted = MANUAL_STEP_INTERVAL;
+                        self.tick(now);
+                    }
+                }
+
+                Oper::ClockWidth(width) => {
+                    self.clock_width = width;
+                }
+
+                Oper::ClearPerformance => {
+                    info!("Clear performance");
+                    self.clear_performance();
+                    self.override_input_mode =
+                        Some(InputModeAtTime(InputMode::ClearPerformance, now));
+                    regenerate = true;
+                }
+
+                Oper::Panic => {
+                    info!("Panic");
+                    self.panic = true;
+                }
+
+                Oper::Reset => {
+                    info!("Reset");
+                    // Reset might affect the tempo detection.
+                    self.tempo.reset();
+                    self.interval_filter.reset();
+
+                    // Whatever tick is coming next, it's going to reset back to 0.
+                    self.next_is_reset = true;
+
+                    // No subdivided step between the reset and the next pulse.
+                    self.sub_ticks_left = 0;
+
+                    for (lfo, reset) in self.lfo.iter_mut().zip(self.lfo_reset.iter()) {
+                        if *reset {
+                            lfo.reset_phase();
+                        }
+                    }
+                }
+
+                Oper::Seed(x) => {
+                    if self.input_mode == InputMode::Fate && self.fate_enabled {
+                        // KABOOM randomize all the things.
+                        self.tonight_im_in_the_hands_of_fate(fate_seed());
+                        self.input_mode.1 = now;
+                        regenerate = true;
+                    } else if self.change_seed(x as i32, now) {
+                        input_mode = Some(InputMode::Seed);
+                        regenerate = true;
+                    }
+                }
+
+                Oper::SeedCoarse(x) => {
+                    let step = self.seed_coarse as i32;
+
+                    if self.change_seed(x as i32 * step, now) {
+                        regenerate = true;
+                    }
+
+                    // Shown over the shift display, with the dp on the digit jumping.
+                    self.override_input_mode = Some(InputModeAtTime(InputMode::Seed, now));
+                }
+
+                Oper::LinkSeed(seed) => {
+                    // The lead might have the extended range while we don't.
+                    let s = seed.saturating_sub(SEED_BASE as u32).min(self.seed_max);
+                    let seed = s + SEED_BASE as u32;
+
+                    if self.params.seed != seed {
+                        self.params.seed = seed;
+                        regenerate = true;
+                    }
+                }
+
+                Oper::SetSeed(s) => {
+                    self.params.seed = s.min(self.seed_max) + SEED_BASE as u32;
+                    input_mode = Some(InputMode::Seed);
+                    regenerate = true;
+                }
+
+                Oper::SetLength(l) => {
+                    self.params.pattern_length = l.max(2).min(64);
+                    input_mode = Some(InputMode::Length);
+                    regenerate = true;
+                }
+
+                Oper::SetSteps(tr, s) => {
+                    let t = &mut self.params.tracks[tr];
+                    t.steps = s.min(t.length);
+                    input_mode = Some(InputMode::Steps(tr));
+                    regenerate_tracks |= 1 << tr;
+                }
+
+                Oper::SetOffset(tr, o) => {
+                    let t = &mut self.params.tracks[tr];
+                    t.offset = o % t.length;
+                    input_mode = Some(InputMode::Offset(tr));
+                    regenerate_tracks |= 1 << tr;
+                }
+
+                Oper::SetLfoMode(tr, m) => {
+                    let m = (m % lfo::Mode::len() as u8) as i8;
+                    let d = m - self.lfo[tr].mode as i8;
+                    self.lfo[tr].set_mode(d);
+                    input_mode = Some(InputMode::Lfo(tr));
+                    regenerate_tracks |= 1 << tr;
+                }
+
+                Oper::Fate => {
+                    self.tonight_im_in_the_hands_of_fate(fate_seed());
+                    regenerate = true;
+                }
+
+                Oper::SeedClick => {
+                    // With fate disabled, the seed button only ever shows the seed.
+                    if self.input_mode == InputMode::Fate || !self.fate_enabled {
+                        input_mode = Some(InputMode::Seed);
+                    } else {
+                        input_mode = Some(InputMode::Fate);
+                    }
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::Transport => {
+                    let n = self.transport as i8 + x;
+                    self.transport = n.into();
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::InputPpqn => {
+                    let i = INPUT_PPQN_CHOICES
+                        .iter()
+                        .position(|p| *p == self.input_ppqn)
+                        .unwrap_or(0) as i8;
+                    let l = INPUT_PPQN_CHOICES.len() as i8;
+                    let n = (i + x).max(0).min(l - 1);
+                    self.input_ppqn = INPUT_PPQN_CHOICES[n as usize];
+                    self.input_pulse = 0;
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::ClockMult => {
+                    let i = CLOCK_MULT_CHOICES
+                        .iter()
+                        .position(|m| *m == self.clock_mult)
+                        .unwrap_or(2) as i8;
+                    let l = CLOCK_MULT_CHOICES.len() as i8;
+                    let n = (i + x).max(0).min(l - 1);
+                    self.clock_mult = CLOCK_MULT_CHOICES[n as usize];
+                    self.input_pulse = 0;
+                    self.sub_ticks_left = 0;
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::ClockQuantize => {
+                    // Turn right to quantize, left to follow the clock as is.
+                    self.clock_quantize = x > 0;
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::ClockStop => {
+                    let n = self.clock_stop as i8 + x;
+                    self.clock_stop = n.into();
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::Swing => {
+                    let n = self.swing as i8 + x;
+                    self.swing = n.max(50).min(75) as u8;
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::SwingResolution => {
+                    let n = self.swing_resolution as i8 + x;
+                    self.swing_resolution = n.into();
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::SeedCoarse => {
+                    let i = SEED_COARSE_CHOICES
+                        .iter()
+                        .position(|c| *c == self.seed_coarse)
+                        .unwrap_or(0) as i8;
+                    let l = SEED_COARSE_CHOICES.len() as i8;
+                    let n = (i + x).max(0).min(l - 1);
+                    self.seed_coarse = SEED_COARSE_CHOICES[n as usize];
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::SeedRange => {
+                    // Turn right for the extended range, left for the default.
+                    self.seed_max = if x > 0 { SEED_MAX_EXTENDED } else { SEED_MAX };
+                    self.input_mode.1 = now;
+
+                    let s = self.params.seed - SEED_BASE as u32;
+                    if s > self.seed_max {
+                        self.params.seed = self.seed_max + SEED_BASE as u32;
+                        regenerate = true;
+                    }
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::FateEnabled => {
+                    // Turn right to allow fate, left to protect the patch.
+                    self.fate_enabled = x > 0;
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::OffsetAbsolute => {
+                    // Turn right for absolute, left for relative.
+                    self.offset_absolute = x > 0;
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::NoEmpty => {
+                    // Turn right to fill empty tracks, left to allow them.
+                    self.no_empty = x > 0;
+                    self.input_mode.1 = now;
+                    regenerate = true;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::Overview => {
+                    let n = self.overview_track as i8 + x;
+                    self.overview_track = n.rem_euclid(TRACK_COUNT as i8) as usize;
+                    self.overview_at = now;
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::LinkRole => {
+                    let n = self.link_role as i8 + x;
+                    self.link_role = n.into();
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::LfoSeedHold => {
+                    // Turn right to hold, left to reseed on every change.
+                    self.lfo_seed_hold = x > 0;
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::Settings => {
+                    let n = self.setting as i8 + x;
+                    self.setting = n.rem_euclid(SETTINGS.len() as i8) as usize;
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Length(x) if self.input_mode == InputMode::LengthMode => {
+                    let n = self.length_mode as i8 + x;
+                    self.length_mode = n.into();
+                    self.input_mode.1 = now;
+                    self.update_track_playhead();
+                }
+
+                Oper::Length(x) if self.lfo_track().is_some() => {
+                    let tr = self.lfo_track().unwrap();
+                    let n = self.lfo[tr].depth as i32 + x as i32 * LFO_DEPTH_STEP;
+                    self.lfo[tr].set_depth(n.max(0).min(lfo::DEPTH_MAX as i32) as u16);
+                    self.input_mode.1 = now;
+                    self.override_input_mode = Some(InputModeAtTime(
+                        InputMode::LfoDepth(self.lfo[tr].depth),
+                        now,
+                    ));
+                }
+
+                Oper::Length(x) => {
+                    let s = self.params.pattern_length as i8;
+                    let n = s + x;
+
+                    // Patterns must be 2-64.
+                    if n >= 2 && n <= 64 {
+                        self.params.pattern_length = n as u8;
+                        input_mode = Some(InputMode::Length);
+                        regenerate = true;
+                    }
+                }
+
+                Oper::LengthClick if self.input_mode == InputMode::Length => {
+                    input_mode = Some(InputMode::LengthMode);
+                }
+
+                Oper::LengthClick if self.input_mode == InputMode::LengthMode => {
+                    self.overview_track = 0;
+                    self.overview_at = now;
+                    input_mode = Some(InputMode::Overview);
+                }
+
+                Oper::LengthClick if self.input_mode == InputMode::Overview => {
+                    input_mode = Some(InputMode::Settings);
+                }
+
+                Oper::LengthClick if self.input_mode == InputMode::Settings => {
+                    input_mode = Some(SETTINGS[self.setting]);
+                }
+
+                Oper::LengthClick if SETTINGS.contains(&self.input_mode.0) => {
+                    // Back to choosing, on the setting just edited.
+                    input_mode = Some(InputMode::Settings);
+                }
+
+                Oper::LengthClick if self.lfo_track().is_some() => {
+                    self.lfo_audition = if self.lfo_audition.is_some() {
+                        None
+                    } else {
+                        Some(now)
+                    };
+                    self.input_mode.1 = now;
+                    self.override_input_mode = Some(InputModeAtTime(
+                        InputMode::LfoAudition(self.lfo_audition.is_some()),
+                        now,
+                    ));
+                }
+
+                Oper::LengthClick => {}
+
+                Oper::Freeze => {
+                    if self.frozen.take().is_none() {
+                        self.frozen = Some(self.params);
+                    } else if self.frozen_pending {
+                        // Apply the accumulated changes once.
+                        self.frozen_pending = false;
+                        regenerate = true;
+                    }
+
+                    self.override_input_mode = Some(InputModeAtTime(
+                        InputMode::Frozen(self.frozen.is_some()),
+                        now,
+                    ));
+                }
+
+                Oper::Offset(tr, x) => {
+                    if self.input_mode == InputMode::Lfo(tr) && self.lfo_audition.is_some() {
+                        // Touching the knob stops auditioning on the current mode.
+                        self.lfo_audition = None;
+                        self.input_mode.1 = now;
+                        self.override_input_mode =
+                            Some(InputModeAtTime(InputMode::LfoAudition(false), now));
+                    } else if self.input_mode == InputMode::Lfo(tr) {
+                        self.lfo[tr].set_mode(x);
+                        self.input_mode.1 = now;
+                        regenerate_tracks |= 1 << tr;
+                    } else if self.input_mode == InputMode::Algo(tr) {
+                        let n = self.algo[tr] as i8 + x;
+                        self.algo[tr] = n.into();
+                        self.input_mode.1 = now;
+                        regenerate_tracks |= 1 << tr;
+                    } else if self.input_mode == InputMode::GateMode(tr) {
+                        let n = self.gate_mode[tr] as i8 + x;
+                        self.gate_mode[tr] = n.into();
+                        self.input_mode.1 = now;
+                        // no need to regenerate here.
+                    } else if self.input_mode == InputMode::GateInvert(tr) {
+                        // Turn right to invert, left for normal.
+                        self.gate_invert[tr] = x > 0;
+                        self.input_mode.1 = now;
+                    } else if self.input_mode == InputMode::ChannelRole(tr) {
+                        let n = self.channel_role[tr] as i8 + x;
+                        self.channel_role[tr] = n.into();
+                        self.dac_refresh = true;
+                        self.input_mode.1 = now;
+                    } else if self.input_mode == InputMode::CvBehavior(tr) {
+                        // Turn right for step values, left for the LFO.
+                        self.cv_behavior[tr] = if x > 0 {
+                            CvBehavior::StepValue
+                        } else {
+                            CvBehavior::Lfo
+                        };
+                        self.dac_refresh = true;
+                        self.input_mode.1 = now;
+                    } else if self.input_mode == InputMode::GateRole(tr) {
+                        let n = self.gate_role[tr].index() + x;
+                        self.gate_role[tr] = n.into();
+                        self.input_mode.1 = now;
+                    } else if self.input_mode == InputMode::Burst(tr) {
+                        let n = self.burst_chance[tr] as i16 + x as i16;
+                        self.burst_chance[tr] = n.max(0).min(100) as u8;
+                        self.input_mode.1 = now;
+                    } else if self.input_mode == InputMode::LfoReset(tr) {
+                        // Turn right to restart on reset, left to keep running.
+                        self.lfo_reset[tr] = x > 0;
+                        self.input_mode.1 = now;
+                    } else if self.input_mode == InputMode::LfoGate(tr) {
+                        // Off below 1%, then the threshold in percent of the LFO range.
+                        let p = self.gate_from_lfo[tr].map(lfo_percent).unwrap_or(0);
+                        let n = (p as i16 + x as i16).max(0).min(99) as u32;
+                        self.gate_from_lfo[tr] = if n == 0 {
+                            None
+                        } else {
+                            Some((n * lfo::DEPTH_MAX as u32 / 100) as u16)
+                        };
+                        self.input_mode.1 = now;
+                    } else if self.input_mode == InputMode::GateLenMod(tr) {
+                        // Off, then the LFO of each track.
+                        let s = self.gate_len_mod_src[tr].map(|s| s as i8 + 1).unwrap_or(0);
+                        let n = (s + x).max(0).min(TRACK_COUNT as i8);
+                        self.gate_len_mod_src[tr] =
+                            if n == 0 { None } else { Some(n as usize - 1) };
+                        self.input_mode.1 = now;
+                    } else if self.input_mode == InputMode::Humanize(tr) {
+                        let n = self.humanize[tr] as i16 + x as i16;
+                        self.humanize[tr] = n.max(0).min(HUMANIZE_MAX) as u8;
+                        self.input_mode.1 = now;
+                    } else if self.input_mode == InputMode::TrackDelay(tr) {
+                        let n = self.track_delay[tr] as i16 + x as i16;
+                        self.track_delay[tr] = n.max(0).min(TRACK_DELAY_MAX) as u8;
+                        self.input_mode.1 = now;
+                    } else if self.input_mode == InputMode::Warmup(tr) {
+                        let n = self.warmup[tr] as i16 + x as i16;
+                        self.warmup[tr] = n.max(0).min(WARMUP_MAX) as u8;
+                        self.input_mode.1 = now;
+                    } else if self.input_mode == InputMode::Anchor(tr) {
+                        // -1 is no anchor, then each step of the track.
+                        let l = self.params.tracks[tr].length as i16;
+                        let s = self.anchor[tr].map(|a| a as i16).unwrap_or(-1);
+                        let n = (s + x as i16).max(-1).min(l - 1);
+                        self.anchor[tr] = if n < 0 { None } else { Some(n as u8) };
+                        self.input_mode.1 = now;
+                        regenerate_tracks |= 1 << tr;
+                    } else if self.input_mode == InputMode::StepEdit(tr)
+                        || self.input_mode == InputMode::StepValue(tr)
+                    {
+                        // Select step, wrapping around the track length.
+                        let l = self.params.tracks[tr].length as i16;
+                        let n = (self.edit_step as i16 + x as i16).rem_euclid(l);
+                        self.edit_step = n as usize;
+                        self.input_mode.1 = now;
+                    } else {
+                        let t = &mut self.params.tracks[tr];
+
+                        let s = t.offset as i8;
+                        let l = t.length as i8;
+                        let mut n = s + x;
+
+                        if self.offset_absolute {
+                            // The start step, stopping at either end of the track.
+                            n = n.max(0).min(l - 1);
+                        }
+
+                        // Offset is 0 to step track length, wrapping around.
+                        while n < 0 {
+                            n += l;
+                        }
+
+                        while n >= l {
+                            n -= l;
+                        }
+
+                        t.offset = n as u8;
+                        input_mode = Some(InputMode::Offset(tr));
+                        regenerate_tracks |= 1 << tr;
+                    }
+                }
+
+                Oper::OffsetAll(x) => {
+                    for t in self.params.tracks.iter_mut() {
+                        // Each track wraps around its own length.
+                        let l = t.length as i16;
+                        t.offset = (t.offset as i16 + x as i16).rem_euclid(l) as u8;
+                    }
+
+                    self.override_input_mode = Some(InputModeAtTime(InputMode::OffsetAll, now));
+                    regenerate = true;
+                }
+
+                Oper::OffsetClick(tr) if self.input_mode == InputMode::StepValue(tr) => {
+                    // Return the selected step to the drawn value.
+                    self.step_values.set(tr, self.edit_step, None);
+                    self.input_mode.1 = now;
+                }
+
+                Oper::OffsetClick(tr) if self.input_mode == InputMode::StepEdit(tr) => {
+                    // Toggle the selected step.
+                    let on = self.step_at(tr, self.edit_step) == 0;
+                    self.overrides.set(tr, self.edit_step, on);
+                    self.input_mode.1 = now;
+                }
+
+                Oper::OffsetClick(tr) => {
+                    // Cycle offset -> lfo -> lfo reset -> lfo gate -> gate len lfo -> algo
+                    // -> gate mode -> gate role -> polarity -> channel role -> cv -> anchor
+                    // -> burst -> humanize -> delay -> intro -> sync -> step edit. In step
+                    // edit the click toggles a step, and steps clicks lead back to offset.
+                    if self.input_mode == InputMode::Lfo(tr) {
+                        input_mode = Some(InputMode::LfoReset(tr));
+                    } else if self.input_mode == InputMode::LfoReset(tr) {
+                        input_mode = Some(InputMode::LfoGate(tr));
+                    } else if self.input_mode == InputMode::LfoGate(tr) {
+                        input_mode = Some(InputMode::GateLenMod(tr));
+                    } else if self.input_mode == InputMode::GateLenMod(tr) {
+                        input_mode = Some(InputMode::Algo(tr));
+                    } else if self.input_mode == InputMode::Algo(tr) {
+                        input_mode = Some(InputMode::GateMode(tr));
+                    } else if self.input_mode == InputMode::GateMode(tr) {
+                        input_mode = Some(InputMode::GateRole(tr));
+                    } else if self.input_mode == InputMode::GateRole(tr) {
+                        input_mode = Some(InputMode::GateInvert(tr));
+                    } else if self.input_mode == InputMode::GateInvert(tr) {
+                        input_mode = Some(InputMode::ChannelRole(tr));
+                    } else if self.input_mode == InputMode::ChannelRole(tr) {
+                        input_mode = Some(InputMode::CvBehavior(tr));
+                    } else if self.input_mode == InputMode::CvBehavior(tr) {
+                        input_mode = Some(InputMode::Anchor(tr));
+                    } else if self.input_mode == InputMode::Anchor(tr) {
+                        input_mode = Some(InputMode::Burst(tr));
+                    } else if self.input_mode == InputMode::Burst(tr) {
+                        input_mode = Some(InputMode::Humanize(tr));
+                    } else if self.input_mode == InputMode::Humanize(tr) {
+                        input_mode = Some(InputMode::TrackDelay(tr));
+                    } else if self.input_mode == InputMode::TrackDelay(tr) {
+                        input_mode = Some(InputMode::Warmup(tr));
+                    } else if self.input_mode == InputMode::Warmup(tr) {
+                        input_mode = Some(InputMode::TrackSync(tr));
+                    } else if self.input_mode == InputMode::TrackSync(tr) {
+                        self.edit_step = 0;
+                        input_mode = Some(InputMode::StepEdit(tr));
+                    } else {
+                        input_mode = Some(InputMode::Lfo(tr));
+                    }
+                }
+
+                Oper::Steps(tr, x) if self.input_mode == InputMode::Lfo(tr) => {
+                    // Turn right to retrigger, left to free run.
+                    let retrigger = x > 0;
+                    self.lfo[tr].set_retrigger(retrigger);
+                    self.input_mode.1 = now;
+                    self.override_input_mode =
+                        Some(InputModeAtTime(InputMode::LfoRetrigger(retrigger), now));
+                }
+
+                Oper::Steps(tr, x) if self.input_mode == InputMode::GateRole(tr) => {
+                    let n = self.sync_width_ms as i16 + x as i16;
+                    self.sync_width_ms = n.max(1).min(50) as u8;
+                    self.input_mode.1 = now;
+                    self.override_input_mode = Some(InputModeAtTime(InputMode::SyncWidth(tr), now));
+                }
+
+                Oper::Steps(tr, x) if self.input_mode == InputMode::StepValue(tr) => {
+                    // Start a step without a stored value from the one drawn.
+                    let v = self.step_values.get(tr, self.edit_step).unwrap_or_else(|| {
+                        let drawn = self.drawn_step_value(tr, self.edit_step) as u32;
+                        (drawn * STEP_VALUE_MAX as u32 / lfo::DEPTH_MAX as u32) as u8
+                    });
+                    let n = (v as i16 + x as i16).max(0).min(STEP_VALUE_MAX as i16);
+                    self.step_values.set(tr, self.edit_step, Some(n as u8));
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Steps(tr, _) if self.input_mode == InputMode::StepEdit(tr) => {
+                    // Stay in the edit, rather than change the pattern underneath it.
+                    self.input_mode.1 = now;
+                }
+
+                Oper::Steps(tr, x) => {
+                    if self.input_mode == InputMode::TrackSync(tr) {
+                        let mut n = self.track_sync[tr] as i8;
+                        n += x;
+                        self.track_sync[tr] = n.into();
+                        self.input_mode.1 = now;
+                        // no need to regenerate here.
+                    } else {
+                        let t = &mut self.params.tracks[tr];
+
+                        let s1 = t.steps as i8;
+
+                        let mut n1 = s1 + x;
+                        let mut n2 = t.length as i8;
+
+                        // Step cannot be longer than track length. Wrap around increases length.
+                        if n1 > n2 {
+                            n1 = 0;
                             n2 += 1;
                         }
 
@@ -325,30 +1817,461 @@ impl State {
                         t.steps = n1 as u8;
                         t.length = n2 as u8;
                         input_mode = Some(InputMode::Steps(tr));
-                        regenerate = true;
+                        regenerate_tracks |= 1 << tr;
                     }
                 }
 
+                Oper::StepsClick(tr) if self.input_mode == InputMode::Lfo(tr) => {
+                    self.lfo[tr].set_coupling(1);
+                    self.input_mode.1 = now;
+                    self.override_input_mode = Some(InputModeAtTime(
+                        InputMode::LfoCoupling(self.lfo[tr].coupling),
+                        now,
+                    ));
+                }
+
+                Oper::StepsClick(tr) if self.input_mode == InputMode::StepEdit(tr) => {
+                    // Same step selected, now editing its value.
+                    input_mode = Some(InputMode::StepValue(tr));
+                }
+
+                Oper::StepsClick(tr) if self.input_mode == InputMode::StepValue(tr) => {
+                    input_mode = Some(InputMode::Offset(tr));
+                }
+
                 Oper::StepsClick(tr) => {
                     self.mute[tr] = !self.mute[tr];
                     self.override_input_mode =
                         Some(InputModeAtTime(InputMode::Mute(self.mute[tr]), now));
                 }
+
+                Oper::GateLen(tr, held) => {
+                    // The hold as a fraction of the step.
+                    let step = self.predicted.count();
+                    if step > 0 {
+                        let l = held.count() * 100 / step;
+                        self.gate_len[tr] = l.max(GATE_LEN_MIN).min(GATE_LEN_MAX) as u8;
+                        self.override_input_mode =
+                            Some(InputModeAtTime(InputMode::GateLen(self.gate_len[tr]), now));
+                    }
+                }
+
+                Oper::Fill(tr, on) => {
+                    // Only the pattern read at the playhead changes, so the fill
+                    // comes and goes without losing the position in the bar.
+                    self.fill[tr] = on;
+                }
+
+                Oper::Shift(on) => {
+                    self.shift = on;
+                }
+
+                Oper::BurstChance(tr, x) => {
+                    let n = self.burst_chance[tr] as i16 + x as i16;
+                    self.burst_chance[tr] = n.max(0).min(100) as u8;
+                    self.override_input_mode = Some(InputModeAtTime(InputMode::Burst(tr), now));
+                }
+
+                Oper::GateLenDelta(tr, x) => {
+                    let n = self.gate_len[tr] as i64 + x as i64;
+                    self.gate_len[tr] = n.max(GATE_LEN_MIN).min(GATE_LEN_MAX) as u8;
+                    self.override_input_mode =
+                        Some(InputModeAtTime(InputMode::GateLen(self.gate_len[tr]), now));
+                }
+
+                Oper::LengthDefault if self.input_mode == InputMode::Settings => {
+                    // Leave the settings.
+                    input_mode = Some(InputMode::Length);
+                }
+
+                Oper::LengthDefault => {
+                    match self.input_mode.0 {
+                        InputMode::LengthMode => {
+                            self.length_mode = LengthMode::default();
+                            self.update_track_playhead();
+                        }
+                        InputMode::Transport => self.transport = Transport::default(),
+                        InputMode::InputPpqn => {
+                            self.input_ppqn = 1;
+                            self.input_pulse = 0;
+                        }
+                        InputMode::ClockMult => {
+                            self.clock_mult = 1;
+                            self.input_pulse = 0;
+                            self.sub_ticks_left = 0;
+                        }
+                        InputMode::ClockStop => self.clock_stop = ClockStop::default(),
+                        InputMode::ClockQuantize => self.clock_quantize = false,
+                        InputMode::Swing => self.swing = 50,
+                        InputMode::SwingResolution => {
+                            self.swing_resolution = SwingResolution::default()
+                        }
+                        InputMode::LfoSeedHold => self.lfo_seed_hold = false,
+                        InputMode::SeedRange => {
+                            self.seed_max = SEED_MAX;
+                            let s = self.params.seed - SEED_BASE as u32;
+                            if s > self.seed_max {
+                                self.params.seed = self.seed_max + SEED_BASE as u32;
+                                regenerate = true;
+                            }
+                        }
+                        InputMode::SeedCoarse => self.seed_coarse = SEED_COARSE_CHOICES[0],
+                        InputMode::LinkRole => self.link_role = LinkRole::default(),
+                        InputMode::FateEnabled => self.fate_enabled = true,
+                        InputMode::OffsetAbsolute => self.offset_absolute = false,
+                        InputMode::NoEmpty => {
+                            self.no_empty = false;
+                            regenerate = true;
+                        }
+                        InputMode::Lfo(tr) => {
+                            let d = self.lfo[tr].mode as i8;
+                            self.lfo[tr].set_mode(-d);
+                            regenerate_tracks |= 1 << tr;
+                        }
+                        InputMode::Run | InputMode::Length => {
+                            self.params.pattern_length = STOKAST_PARAMS.pattern_length;
+                            input_mode = Some(InputMode::Length);
+                            regenerate = true;
+                        }
+                        // Nothing to reset in this menu.
+                        _ => continue,
+                    }
+
+                    self.input_mode.1 = now;
+                    self.override_input_mode = Some(InputModeAtTime(InputMode::ParamDefault, now));
+                }
+
+                Oper::OffsetDefault(tr) => {
+                    match self.input_mode.0 {
+                        InputMode::Lfo(t) if t == tr => {
+                            let d = self.lfo[tr].mode as i8;
+                            self.lfo[tr].set_mode(-d);
+                            regenerate_tracks |= 1 << tr;
+                        }
+                        InputMode::Algo(t) if t == tr => {
+                            self.algo[tr] = Algo::default();
+                            regenerate_tracks |= 1 << tr;
+                        }
+                        InputMode::GateMode(t) if t == tr => {
+                            self.gate_mode[tr] = GateMode::default()
+                        }
+                        InputMode::GateInvert(t) if t == tr => self.gate_invert[tr] = false,
+                        InputMode::ChannelRole(t) if t == tr => {
+                            self.channel_role[tr] = ChannelRole::default();
+                            self.dac_refresh = true;
+                        }
+                        InputMode::CvBehavior(t) if t == tr => {
+                            self.cv_behavior[tr] = CvBehavior::default();
+                            self.dac_refresh = true;
+                        }
+                        InputMode::GateRole(t) if t == tr => {
+                            self.gate_role[tr] = GateRole::Track(tr)
+                        }
+                        InputMode::Anchor(t) if t == tr => {
+                            self.anchor[tr] = None;
+                            regenerate_tracks |= 1 << tr;
+                        }
+                        InputMode::Burst(t) if t == tr => self.burst_chance[tr] = 0,
+                        InputMode::Humanize(t) if t == tr => self.humanize[tr] = 0,
+                        InputMode::TrackDelay(t) if t == tr => self.track_delay[tr] = 0,
+                        InputMode::Warmup(t) if t == tr => self.warmup[tr] = 0,
+                        InputMode::TrackSync(t) if t == tr => {
+                            self.track_sync[tr] = TrackSync::default()
+                        }
+                        InputMode::LfoReset(t) if t == tr => self.lfo_reset[tr] = false,
+                        InputMode::LfoGate(t) if t == tr => self.gate_from_lfo[tr] = None,
+                        InputMode::GateLenMod(t) if t == tr => self.gate_len_mod_src[tr] = None,
+                        InputMode::StepEdit(t) if t == tr => self.overrides.clear(tr),
+                        InputMode::StepValue(t) if t == tr => self.step_values.clear(tr),
+                        _ => {
+                            self.params.tracks[tr].offset = 0;
+                            input_mode = Some(InputMode::Offset(tr));
+                            regenerate_tracks |= 1 << tr;
+                        }
+                    }
+
+                    self.input_mode.1 = now;
+                    self.override_input_mode = Some(InputModeAtTime(InputMode::ParamDefault, now));
+                }
+
+                Oper::StepsDefault(tr) => {
+                    match self.input_mode.0 {
+                        InputMode::TrackSync(t) if t == tr => {
+                            self.track_sync[tr] = TrackSync::default()
+                        }
+                        _ => {
+                            let d = &STOKAST_PARAMS.tracks[tr];
+                            let t = &mut self.params.tracks[tr];
+                            t.length = d.length;
+                            t.steps = d.steps;
+                            input_mode = Some(InputMode::Steps(tr));
+                            regenerate_tracks |= 1 << tr;
+                        }
+                    }
+
+                    self.input_mode.1 = now;
+                    self.override_input_mode = Some(InputModeAtTime(InputMode::ParamDefault, now));
+                }
             }
         }
 
-        if regenerate {
-            self.regenerate();
+        if regenerate || regenerate_tracks != 0 {
+            if self.frozen.is_some() {
+                self.frozen_pending = true;
+            } else if !regenerate && regenerate_tracks.count_ones() == 1 {
+                self.regenerate_track(regenerate_tracks.trailing_zeros() as usize);
+            } else {
+                self.regenerate();
+            }
         }
 
         if let Some(input_mode) = input_mode {
+            if self.input_mode != input_mode && input_mode.label().is_some() {
+                self.mode_label_at = Some(now);
+            }
+
             self.input_mode = InputModeAtTime(input_mode, now);
         }
     }
 
+    /// Input mode currently shown on the display, taking overrides into account.
+    pub fn display_mode(&self) -> &InputMode {
+        self.override_input_mode
+            .as_ref()
+            .map(|x| &x.0)
+            .unwrap_or(&self.input_mode.0)
+    }
+
+    /// Current input mode, disregarding any override.
+    pub fn input_mode(&self) -> &InputMode {
+        &self.input_mode.0
+    }
+
+    /// The track of the LFO menu, if that's the current input mode.
+    fn lfo_track(&self) -> Option<usize> {
+        if let InputMode::Lfo(tr) = self.input_mode.0 {
+            Some(tr)
+        } else {
+            None
+        }
+    }
+
+    /// Take the request to drive the gates low, if any.
+    pub fn take_gates_clear(&mut self) -> bool {
+        core::mem::replace(&mut self.gates_clear, false)
+    }
+
+    /// Move the user facing seed by a delta, returning whether it changed. The seed
+    /// is 0-9999, or 0-ffff extended. Clamped, since fast and coarse turns jump.
+    fn change_seed(&mut self, delta: i32, now: Time<{ CPU_SPEED }>) -> bool {
+        let s = (self.params.seed - SEED_BASE as u32) as i32;
+        let n = (s + delta).max(0).min(self.seed_max as i32);
+
+        self.seed_step = delta.unsigned_abs().min(u16::MAX as u32) as u16;
+        self.seed_step_at = now;
+
+        if n == s {
+            return false;
+        }
+
+        self.params.seed = (n + SEED_BASE) as u32;
+
+        true
+    }
+
+    /// Take the panic request, if any.
+    pub fn take_panic(&mut self) -> bool {
+        core::mem::replace(&mut self.panic, false)
+    }
+
+    /// Advance the playhead one step.
+    fn tick(&mut self, now: Time<{ CPU_SPEED }>) {
+        self.last = now;
+        self.tick_count += 1;
+
+        self.playhead = if self.next_is_reset {
+            self.next_is_reset = false;
+            self.steps_since_reset = 0;
+
+            0
+        } else {
+            self.steps_since_reset = self.steps_since_reset.saturating_add(1);
+
+            self.playhead + 1
+        };
+
+        trace!(
+            "Tick playhead: {} tick_count: {}",
+            self.playhead,
+            self.tick_count
+        );
+        self.update_track_playhead();
+    }
+
+    /// Snap an early step onto the grid of the predicted tempo, returning the step
+    /// time to use. The gates are delayed by the difference, see `grid_delay()`. A
+    /// late step can't be pulled back, and resyncs the grid.
+    fn quantize(&mut self, at: Time<{ CPU_SPEED }>, grid: i64) -> Time<{ CPU_SPEED }> {
+        self.grid_delay = Time::default();
+
+        if !self.clock_quantize || self.next_is_reset || self.tick_count == 0 {
+            return at;
+        }
+
+        let early = grid - at.count();
+
+        // More than a quarter step early is a change of tempo rather than jitter.
+        if early <= 0 || early > self.predicted.count() / 4 {
+            return at;
+        }
+
+        self.grid_delay.count = early;
+
+        let mut snapped = at;
+        snapped.count = grid;
+        snapped
+    }
+
+    /// Delay of the gates of the current step to land on the grid, when quantizing.
+    pub fn grid_delay(&self) -> Option<Time<{ CPU_SPEED }>> {
+        if self.grid_delay.count() > 0 {
+            Some(self.grid_delay)
+        } else {
+            None
+        }
+    }
+
+    /// Input clock pulses per step, including the global clock divider.
+    fn pulses_per_step(&self) -> u8 {
+        let div = if self.clock_mult < 0 {
+            -self.clock_mult as u8
+        } else {
+            1
+        };
+
+        (self.input_ppqn * div).max(1)
+    }
+
+    /// Interval to the next predicted step.
+    pub fn predicted(&self) -> Time<{ CPU_SPEED }> {
+        self.predicted
+    }
+
+    /// Time of the last step.
+    pub fn last_tick(&self) -> Time<{ CPU_SPEED }> {
+        self.last
+    }
+
+    /// Time until the next step is expected. Zero when it's overdue.
+    pub fn next_tick_eta(&self, now: Time<{ CPU_SPEED }>) -> Time<{ CPU_SPEED }> {
+        let mut eta = self.predicted - (now - self.last);
+
+        if eta.count() < 0 {
+            eta.count = 0;
+        }
+
+        eta
+    }
+
+    /// The parameters the pattern plays, which are held while frozen.
+    pub fn playing(&self) -> &Params<{ TRACK_COUNT }> {
+        self.frozen.as_ref().unwrap_or(&self.params)
+    }
+
     /// Current playhead, 0-63 for instance (depends on pattern length).
     pub fn playhead(&self) -> usize {
-        (self.playhead % self.params.pattern_length as u64) as usize
+        (self.playhead % self.playing().pattern_length as u64) as usize
+    }
+
+    /// Tick the LFOs and cache the most recent values. After a refresh is
+    /// requested, all values are returned, changed or not.
+    pub fn tick_lfo(&mut self, now: Time<{ CPU_SPEED }>) -> [Option<u16>; TRACK_COUNT] {
+        let mut upd = [None; TRACK_COUNT];
+        let refresh = core::mem::replace(&mut self.dac_refresh, false);
+
+        for i in 0..TRACK_COUNT {
+            upd[i] = self.lfo[i].tick(now);
+
+            if let Some(v) = upd[i] {
+                self.lfo_value[i] = v;
+            } else if refresh {
+                upd[i] = Some(self.lfo_value[i]);
+            }
+        }
+
+        upd
+    }
+
+    /// Latch the step values of the channels in `CvBehavior::StepValue` whose gate
+    /// rose, bit 0-3 for gate 1-4. The DAC picks them up on the next `tick_lfo()`.
+    pub fn latch_step_values(&mut self, rising: u8) {
+        for ch in 0..TRACK_COUNT {
+            if rising & 1 << ch == 0 || self.cv_behavior[ch] != CvBehavior::StepValue {
+                continue;
+            }
+
+            // The track playing on the gate of the channel.
+            let tr = match self.gate_role[ch] {
+                GateRole::Track(tr) => tr,
+                GateRole::Mirror(j) => match self.gate_role[j] {
+                    GateRole::Track(tr) => tr,
+                    _ => continue,
+                },
+                _ => continue,
+            };
+
+            self.cv_latch[ch] = self.step_value(tr, self.track_playhead[tr]);
+            self.dac_refresh = true;
+        }
+    }
+
+    /// The value of a step of a track, stored or drawn from the seed of the track,
+    /// and scaled by the LFO depth like the LFO output.
+    pub fn step_value(&self, tr: usize, step: usize) -> u16 {
+        let raw = match self.step_values.get(tr, step) {
+            Some(v) => (v as u32 * lfo::DEPTH_MAX as u32 / STEP_VALUE_MAX as u32) as u16,
+            None => self.drawn_step_value(tr, step),
+        };
+
+        lfo::scale_output(raw, self.lfo[tr].depth)
+    }
+
+    /// The raw 12-bit value of a step drawn from the seed of the track.
+    fn drawn_step_value(&self, tr: usize, step: usize) -> u16 {
+        let mut rnd = Rnd::new(self.seeds.algo[tr] ^ STEP_VALUE_SEED ^ step as u32);
+        (rnd.next() >> 20) as u16
+    }
+
+    /// The current LFO values in DAC channel order.
+    pub fn dac_values(&self) -> [Option<u16>; TRACK_COUNT] {
+        let mut v = [None; TRACK_COUNT];
+
+        for i in 0..TRACK_COUNT {
+            v[i] = Some(self.lfo_value[i]);
+        }
+
+        self.dac_channels(&v)
+    }
+
+    /// Reorder per track LFO updates into DAC channel order. Channels without
+    /// CV output are held at 0.
+    pub fn dac_channels(&self, upd: &[Option<u16>; TRACK_COUNT]) -> [Option<u16>; TRACK_COUNT] {
+        let mut v = self.dac_map.apply(upd);
+
+        for ch in 0..TRACK_COUNT {
+            if !self.channel_role[ch].has_cv() {
+                v[ch] = v[ch].map(|_| 0);
+            } else if self.cv_behavior[ch] == CvBehavior::StepValue {
+                v[ch] = v[ch].map(|_| self.cv_latch[ch]);
+            }
+        }
+
+        v
+    }
+
+    /// The LFO value modulating the gate length of a track, if any.
+    pub fn gate_len_mod(&self, tr: usize) -> Option<u16> {
+        self.gate_len_mod_src[tr].map(|src| self.lfo_value[src])
     }
 
     /// Update the state with passing time.
@@ -360,44 +2283,253 @@ impl State {
             }
         }
 
+        if let Some(at) = self.mode_label_at {
+            if now - at > MODE_LABEL_TIMEOUT {
+                self.mode_label_at = None;
+            }
+        }
+
+        if let Some(at) = self.lfo_audition {
+            if let Some(tr) = self.lfo_track() {
+                if now - at > LFO_AUDITION_INTERVAL {
+                    self.lfo[tr].set_mode(1);
+                    self.lfo_audition = Some(now);
+                    // Stay in the menu while auditioning.
+                    self.input_mode.1 = now;
+                }
+            } else {
+                // Leaving the menu stops auditioning.
+                self.lfo_audition = None;
+            }
+        }
+
+        if self.input_mode == InputMode::Overview && now - self.overview_at > OVERVIEW_INTERVAL {
+            self.overview_track = (self.overview_track + 1) % TRACK_COUNT;
+            self.overview_at = now;
+            // Stay in the overview while it's cycling.
+            self.input_mode.1 = now;
+        }
+
+        if self.seed_step > 1 && now - self.seed_step_at > OVERRIDE_INPUT_TIMEOUT {
+            self.seed_step = 0;
+        }
+
+        // Subdivided steps when multiplying the clock.
+        if self.sub_ticks_left > 0 && now - self.last >= self.predicted {
+            self.sub_ticks_left -= 1;
+            let mut at = self.last;
+            at.count += self.predicted.count();
+            self.tick(at);
+        }
+
+        self.update_clock_stop(now);
+
+        if !self.standby && now - self.last_activity > STANDBY_TIMEOUT {
+            info!("Standby");
+            self.standby = true;
+        }
+
         // Reset back the input mode to the default after a timeout.
         if self.input_mode != InputMode::Run && now - self.input_mode.1 > INPUT_MODE_TIMEOUT {
             self.input_mode = InputModeAtTime(InputMode::Run, now);
         }
 
-        let offset = self.track_offset(now);
+        let offset = self.track_offset(now);
+
+        for (i, lfo) in self.lfo.iter_mut().enumerate() {
+            lfo.set_offset(offset[i]);
+        }
+    }
+
+    /// Detect a stopped external clock, i.e. no pulse for a few predicted intervals,
+    /// and act on it according to the setting.
+    fn update_clock_stop(&mut self, now: Time<{ CPU_SPEED }>) {
+        if self.transport != Transport::Clock || self.tick_count == 0 {
+            return;
+        }
+
+        let predicted = self.predicted.count();
+
+        if predicted <= 0 {
+            return;
+        }
+
+        if !self.clock_stopped && (now - self.last).count() > predicted * CLOCK_STOP_INTERVALS {
+            info!("Clock stopped: {:?}", self.clock_stop);
+            self.clock_stopped = true;
+            self.sub_ticks_left = 0;
+
+            match self.clock_stop {
+                ClockStop::Hold => {}
+                ClockStop::Clear => self.gates_clear = true,
+                // Pick up where the clock left off.
+                ClockStop::Run => {
+                    let mut at = self.last;
+                    at.count += predicted;
+                    self.tick(at);
+                }
+            }
+        }
+
+        // Keep running on the last tempo until the clock resumes.
+        if self.clock_stopped
+            && self.clock_stop == ClockStop::Run
+            && now - self.last >= self.predicted
+        {
+            let mut at = self.last;
+            at.count += predicted;
+            self.tick(at);
+        }
+    }
+
+    /// Apply a script of timed operations in order, as if they came from the inputs.
+    ///
+    /// Time passes between the operations, so timeouts behave as on the hardware.
+    /// Used to simulate the input to state pipeline off target.
+    #[cfg(feature = "sim")]
+    pub fn apply_script(mut self, script: &[(Time<{ CPU_SPEED }>, Oper)]) -> State {
+        for (now, oper) in script {
+            self.update_time(*now);
+            self.update(*now, core::iter::once(oper.clone()));
+        }
+
+        self
+    }
+
+    /// Make every track internally consistent, steps and offset within length.
+    fn normalize(&mut self) {
+        let absolute = self.offset_absolute;
+
+        for t in self.params.tracks.iter_mut() {
+            t.length = t.length.max(2).min(MAX_LEN as u8);
+            t.steps = t.steps.min(t.length);
+
+            if absolute {
+                // A start step past a shortened track becomes the last step.
+                t.offset = t.offset.min(t.length - 1);
+            } else {
+                t.offset %= t.length;
+            }
+        }
+    }
+
+    pub fn regenerate(&mut self) {
+        self.normalize();
+
+        let mut generated = Generated::new(self.params);
+        self.seeds = self.derive_seeds_from(&mut generated);
+        self.generated = generated;
+
+        for i in 0..TRACK_COUNT {
+            self.update_track(i);
+        }
+    }
+
+    /// Regenerate after a change only affecting one track. The result is the
+    /// same as a full `regenerate()`, but the other tracks are left alone when
+    /// their seeds are unchanged.
+    fn regenerate_track(&mut self, tr: usize) {
+        self.normalize();
+
+        // alg generates all tracks in one go, and the seeds are drawn after.
+        let mut generated = Generated::new(self.params);
+        let seeds = self.derive_seeds_from(&mut generated);
+
+        if seeds != self.seeds {
+            // Everything is reseeded, which is a full regenerate.
+            self.generated = generated;
+            self.seeds = seeds;
+
+            for i in 0..TRACK_COUNT {
+                self.update_track(i);
+            }
+
+            return;
+        }
+
+        // Patterns from the other algorithms only depend on the seeds and
+        // their own track, so they are unchanged.
+        for i in 0..TRACK_COUNT {
+            if i != tr && self.algo[i] != Algo::Generative {
+                generated.patterns[i] = self.generated.patterns[i];
+            }
+        }
+
+        self.generated = generated;
+        self.update_track(tr);
+    }
+
+    /// Derive the seeds, keeping the LFO seeds if they are held and the global
+    /// seed is unchanged.
+    fn derive_seeds_from(&mut self, generated: &mut Generated<{ TRACK_COUNT }>) -> DerivedSeeds {
+        let mut seeds = derive_seeds(generated);
 
-        for (i, lfo) in self.lfo.iter_mut().enumerate() {
-            lfo.set_offset(offset[i]);
+        if self.lfo_seed_hold && self.lfo_seed_for == Some(self.params.seed) {
+            seeds.lfo = self.seeds.lfo;
+        } else {
+            self.lfo_seed_for = Some(self.params.seed);
         }
+
+        seeds
     }
 
-    fn regenerate(&mut self) {
-        self.generated = Generated::new(self.params);
+    /// Apply the seeds, algorithm and anchor to a freshly generated track.
+    fn update_track(&mut self, i: usize) {
+        let t = self.params.tracks[i];
 
-        let mut rnd = Rnd::new(self.generated.rnd.next());
+        self.lfo[i].set_seed_length(self.seeds.lfo[i], t.length);
 
-        for (i, lfo) in self.lfo.iter_mut().enumerate() {
-            let length = self.params.tracks[i].length;
-            lfo.set_seed_length(rnd.next(), length);
+        let pattern = &mut self.generated.patterns[i];
+        self.algo[i].generate(self.seeds.algo[i], t.steps, t.length, t.offset, pattern);
+
+        // The pattern is already rotated by the offset, so the anchor is
+        // the step as played.
+        if let Some(a) = self.anchor[i] {
+            pattern[a as usize % t.length as usize] = 1;
         }
 
-        for i in 0..TRACK_COUNT {
-            self.track_per_tick[i] = (u32::MAX / (self.params.tracks[i].length as u32)) as u64
+        // An all rest track gets a hit on the first step as played.
+        if self.no_empty && pattern[..t.length as usize].iter().all(|s| *s == 0) {
+            pattern[0] = 1;
         }
+
+        // The fill keeps the hits and adds about half of the rests.
+        let len = t.length as usize;
+        let rests = len - (t.steps as usize).min(len);
+        let mut extra = [0; MAX_LEN];
+        let seed = self.seeds.algo[i] ^ FILL_SEED;
+        Algo::Density.generate(seed, ((rests + 1) / 2) as u8, t.length, 0, &mut extra);
+
+        let mut fill = 0;
+        for n in 0..len {
+            if pattern[n] != 0 || extra[n] != 0 {
+                fill |= 1 << n;
+            }
+        }
+        self.fill_pattern[i] = fill;
+
+        self.track_per_tick[i] = (u32::MAX / (t.length as u32)) as u64
     }
 
     fn update_track_playhead(&mut self) {
-        let parm = &self.params;
+        // Copied, a borrow of the playing parameters would hold all of self.
+        let parm = *self.playing();
         let plen = parm.pattern_length as usize;
-        let playhead = self.playhead();
 
         for i in 0..TRACK_COUNT {
-            self.track_playhead[i] = match self.track_sync[i] {
-                TrackSync::Sync => playhead % plen.min(parm.tracks[i].length as usize),
-                TrackSync::Free => (self.playhead % parm.tracks[i].length as u64) as usize,
-                TrackSync::Loop => (self.tick_count % parm.tracks[i].length as u64) as usize,
-            };
+            self.track_playhead[i] = compute_track_playhead(
+                self.track_sync[i],
+                self.length_mode,
+                self.playhead,
+                self.tick_count,
+                plen,
+                parm.tracks[i].length as usize,
+            );
+
+            // The playhead counts from reset, so it tells when one run is through.
+            let len = parm.tracks[i].length as u64;
+            self.one_shot_done[i] =
+                self.track_sync[i] == TrackSync::OneShot && self.playhead >= len;
         }
     }
 
@@ -426,13 +2558,258 @@ impl State {
         offs
     }
 
+    /// How much to delay the gate of a track for the current step, if it is swung.
+    ///
+    /// A step is taken to be a 16th note. At 16th resolution every other step is
+    /// swung, and at 8th resolution the third step of every four. The delay is
+    /// relative to the length of a swing pair.
+    pub fn swing_delay(&self, tr: usize) -> Option<Time<{ CPU_SPEED }>> {
+        if self.swing <= 50 {
+            return None;
+        }
+
+        // Steps per swing pair.
+        let pair = match self.swing_resolution {
+            SwingResolution::Eighth => 4,
+            SwingResolution::Sixteenth => 2,
+        };
+
+        if self.track_playhead[tr] % pair != pair / 2 {
+            return None;
+        }
+
+        let step = self.predicted.count();
+
+        let mut delay = Time::default();
+        delay.count = step * pair as i64 * (self.swing - 50) as i64 / 100;
+
+        Some(delay)
+    }
+
+    /// Delay of the current step of a track, combining the grid, swing, humanize and
+    /// the fixed track delay. The sum never reaches the next step, so the hits keep
+    /// their order however the settings stack.
+    pub fn step_delay(&self, tr: usize) -> Option<Time<{ CPU_SPEED }>> {
+        let delays = [
+            self.grid_delay(),
+            self.swing_delay(tr),
+            self.humanize_delay(tr),
+            self.fixed_delay(tr),
+        ];
+
+        let total: i64 = delays.iter().flatten().map(|d| d.count).sum();
+
+        if total == 0 {
+            return None;
+        }
+
+        // Leave an eighth of the step for the gate before the next hit.
+        let step = self.predicted.count();
+        let max = step - step / 8;
+
+        let mut delay = Time::default();
+        delay.count = total.min(max).max(0);
+
+        Some(delay)
+    }
+
+    /// Fixed delay of every hit of a track.
+    fn fixed_delay(&self, tr: usize) -> Option<Time<{ CPU_SPEED }>> {
+        if self.track_delay[tr] == 0 {
+            return None;
+        }
+
+        let mut delay = Time::default();
+        delay.count = self.track_delay[tr] as i64 * (CPU_SPEED / 1000) as i64;
+
+        Some(delay)
+    }
+
+    /// Random delay for the current step of a track. Drawn from the seed, track and
+    /// step so it's reproducible. Never more than a quarter step, to stay clear of
+    /// the next hit even when combined with swing.
+    pub fn humanize_delay(&self, tr: usize) -> Option<Time<{ CPU_SPEED }>> {
+        let max = self.humanize[tr] as i64 * (CPU_SPEED / 1000) as i64;
+
+        if max == 0 {
+            return None;
+        }
+
+        let step = self.track_playhead[tr] as u32;
+        let seed = self.playing().seed;
+        let mut rnd = Rnd::new(seed ^ ((tr as u32) << 16) ^ (step << 8) ^ 0x4a17);
+
+        let quarter = self.predicted.count() / 4;
+        let max = max.min(quarter);
+
+        if max <= 0 {
+            return None;
+        }
+
+        let mut delay = Time::default();
+        delay.count = rnd.next() as i64 % (max + 1);
+
+        Some(delay)
+    }
+
+    /// Number of triggers to fire for the current step of a track, 0 for a
+    /// plain gate. Drawn from the seed, track and step so it's reproducible.
+    pub fn burst_count(&self, tr: usize) -> u8 {
+        let chance = self.burst_chance[tr] as u32;
+
+        if chance == 0 {
+            return 0;
+        }
+
+        let step = self.track_playhead[tr] as u32;
+        let seed = self.playing().seed;
+        let mut rnd = Rnd::new(seed ^ ((tr as u32) << 16) ^ (step << 8) ^ 0xb0b5);
+
+        if rnd.next() % 100 < chance {
+            // 2-4 triggers.
+            2 + (rnd.next() % 3) as u8
+        } else {
+            0
+        }
+    }
+
+    /// Whether the current step is on a beat, for the metronome.
+    pub fn is_beat(&self) -> bool {
+        self.playhead() % STEPS_PER_BEAT == 0
+    }
+
+    /// The pulse width of the gate outputs not driven by a track.
+    pub fn sync_width(&self) -> Time<{ CPU_SPEED }> {
+        let mut w = Time::default();
+        w.count = self.sync_width_ms as i64 * (CPU_SPEED / 1000) as i64;
+        w
+    }
+
+    /// The gate width for a track following the clock width. None if the track
+    /// doesn't follow the clock, or there is no measurable clock width.
+    pub fn gate_width(&self, tr: usize) -> Option<Time<{ CPU_SPEED }>> {
+        if self.gate_mode[tr] != GateMode::Clock {
+            return None;
+        }
+
+        let w = self.clock_width;
+
+        if w.count() > 0 && w < self.predicted {
+            Some(w)
+        } else {
+            None
+        }
+    }
+
+    /// The step value of a track, taking manual overrides into account.
+    pub fn step_at(&self, tr: usize, step: usize) -> u8 {
+        match self.overrides.get(tr, step) {
+            Some(true) => 1,
+            Some(false) => 0,
+            None => self.generated.patterns[tr][step],
+        }
+    }
+
+    /// Whether a track is still silent after the last reset.
+    pub fn in_warmup(&self, tr: usize) -> bool {
+        self.steps_since_reset < self.warmup[tr] as u32
+    }
+
+    /// The step as played, which is the fill while it's held.
+    pub fn played_step_at(&self, tr: usize, step: usize) -> u8 {
+        if self.fill[tr] && self.fill_pattern[tr] & (1 << step) != 0 {
+            1
+        } else {
+            self.step_at(tr, step)
+        }
+    }
+
+    /// Iterate the indices of the active steps of a track, with offset and
+    /// manual overrides applied.
+    pub fn iter_hits(&self, tr: usize) -> impl Iterator<Item = usize> + '_ {
+        let len = self.playing().tracks[tr].length as usize;
+
+        (0..len).filter(move |n| self.step_at(tr, *n) != 0)
+    }
+
+    /// The first active step of a track, with offset applied.
+    pub fn first_step(&self, tr: usize) -> Option<u8> {
+        self.iter_hits(tr).next().map(|n| n as u8)
+    }
+
+    /// The step following the current track playhead.
+    pub fn track_next_step(&self, tr: usize) -> usize {
+        let parm = self.playing();
+        let len = parm.tracks[tr].length as usize;
+
+        let len = match (self.track_sync[tr], self.length_mode) {
+            (TrackSync::Sync, LengthMode::Clip) => len.min(parm.pattern_length as usize),
+            _ => len,
+        };
+
+        (self.track_playhead[tr] + 1) % len
+    }
+
+    /// Decimal points to light on the segment display.
+    ///
+    /// In run mode, the number of lit decimal points shows the clock division, and
+    /// blinking ones the multiplication. Off when the clock is 1:1.
+    pub fn to_display_dp(&self) -> u8 {
+        let mode = self.display_mode();
+
+        // The dp of the digit changing, when the seed moves by 10, 100 or 1000.
+        if *mode == InputMode::Seed {
+            return if self.seed_step >= 1000 {
+                1 << 3
+            } else if self.seed_step >= 100 {
+                1 << 2
+            } else if self.seed_step >= 10 {
+                1 << 1
+            } else {
+                0
+            };
+        }
+
+        // The dp of the digit counting from the left is the track.
+        if *mode == InputMode::Overview {
+            return 1 << (3 - self.overview_track);
+        }
+
+        if *mode != InputMode::Run {
+            return 0;
+        }
+
+        let n = self.clock_mult.abs().min(4);
+
+        if n <= 1 {
+            return 0;
+        }
+
+        let dp = (1 << n) - 1;
+
+        // Multiplied steps blink, lit every other step.
+        if self.clock_mult > 0 && self.playhead % 2 == 1 {
+            0
+        } else {
+            dp
+        }
+    }
+
     /// Represent the current state on the segment display.
     pub fn to_display(&self) -> Segs4 {
-        let mode = self
-            .override_input_mode
-            .as_ref()
-            .map(|x| &x.0)
-            .unwrap_or(&self.input_mode.0);
+        let mode = self.display_mode();
+
+        // Shift held, until a secondary parameter is turned.
+        if self.shift && self.override_input_mode.is_none() {
+            return "fn  ".into();
+        }
+
+        // Briefly name the menu we just entered, unless an override is showing.
+        if self.mode_label_at.is_some() && self.override_input_mode.is_none() {
+            if let Some(label) = mode.label() {
+                return label.into();
+            }
+        }
 
         match mode {
             InputMode::Run => {
@@ -442,57 +2819,361 @@ impl State {
                 segs.0[2] = Seg::from(playhead as u8 % 10) as u8;
                 segs.0[3] = Seg::from((playhead as u8 / 10) % 10) as u8;
 
-                // Loop animation over 6 frames synced on playhead.
-                const LOOP: [u8; 6] = [
-                    Seg::SegA as u8,
-                    Seg::SegB as u8,
-                    Seg::SegC as u8,
-                    Seg::SegD as u8,
-                    Seg::SegE as u8,
-                    Seg::SegF as u8,
-                ];
+                // Loop animation over 6 frames synced on playhead.
+                const LOOP: [u8; 6] = [
+                    Seg::SegA as u8,
+                    Seg::SegB as u8,
+                    Seg::SegC as u8,
+                    Seg::SegD as u8,
+                    Seg::SegE as u8,
+                    Seg::SegF as u8,
+                ];
+
+                let li = playhead % 6;
+                let c = LOOP[li];
+                segs.0[1] = if self.supply_low {
+                    // Warn of a sagging supply in place of the animation.
+                    Seg::U as u8
+                } else {
+                    c
+                };
+                segs.0[4] = if self.frozen.is_some() {
+                    // Lock glyph when frozen.
+                    Seg::L as u8
+                } else {
+                    c
+                };
+
+                segs
+            }
+
+            InputMode::Seed => {
+                let s = self.params.seed - SEED_BASE as u32;
+
+                if self.seed_max > SEED_MAX {
+                    hex_segs(s as u16)
+                } else {
+                    Segs4::from_right_aligned(s, 4, true)
+                }
+            }
+
+            InputMode::SeedRange => {
+                if self.seed_max > SEED_MAX {
+                    "b 16"
+                } else {
+                    "b 10"
+                }
+            }
+            .into(),
+
+            InputMode::SeedCoarse => Segs4::from_right_aligned(self.seed_coarse as u32, 4, true),
+
+            InputMode::LinkRole => match self.link_role {
+                LinkRole::Off => "off ",
+                LinkRole::Lead => "lead",
+                LinkRole::Follow => "foll",
+                LinkRole::FollowSeed => "fsed",
+            }
+            .into(),
+
+            InputMode::Fate => "fate".into(),
+
+            InputMode::FateEnabled => {
+                if self.fate_enabled {
+                    "on  "
+                } else {
+                    "off "
+                }
+            }
+            .into(),
+
+            InputMode::OffsetAbsolute => {
+                if self.offset_absolute {
+                    "abs "
+                } else {
+                    "rel "
+                }
+            }
+            .into(),
+
+            InputMode::NoEmpty => {
+                if self.no_empty {
+                    "on  "
+                } else {
+                    "off "
+                }
+            }
+            .into(),
+
+            InputMode::Length => self.params.pattern_length.into(),
+
+            InputMode::Transport => match self.transport {
+                Transport::Clock => "cloc",
+                Transport::Manual => "step",
+            }
+            .into(),
+
+            InputMode::ClockMult => {
+                // d for divide, t for times.
+                let mut segs: Segs4 = if self.clock_mult < 0 { "d   " } else { "t   " }.into();
+                segs.0[1] = Seg::from(self.clock_mult.abs() as u8) as u8;
+                segs
+            }
+
+            InputMode::ClockQuantize => {
+                if self.clock_quantize {
+                    "on  "
+                } else {
+                    "off "
+                }
+            }
+            .into(),
+
+            InputMode::ClockStop => match self.clock_stop {
+                ClockStop::Hold => "hold",
+                ClockStop::Clear => "clr ",
+                ClockStop::Run => "run ",
+            }
+            .into(),
+
+            InputMode::Swing => self.swing.into(),
+
+            InputMode::SyncWidth(_) => self.sync_width_ms.into(),
+
+            InputMode::Settings => SETTINGS[self.setting].label().unwrap_or("    ").into(),
+
+            InputMode::OffsetAll => "shft".into(),
+
+            InputMode::LfoDepth(d) => (*d).into(),
+
+            InputMode::GateLen(l) => (*l).into(),
+
+            InputMode::ParamDefault => "dflt".into(),
+
+            InputMode::LfoSeedHold => {
+                if self.lfo_seed_hold {
+                    "hold"
+                } else {
+                    "rand"
+                }
+            }
+            .into(),
+
+            InputMode::SwingResolution => match self.swing_resolution {
+                SwingResolution::Eighth => "8th ",
+                SwingResolution::Sixteenth => "16th",
+            }
+            .into(),
+
+            InputMode::InputPpqn => match self.input_ppqn {
+                1 => "p  1",
+                2 => "p  2",
+                4 => "p  4",
+                24 => "p 24",
+                _ => "p   ",
+            }
+            .into(),
+
+            InputMode::LengthMode => match self.length_mode {
+                LengthMode::Clip => "clip",
+                LengthMode::Independent => "poly",
+            }
+            .into(),
+
+            InputMode::Offset(tr) => {
+                let offset = self.params.tracks[*tr].offset;
+
+                // Absolute editing shows just the start step.
+                if !self.offset_show_first || self.offset_absolute {
+                    return offset.into();
+                }
+
+                let mut segs = Segs4::new();
+
+                if let Some(f) = self.first_step(*tr) {
+                    segs.0[1] = Seg::from(f % 10) as u8;
+                    segs.0[2] = Seg::from((f / 10) % 10) as u8;
+                } else {
+                    // No active steps.
+                    segs.0[1] = Seg::SegG as u8;
+                    segs.0[2] = Seg::SegG as u8;
+                }
+                segs.0[3] = Seg::from(offset % 10) as u8;
+                segs.0[4] = Seg::from((offset / 10) % 10) as u8;
+
+                segs
+            }
+
+            InputMode::Lfo(tr) => match self.lfo[*tr].mode {
+                lfo::Mode::Random => "rand".into(),
+                lfo::Mode::SawUp => SAW_UP,
+                lfo::Mode::SawDown => SAW_DN,
+                lfo::Mode::Sine => "sine".into(),
+                lfo::Mode::Sine90 => "si90".into(),
+                lfo::Mode::Sine180 => "s180".into(),
+                lfo::Mode::Triangle => "tria".into(),
+                lfo::Mode::Triangle90 => "tr90".into(),
+                lfo::Mode::Triangle180 => "t180".into(),
+                lfo::Mode::Square => "puls".into(),
+                lfo::Mode::Square90 => "pu90".into(),
+                lfo::Mode::Square180 => "p180".into(),
+                // There is no K on the display.
+                lfo::Mode::Drunk => "drft".into(),
+            },
+
+            InputMode::Algo(tr) => self.algo[*tr].label().into(),
+
+            InputMode::GateMode(tr) => match self.gate_mode[*tr] {
+                GateMode::Gate => "gate",
+                GateMode::Hold => "hold",
+                GateMode::Clock => "cloc",
+            }
+            .into(),
+
+            InputMode::Burst(tr) => self.burst_chance[*tr].into(),
+
+            InputMode::Humanize(tr) => self.humanize[*tr].into(),
+
+            InputMode::TrackDelay(tr) => self.track_delay[*tr].into(),
+
+            InputMode::Warmup(tr) => Segs4::from_right_aligned(self.warmup[*tr] as u32, 4, true),
+
+            InputMode::LfoReset(tr) => {
+                if self.lfo_reset[*tr] {
+                    "on  "
+                } else {
+                    "off "
+                }
+            }
+            .into(),
+
+            InputMode::LfoGate(tr) => match self.gate_from_lfo[*tr] {
+                Some(t) => lfo_percent(t).into(),
+                None => "off ".into(),
+            },
+
+            InputMode::GateLenMod(tr) => match self.gate_len_mod_src[*tr] {
+                Some(0) => "lfo1",
+                Some(1) => "lfo2",
+                Some(2) => "lfo3",
+                Some(_) => "lfo4",
+                None => "off ",
+            }
+            .into(),
+
+            InputMode::Anchor(tr) => match self.anchor[*tr] {
+                // Steps are shown 1-based.
+                Some(a) => (a + 1).into(),
+                None => "off ".into(),
+            },
+
+            InputMode::GateRole(tr) => match self.gate_role[*tr] {
+                GateRole::Track(0) => "tr 1",
+                GateRole::Track(1) => "tr 2",
+                GateRole::Track(2) => "tr 3",
+                GateRole::Track(_) => "tr 4",
+                GateRole::Clock => "cloc",
+                GateRole::Reset => "rset",
+                GateRole::BarStart => "bar ",
+                GateRole::EndOfCycle => "eoc ",
+                // There is no M on the display.
+                GateRole::Mirror(0) => "cpy1",
+                GateRole::Mirror(1) => "cpy2",
+                GateRole::Mirror(2) => "cpy3",
+                GateRole::Mirror(_) => "cpy4",
+                GateRole::Metronome => "beat",
+                GateRole::AnyActive(mask) => return gate_mask_segs(mask),
+                // Coincidence of two tracks.
+                GateRole::Coincidence(a, b) => {
+                    let mut segs = Segs4::new();
+                    segs.0[4] = Seg::C as u8;
+                    segs.0[2] = Seg::from(a as u8 + 1) as u8;
+                    segs.0[1] = Seg::from(b as u8 + 1) as u8;
+                    return segs;
+                }
+            }
+            .into(),
+
+            InputMode::GateInvert(tr) => {
+                if self.gate_invert[*tr] {
+                    "neg "
+                } else {
+                    "pos "
+                }
+            }
+            .into(),
+
+            InputMode::CvBehavior(tr) => match self.cv_behavior[*tr] {
+                CvBehavior::Lfo => "lfo ",
+                CvBehavior::StepValue => "step",
+            }
+            .into(),
+
+            InputMode::ChannelRole(tr) => match self.channel_role[*tr] {
+                ChannelRole::Both => "both",
+                ChannelRole::CvOnly => "lfo ",
+                ChannelRole::GateOnly => "gate",
+            }
+            .into(),
+
+            InputMode::StepEdit(tr) => {
+                let n = self.edit_step + 1;
+
+                let mut segs = Segs4::new();
 
-                let li = playhead % 6;
-                let c = LOOP[li];
-                segs.0[1] = c;
-                segs.0[4] = c;
+                // Selected step to the left, and whether it's active to the right.
+                segs.0[1] = if self.step_at(*tr, self.edit_step) != 0 {
+                    Seg::N1 as u8
+                } else {
+                    Seg::SegD as u8
+                };
+                segs.0[3] = Seg::from((n % 10) as u8) as u8;
+                segs.0[4] = Seg::from(((n / 10) % 10) as u8) as u8;
 
                 segs
             }
 
-            InputMode::Seed => (self.params.seed - SEED_BASE as u32).into(),
+            InputMode::StepValue(tr) => {
+                let n = self.edit_step + 1;
 
-            InputMode::Fate => "fate".into(),
+                let mut segs = Segs4::new();
 
-            InputMode::Length => self.params.pattern_length.into(),
+                // Selected step to the left, and the stored value to the right.
+                if let Some(v) = self.step_values.get(*tr, self.edit_step) {
+                    segs.0[1] = Seg::from(v % 10) as u8;
+                    segs.0[2] = Seg::from((v / 10) % 10) as u8;
+                } else {
+                    // Drawn from the seed.
+                    segs.0[1] = Seg::SegG as u8;
+                    segs.0[2] = Seg::SegG as u8;
+                }
+                segs.0[3] = Seg::from((n % 10) as u8) as u8;
+                segs.0[4] = Seg::from(((n / 10) % 10) as u8) as u8;
 
-            InputMode::Offset(tr) => self.params.tracks[*tr].offset.into(),
+                segs
+            }
 
-            InputMode::Lfo(tr) => match self.lfo[*tr].mode {
-                lfo::Mode::Random => "rand".into(),
-                lfo::Mode::SawUp => SAW_UP,
-                lfo::Mode::SawDown => SAW_DN,
-                lfo::Mode::Sine => "sine".into(),
-                lfo::Mode::Sine90 => "si90".into(),
-                lfo::Mode::Sine180 => "s180".into(),
-                lfo::Mode::Triangle => "tria".into(),
-                lfo::Mode::Triangle90 => "tr90".into(),
-                lfo::Mode::Triangle180 => "t180".into(),
-                lfo::Mode::Square => "puls".into(),
-                lfo::Mode::Square90 => "pu90".into(),
-                lfo::Mode::Square180 => "p180".into(),
-            },
+            InputMode::Steps(_) | InputMode::Overview => {
+                let tr = match mode {
+                    InputMode::Steps(tr) => *tr,
+                    _ => self.overview_track,
+                };
 
-            InputMode::Steps(tr) => {
                 let (s, l) = {
-                    let p = &self.params.tracks[*tr];
+                    let p = &self.params.tracks[tr];
                     (p.steps, p.length)
                 };
 
                 let mut segs = Segs4::new();
 
-                segs.0[1] = Seg::from(s % 10) as u8;
-                segs.0[2] = Seg::from((s / 10) % 10) as u8;
+                if self.first_step(tr).is_some() {
+                    segs.0[1] = Seg::from(s % 10) as u8;
+                    segs.0[2] = Seg::from((s / 10) % 10) as u8;
+                } else {
+                    // All rests, the track is silent whatever the steps.
+                    segs.0[1] = Seg::SegG as u8;
+                    segs.0[2] = Seg::SegG as u8;
+                }
                 segs.0[3] = Seg::from(l % 10) as u8;
                 segs.0[4] = Seg::from((l / 10) % 10) as u8;
 
@@ -503,6 +3184,7 @@ impl State {
                 TrackSync::Sync => "sync",
                 TrackSync::Free => "free",
                 TrackSync::Loop => "loop",
+                TrackSync::OneShot => "once",
             }
             .into(),
 
@@ -514,15 +3196,51 @@ impl State {
                 }
             }
             .into(),
+
+            InputMode::FactoryReset => "rset".into(),
+
+            InputMode::ClearPerformance => "clr ".into(),
+
+            InputMode::LfoAudition(on) => {
+                if *on {
+                    "auto"
+                } else {
+                    "stop"
+                }
+            }
+            .into(),
+
+            InputMode::LfoCoupling(c) => match c {
+                lfo::Coupling::Rise => "rise",
+                lfo::Coupling::Fall => "fall",
+                lfo::Coupling::Off => "off ",
+            }
+            .into(),
+
+            InputMode::LfoRetrigger(on) => {
+                if *on {
+                    "rtrg"
+                } else {
+                    "free"
+                }
+            }
+            .into(),
+
+            InputMode::Frozen(on) => {
+                if *on {
+                    "hold"
+                } else {
+                    "free"
+                }
+            }
+            .into(),
         }
     }
 
-    fn tonight_im_in_the_hands_of_fate(&mut self) {
+    fn tonight_im_in_the_hands_of_fate(&mut self, fate_seed: u32) {
         // TODO: the logic here should maybe be moved into alg?
 
-        // Cycle count is probably random enough as starting point.
-        let seed = DWT::get_cycle_count();
-        let mut rnd = Rnd::new(seed);
+        let mut rnd = Rnd::new(fate_seed);
 
         // do tracks before global seed since the seed is further used
         // for randomization and we don't want the same values.
@@ -573,16 +3291,178 @@ impl State {
             }
         }
 
-        self.params.seed = (rnd.next() / (u32::MAX / 9999)) + SEED_BASE as u32;
-
-        // Make moar magic happen
-        self.regenerate();
+        self.params.seed =
+            (rnd.next() / (u32::MAX / self.seed_max)).min(self.seed_max) + SEED_BASE as u32;
 
         // Next tick will start from 0
         self.next_is_reset = true;
     }
 }
 
+/// Compute the playhead of a track.
+///
+/// * `length_mode` decides whether synced tracks are clipped to pattern length.
+/// * `playhead` is the global playhead, not wrapped at pattern length.
+/// * `tick_count` is the never resetting count of clock ticks.
+/// * `plen` is the pattern length.
+/// * `track_len` is the length of this track.
+pub fn compute_track_playhead(
+    sync: TrackSync,
+    length_mode: LengthMode,
+    playhead: u64,
+    tick_count: u64,
+    plen: usize,
+    track_len: usize,
+) -> usize {
+    // An independent synced track longer than the pattern isn't restarted by it.
+    let sync = match (sync, length_mode) {
+        (TrackSync::Sync, LengthMode::Independent) if track_len > plen => TrackSync::Free,
+        _ => sync,
+    };
+
+    let count = match sync {
+        TrackSync::Sync => playhead % plen as u64,
+        TrackSync::Free | TrackSync::OneShot => playhead,
+        TrackSync::Loop => tick_count,
+    };
+
+    let step = count as usize;
+
+    match sync {
+        TrackSync::Sync => step % plen.min(track_len),
+        _ => step % track_len,
+    }
+}
+
+/// Seed for randomizing using fate.
+///
+/// Cycle count is probably random enough as starting point.
+pub fn fate_seed() -> u32 {
+    DWT::get_cycle_count()
+}
+
+/// Show a number as 4 hexadecimal digits.
+fn hex_segs(mut n: u16) -> Segs4 {
+    let mut segs = Segs4::new();
+
+    for i in 1..5 {
+        let d = (n & 0xf) as u8;
+        segs.0[i] = if d < 10 {
+            Seg::from(d)
+        } else {
+            Seg::from(b'a' + d - 10)
+        } as u8;
+        n >>= 4;
+    }
+
+    segs
+}
+
+/// Show a mask of gate outputs, gate 1 leftmost. A gate in the mask shows its
+/// number, the others a dash.
+fn gate_mask_segs(mask: u8) -> Segs4 {
+    let mut segs = Segs4::new();
+
+    for i in 0..TRACK_COUNT {
+        segs.0[4 - i] = if mask & 1 << i != 0 {
+            Seg::from(i as u8 + 1)
+        } else {
+            Seg::SegG
+        } as u8;
+    }
+
+    segs
+}
+
+/// An LFO value in percent of the range, rounded.
+fn lfo_percent(v: u16) -> u8 {
+    ((v as u32 * 100 + lfo::DEPTH_MAX as u32 / 2) / lfo::DEPTH_MAX as u32) as u8
+}
+
+/// Seeds derived from a generated pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DerivedSeeds {
+    /// Seed per track LFO.
+    pub lfo: [u32; TRACK_COUNT],
+    /// Seed per track pattern algorithm.
+    pub algo: [u32; TRACK_COUNT],
+}
+
+/// Derive the seeds from a freshly generated pattern. This consumes the random
+/// sequence of `generated` the same way as `regenerate()`.
+pub fn derive_seeds(generated: &mut Generated<{ TRACK_COUNT }>) -> DerivedSeeds {
+    let mut rnd = Rnd::new(generated.rnd.next());
+
+    let mut seeds = DerivedSeeds {
+        lfo: [0; TRACK_COUNT],
+        algo: [0; TRACK_COUNT],
+    };
+
+    for s in seeds.lfo.iter_mut() {
+        *s = rnd.next();
+    }
+
+    // Drawn after the LFO seeds to keep those stable.
+    for s in seeds.algo.iter_mut() {
+        *s = rnd.next();
+    }
+
+    seeds
+}
+
+/// The full sequence of seeds derived by `regenerate()` for the given params.
+pub fn derived_seeds(params: Params<{ TRACK_COUNT }>) -> DerivedSeeds {
+    derive_seeds(&mut Generated::new(params))
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Clock
+    }
+}
+
+impl Default for LengthMode {
+    fn default() -> Self {
+        LengthMode::Clip
+    }
+}
+
+impl Default for ChannelRole {
+    fn default() -> Self {
+        ChannelRole::Both
+    }
+}
+
+impl Default for CvBehavior {
+    fn default() -> Self {
+        CvBehavior::Lfo
+    }
+}
+
+impl Default for ClockStop {
+    fn default() -> Self {
+        ClockStop::Hold
+    }
+}
+
+impl Default for SwingResolution {
+    fn default() -> Self {
+        SwingResolution::Sixteenth
+    }
+}
+
+impl Default for GateRole {
+    fn default() -> Self {
+        GateRole::Track(0)
+    }
+}
+
+impl Default for GateMode {
+    fn default() -> Self {
+        GateMode::Gate
+    }
+}
+
 impl Default for TrackSync {
     fn default() -> Self {
         TrackSync::Sync
@@ -600,3 +3480,580 @@ impl<const X: u32> PartialEq<InputMode> for InputModeAtTime<X> {
         self.0 == *other
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(v: i64) -> Time<{ CPU_SPEED }> {
+        let mut t = Time::default();
+        t.count = v * (CPU_SPEED / 1000) as i64;
+        t
+    }
+
+    fn apply(st: &mut State, now: Time<{ CPU_SPEED }>, oper: Oper) {
+        st.update(now, core::iter::once(oper));
+    }
+
+    #[test]
+    fn track_playhead_sync() {
+        use LengthMode::Clip;
+        use TrackSync::Sync;
+
+        // Restarted by the pattern length, and loops within the track length.
+        assert_eq!(compute_track_playhead(Sync, Clip, 13, 13, 16, 12), 1);
+        assert_eq!(compute_track_playhead(Sync, Clip, 16, 16, 16, 12), 0);
+
+        // A track longer than the pattern is clipped.
+        assert_eq!(compute_track_playhead(Sync, Clip, 9, 9, 8, 12), 1);
+    }
+
+    #[test]
+    fn track_playhead_free_and_loop() {
+        use LengthMode::Clip;
+        use TrackSync::{Free, Loop};
+
+        assert_eq!(compute_track_playhead(Free, Clip, 16, 16, 16, 12), 4);
+
+        // Loop ignores reset, which only zeroes the playhead.
+        assert_eq!(compute_track_playhead(Loop, Clip, 0, 30, 16, 12), 6);
+    }
+
+    #[test]
+    fn track_playhead_own_length() {
+        let lengths = [3, 5, 7, 4];
+
+        let mut st = State::new();
+        st.params.pattern_length = 16;
+        for (t, l) in st.params.tracks.iter_mut().zip(lengths.iter()) {
+            t.length = *l;
+        }
+        st.regenerate();
+
+        for sync in [TrackSync::Sync, TrackSync::Free, TrackSync::Loop].iter() {
+            st.track_sync = [*sync; TRACK_COUNT];
+
+            for n in 0..40 {
+                // Loop follows the tick count, which a reset doesn't zero.
+                st.playhead = n;
+                st.tick_count = n + 100;
+                st.update_track_playhead();
+
+                for (i, l) in lengths.iter().enumerate() {
+                    let expected = match sync {
+                        TrackSync::Sync => (n % 16) % *l as u64,
+                        TrackSync::Loop => (n + 100) % *l as u64,
+                        _ => n % *l as u64,
+                    };
+                    assert_eq!(
+                        st.track_playhead[i], expected as usize,
+                        "{:?} track {} at {}",
+                        sync, i, n
+                    );
+                }
+            }
+        }
+    }
+
+    /// A state with track 0 an euclidean pattern, hitting every fourth step.
+    fn euclid() -> State {
+        let mut st = State::new();
+
+        st.algo[0] = Algo::Euclid;
+        let t = &mut st.params.tracks[0];
+        t.length = 16;
+        t.steps = 4;
+        t.offset = 0;
+
+        st.regenerate();
+
+        st
+    }
+
+    #[test]
+    fn iter_hits_overrides() {
+        let mut st = euclid();
+
+        let hits: Vec<_> = st.iter_hits(0).collect();
+        assert_eq!(hits, [0, 4, 8, 12]);
+
+        st.overrides.set(0, 2, true);
+        st.overrides.set(0, 4, false);
+
+        let hits: Vec<_> = st.iter_hits(0).collect();
+        assert_eq!(hits, [0, 2, 8, 12]);
+        assert_eq!(st.first_step(0), Some(0));
+    }
+
+    #[test]
+    fn steps_clamped_to_length() {
+        let mut st = State::new();
+
+        let t = &mut st.params.tracks[1];
+        t.length = 5;
+        t.steps = 9;
+        t.offset = 7;
+        st.regenerate();
+
+        let t = &st.params.tracks[1];
+        assert_eq!((t.length, t.steps, t.offset), (5, 5, 2));
+
+        // An absolute offset past the end becomes the last step.
+        st.offset_absolute = true;
+        st.params.tracks[1].offset = 7;
+        st.regenerate();
+        assert_eq!(st.params.tracks[1].offset, 4);
+    }
+
+    #[test]
+    fn independent_length_mode() {
+        use LengthMode::Independent;
+        use TrackSync::Sync;
+
+        // A track longer than the pattern plays its full length.
+        assert_eq!(compute_track_playhead(Sync, Independent, 9, 9, 8, 12), 9);
+
+        // A shorter one is still restarted by the pattern.
+        assert_eq!(compute_track_playhead(Sync, Independent, 16, 16, 16, 12), 0);
+
+        let mut st = State::new();
+        st.params.pattern_length = 8;
+        st.params.tracks[0].length = 12;
+        st.track_playhead[0] = 9;
+
+        assert_eq!(st.track_next_step(0), 2);
+        st.length_mode = Independent;
+        assert_eq!(st.track_next_step(0), 10);
+    }
+
+    #[test]
+    fn tick_at_edge_time() {
+        let mut st = State::new();
+
+        // The main loop sees the edges late, the tick is at the edge.
+        apply(&mut st, ms(3), Oper::Tick(ms(0), None));
+        assert_eq!(st.last_tick(), ms(0));
+
+        apply(&mut st, ms(104), Oper::Tick(ms(100), Some(ms(100))));
+        assert_eq!(st.last_tick(), ms(100));
+        assert_eq!(st.playhead(), 2);
+    }
+
+    #[test]
+    fn regenerate_track_as_full() {
+        let mut st = euclid();
+        st.algo[2] = Algo::Density;
+        st.algo[3] = Algo::Nth;
+        st.regenerate();
+
+        for tr in 0..TRACK_COUNT {
+            let mut one = st.clone();
+            let t = &mut one.params.tracks[tr];
+            t.steps = t.steps.saturating_sub(1);
+            t.offset = 1;
+
+            let mut full = one.clone();
+            one.regenerate_track(tr);
+            full.regenerate();
+
+            for i in 0..TRACK_COUNT {
+                assert_eq!(
+                    &one.generated.patterns[i][..],
+                    &full.generated.patterns[i][..]
+                );
+                assert_eq!(one.fill_pattern[i], full.fill_pattern[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn next_tick_eta() {
+        let mut st = State::new();
+        st.transport = Transport::Manual;
+
+        apply(&mut st, ms(1000), Oper::Advance);
+
+        assert_eq!(st.predicted(), MANUAL_STEP_INTERVAL);
+        assert_eq!(st.last_tick(), ms(1000));
+        assert_eq!(st.next_tick_eta(ms(1100)), ms(150));
+
+        // Overdue.
+        assert_eq!(st.next_tick_eta(ms(1300)), ms(0));
+    }
+
+    /// The user facing seed.
+    fn seed(st: &State) -> u32 {
+        st.params.seed - SEED_BASE as u32
+    }
+
+    #[test]
+    fn seed_range() {
+        let mut st = State::new();
+
+        apply(&mut st, ms(0), Oper::SetSeed(9998));
+        apply(&mut st, ms(10), Oper::Seed(5));
+        assert_eq!(seed(&st), 9999);
+
+        st.input_mode = InputModeAtTime(InputMode::SeedRange, ms(20));
+        apply(&mut st, ms(20), Oper::Length(1));
+        assert_eq!(st.seed_max, SEED_MAX_EXTENDED);
+
+        apply(&mut st, ms(30), Oper::SetSeed(0xabcd));
+        assert_eq!(seed(&st), 0xabcd);
+
+        // Shown in hex.
+        st.mode_label_at = None;
+        st.override_input_mode = None;
+        assert_eq!(st.to_display(), hex_segs(0xabcd));
+
+        // Back to the default range clamps the seed.
+        st.input_mode = InputModeAtTime(InputMode::SeedRange, ms(40));
+        apply(&mut st, ms(40), Oper::Length(-1));
+        assert_eq!(st.seed_max, SEED_MAX);
+        assert_eq!(seed(&st), 9999);
+    }
+
+    #[test]
+    fn interval_outliers() {
+        let mut filter = IntervalFilter::default();
+
+        // Nothing to compare with at first.
+        for _ in 0..3 {
+            assert!(filter.accept(ms(100)).is_some());
+        }
+
+        // Missed and doubled edges.
+        assert!(filter.accept(ms(200)).is_none());
+        assert!(filter.accept(ms(40)).is_none());
+        assert!(filter.accept(ms(110)).is_some());
+
+        // A real change of tempo wins once it's the majority.
+        assert!(filter.accept(ms(200)).is_none());
+        assert!(filter.accept(ms(200)).is_none());
+        assert!(filter.accept(ms(200)).is_some());
+
+        filter.reset();
+        assert!(filter.accept(ms(20)).is_some());
+    }
+
+    #[test]
+    fn clear_performance() {
+        let mut st = euclid();
+        let seed = st.params.seed;
+
+        st.params.tracks[0].offset = 3;
+        st.mute[1] = true;
+        st.track_sync[2] = TrackSync::Loop;
+        st.lfo[3].set_mode(4);
+        apply(&mut st, ms(0), Oper::ClearPerformance);
+
+        assert_eq!(st.params.tracks[0].offset, 0);
+        assert!(!st.mute[1]);
+        assert_eq!(st.track_sync[2], TrackSync::default());
+        assert_eq!(st.lfo[3].mode, lfo::Mode::default());
+
+        // The pattern itself is kept.
+        assert_eq!(st.params.seed, seed);
+        assert_eq!(st.params.tracks[0].length, 16);
+        assert_eq!(st.params.tracks[0].steps, 4);
+        assert_eq!(st.step_at(0, 0), 1);
+    }
+
+    #[test]
+    fn quantize_early_steps() {
+        let mut st = State::new();
+        st.clock_quantize = true;
+        st.next_is_reset = false;
+        st.tick_count = 1;
+        st.predicted = ms(100);
+        let grid = ms(200).count;
+
+        // Jitter is snapped, the gates delayed by the difference.
+        assert_eq!(st.quantize(ms(195), grid).count, grid);
+        assert_eq!(st.grid_delay().map(|d| d.count), Some(ms(5).count));
+
+        // A change of tempo, or a late step, resyncs the grid.
+        assert_eq!(st.quantize(ms(170), grid).count, ms(170).count);
+        assert!(st.grid_delay().is_none());
+        assert_eq!(st.quantize(ms(205), grid).count, ms(205).count);
+        assert!(st.grid_delay().is_none());
+
+        st.clock_quantize = false;
+        assert_eq!(st.quantize(ms(195), grid).count, ms(195).count);
+        assert!(st.grid_delay().is_none());
+    }
+
+    #[test]
+    fn freeze_holds_the_pattern() {
+        let mut st = euclid();
+        st.params.pattern_length = 16;
+        st.regenerate();
+
+        apply(&mut st, ms(0), Oper::Freeze);
+        assert!(st.frozen.is_some());
+
+        // The edits are stored, but the pattern plays on as it was.
+        apply(&mut st, ms(10), Oper::SetLength(8));
+        apply(&mut st, ms(20), Oper::SetOffset(0, 2));
+        assert_eq!(st.params.pattern_length, 8);
+        assert_eq!(st.params.tracks[0].offset, 2);
+
+        st.playhead = 9;
+        apply(&mut st, ms(100), Oper::Tick(ms(100), None));
+        assert_eq!(st.playhead(), 10);
+        assert_eq!(st.track_playhead[0], 10);
+        assert_eq!(st.iter_hits(0).collect::<Vec<_>>(), vec![0, 4, 8, 12]);
+
+        // Unfreezing applies the edits once.
+        apply(&mut st, ms(200), Oper::Freeze);
+        assert!(st.frozen.is_none());
+        assert_eq!(st.playhead(), 2);
+        assert_ne!(st.iter_hits(0).collect::<Vec<_>>(), vec![0, 4, 8, 12]);
+
+        apply(&mut st, ms(300), Oper::Tick(ms(300), None));
+        assert_eq!(st.track_playhead[0], 3);
+    }
+
+    #[test]
+    fn clock_mult_on_the_dps() {
+        let mut st = State::new();
+        assert_eq!(st.to_display_dp(), 0);
+
+        // A dp per division.
+        st.clock_mult = -2;
+        assert_eq!(st.to_display_dp(), 0b0011);
+        st.clock_mult = -4;
+        assert_eq!(st.to_display_dp(), 0b1111);
+
+        // Multiplication blinks with the steps.
+        st.clock_mult = 2;
+        assert_eq!(st.to_display_dp(), 0b0011);
+        st.playhead = 1;
+        assert_eq!(st.to_display_dp(), 0);
+        st.playhead = 2;
+        st.clock_mult = 4;
+        assert_eq!(st.to_display_dp(), 0b1111);
+
+        // Only in run mode.
+        st.input_mode = InputModeAtTime(InputMode::Length, ms(0));
+        assert_eq!(st.to_display_dp(), 0);
+    }
+
+    #[test]
+    fn step_edit_from_menu() {
+        let mut st = euclid();
+
+        // A steps click after turning the offset still mutes.
+        apply(&mut st, ms(0), Oper::Offset(0, 1));
+        assert_eq!(st.input_mode.0, InputMode::Offset(0));
+        apply(&mut st, ms(10), Oper::StepsClick(0));
+        assert!(st.mute[0]);
+        assert_eq!(st.input_mode.0, InputMode::Offset(0));
+
+        // Step edit follows sync in the offset menu.
+        st.input_mode = InputModeAtTime(InputMode::TrackSync(0), ms(20));
+        apply(&mut st, ms(20), Oper::OffsetClick(0));
+        assert_eq!(st.input_mode.0, InputMode::StepEdit(0));
+
+        apply(&mut st, ms(30), Oper::Offset(0, 2));
+        apply(&mut st, ms(40), Oper::OffsetClick(0));
+        assert_eq!(st.overrides.get(0, 2), Some(true));
+
+        // Turning the steps keeps the overrides.
+        apply(&mut st, ms(50), Oper::Steps(0, 1));
+        assert_eq!(st.overrides.get(0, 2), Some(true));
+        assert_eq!(st.input_mode.0, InputMode::StepEdit(0));
+
+        // A long press clears them.
+        apply(&mut st, ms(60), Oper::OffsetDefault(0));
+        assert_eq!(st.overrides.get(0, 2), None);
+
+        // Steps clicks lead through the step value back to offset.
+        apply(&mut st, ms(70), Oper::StepsClick(0));
+        assert_eq!(st.input_mode.0, InputMode::StepValue(0));
+        apply(&mut st, ms(80), Oper::StepsClick(0));
+        assert_eq!(st.input_mode.0, InputMode::Offset(0));
+        assert!(st.mute[0]);
+    }
+
+    #[test]
+    fn warmup_after_reset() {
+        let mut st = euclid();
+        st.warmup[0] = 5;
+
+        let mut warm = vec![];
+
+        for reset in 0..2 {
+            apply(&mut st, ms(1000 * reset), Oper::Reset);
+
+            for i in 0..7 {
+                let at = ms(1000 * reset + 100 * i);
+                apply(&mut st, at, Oper::Tick(at, Some(ms(100))));
+                warm.push(st.in_warmup(0));
+            }
+        }
+
+        // Silent for the first 5 steps, whenever reset.
+        let expected = [true, true, true, true, true, false, false];
+        assert_eq!(&warm[..7], &expected);
+        assert_eq!(&warm[7..], &expected);
+        assert!(!st.in_warmup(1));
+    }
+
+    #[test]
+    fn offset_relative_and_absolute() {
+        let mut st = euclid();
+
+        // Relative wraps around the track length.
+        apply(&mut st, ms(0), Oper::Offset(0, -1));
+        assert_eq!(st.params.tracks[0].offset, 15);
+
+        // Absolute stops at either end.
+        st.offset_absolute = true;
+        apply(&mut st, ms(10), Oper::Offset(0, 5));
+        assert_eq!(st.params.tracks[0].offset, 15);
+        st.params.tracks[0].offset = 1;
+        apply(&mut st, ms(20), Oper::Offset(0, -5));
+        assert_eq!(st.params.tracks[0].offset, 0);
+
+        // Shortening the track clamps, or wraps.
+        st.params.tracks[0].offset = 15;
+        st.params.tracks[0].length = 10;
+        st.normalize();
+        assert_eq!(st.params.tracks[0].offset, 9);
+
+        st.offset_absolute = false;
+        st.params.tracks[0].offset = 15;
+        st.normalize();
+        assert_eq!(st.params.tracks[0].offset, 5);
+    }
+
+    #[test]
+    fn gate_role_index() {
+        for x in 0..GateRole::len() {
+            assert_eq!(GateRole::from(x as i8).index(), x as i8);
+        }
+
+        // The coincidences come last, after every OR mask.
+        assert_eq!(GateRole::from(-1), GateRole::Coincidence(2, 3));
+        assert_eq!(GateRole::from(-6), GateRole::Coincidence(0, 1));
+        assert_eq!(GateRole::from(-7), GateRole::AnyActive(0b1111));
+    }
+
+    #[test]
+    fn one_shot_until_reset() {
+        let mut st = euclid();
+        st.track_sync[0] = TrackSync::OneShot;
+
+        let mut done = vec![];
+
+        for reset in 0..2 {
+            apply(&mut st, ms(10_000 * reset), Oper::Reset);
+
+            for i in 0..40 {
+                let at = ms(10_000 * reset + 100 * i);
+                apply(&mut st, at, Oper::Tick(at, Some(ms(100))));
+                done.push(st.one_shot_done[0]);
+            }
+        }
+
+        // Plays the 16 steps once, then stops until reset.
+        for run in done.chunks(40) {
+            assert!(run[..16].iter().all(|d| !d));
+            assert!(run[16..].iter().all(|d| *d));
+        }
+
+        assert!(!st.one_shot_done[1]);
+    }
+
+    #[test]
+    fn gate_len_lfo_in_offset_chain() {
+        let mut st = State::new();
+        st.input_mode = InputModeAtTime(InputMode::LfoGate(1), ms(0));
+
+        apply(&mut st, ms(10), Oper::OffsetClick(1));
+        assert_eq!(st.input_mode.0, InputMode::GateLenMod(1));
+
+        // Off, then the LFO of each track, stopping at the last.
+        apply(&mut st, ms(20), Oper::Offset(1, 2));
+        assert_eq!(st.gate_len_mod_src[1], Some(1));
+        apply(&mut st, ms(30), Oper::Offset(1, 10));
+        assert_eq!(st.gate_len_mod_src[1], Some(3));
+
+        apply(&mut st, ms(40), Oper::OffsetDefault(1));
+        assert_eq!(st.gate_len_mod_src[1], None);
+
+        apply(&mut st, ms(50), Oper::OffsetClick(1));
+        assert_eq!(st.input_mode.0, InputMode::Algo(1));
+    }
+
+    #[test]
+    fn length_default_in_menu() {
+        let mut st = State::new();
+        st.params.pattern_length = 5;
+        st.swing = 60;
+        st.lfo[2].set_mode(3);
+
+        // Only resets the parameter of the menu.
+        st.input_mode = InputModeAtTime(InputMode::Swing, ms(0));
+        apply(&mut st, ms(10), Oper::LengthDefault);
+        assert_eq!(st.swing, 50);
+        assert_eq!(st.params.pattern_length, 5);
+
+        st.input_mode = InputModeAtTime(InputMode::Lfo(2), ms(20));
+        apply(&mut st, ms(20), Oper::LengthDefault);
+        assert_eq!(st.lfo[2].mode, lfo::Mode::Random);
+        assert_eq!(st.params.pattern_length, 5);
+
+        // Nothing to reset in the offset menu.
+        st.override_input_mode = None;
+        st.input_mode = InputModeAtTime(InputMode::Offset(1), ms(30));
+        apply(&mut st, ms(30), Oper::LengthDefault);
+        assert_eq!(st.input_mode.0, InputMode::Offset(1));
+        assert_eq!(st.params.pattern_length, 5);
+        assert!(st.override_input_mode.is_none());
+
+        st.input_mode = InputModeAtTime(InputMode::Run, ms(40));
+        apply(&mut st, ms(40), Oper::LengthDefault);
+        assert_eq!(st.params.pattern_length, STOKAST_PARAMS.pattern_length);
+        assert_eq!(st.input_mode.0, InputMode::Length);
+    }
+
+    #[test]
+    fn track_sync_in_offset_chain() {
+        let mut st = State::new();
+        st.input_mode = InputModeAtTime(InputMode::Warmup(2), ms(0));
+
+        apply(&mut st, ms(10), Oper::OffsetClick(2));
+        assert_eq!(st.input_mode.0, InputMode::TrackSync(2));
+
+        // Followed by the step edit.
+        apply(&mut st, ms(20), Oper::OffsetClick(2));
+        assert_eq!(st.input_mode.0, InputMode::StepEdit(2));
+    }
+
+    #[test]
+    fn empty_track() {
+        let mut st = euclid();
+        st.params.tracks[0].steps = 0;
+        st.regenerate();
+        assert_eq!(st.first_step(0), None);
+
+        // Shown as dashes in place of the steps.
+        st.input_mode = InputModeAtTime(InputMode::Steps(0), ms(0));
+        st.mode_label_at = None;
+        st.override_input_mode = None;
+        let segs = st.to_display();
+        assert_eq!(segs.0[1], Seg::SegG as u8);
+        assert_eq!(segs.0[2], Seg::SegG as u8);
+
+        // Given a single hit when not allowed.
+        st.input_mode = InputModeAtTime(InputMode::NoEmpty, ms(0));
+        apply(&mut st, ms(10), Oper::Length(1));
+        assert!(st.no_empty);
+        assert_eq!(st.iter_hits(0).count(), 1);
+
+        apply(&mut st, ms(20), Oper::Length(-1));
+        assert_eq!(st.first_step(0), None);
+    }
+}
@@ -0,0 +1,48 @@
+//! Monitoring of the +12V supply rail.
+//!
+//! The rail reaches the ADC on pin 16 (A2) through a resistor divider, 100k on top
+//! and 22k to ground, so 12V reads as 12 * 22 / 122 = 2.16V. The ADC runs at its
+//! default 10 bits against the 3.3V reference, which makes one step 3300 / 1023 =
+//! 3.2mV at the pin, or 3.2 * 122 / 22 = 17.9mV on the rail. The full scale is
+//! 3.3 * 122 / 22 = 18.3V, well above any eurorack rail.
+
+use alg::clock::Time;
+
+use crate::CPU_SPEED;
+
+/// How often to read the supply. Sagging is slow compared to this.
+pub const READ_INTERVAL: Time<{ CPU_SPEED }> = Time::from_millis(100);
+
+/// Below this the supply is low, in millivolts.
+pub const LOW_MV: u32 = 10_500;
+
+/// Above this a low supply is considered recovered, in millivolts. The gap to
+/// `LOW_MV` stops the warning flickering on a noisy rail.
+pub const RECOVER_MV: u32 = 11_000;
+
+/// ADC reading at the reference voltage.
+const ADC_MAX: u32 = 1023;
+
+/// ADC reference voltage in millivolts.
+const ADC_REF_MV: u32 = 3300;
+
+/// Divider resistor between the rail and the pin, in kΩ.
+const R_TOP: u32 = 100;
+
+/// Divider resistor between the pin and ground, in kΩ.
+const R_BOTTOM: u32 = 22;
+
+/// Convert an ADC reading to the rail voltage in millivolts.
+pub fn millivolts(reading: u16) -> u32 {
+    let pin_mv = reading as u32 * ADC_REF_MV / ADC_MAX;
+    pin_mv * (R_TOP + R_BOTTOM) / R_BOTTOM
+}
+
+/// Whether the supply is low given the rail voltage and whether it was low before.
+pub fn is_low(mv: u32, was_low: bool) -> bool {
+    if was_low {
+        mv < RECOVER_MV
+    } else {
+        mv < LOW_MV
+    }
+}
@@ -0,0 +1,69 @@
+//! Test pattern for bring-up and demos.
+//!
+//! Ignores the state entirely. The gates march 1-2-3-4 for two rounds, then
+//! alternate 1+3 and 2+4 for two rounds, while the DAC channels ramp up with a
+//! quarter of a cycle between them.
+
+use alg::clock::Time;
+
+use crate::output::GateSet;
+use crate::CPU_SPEED;
+
+/// Step interval, 8ths at 120 BPM.
+pub const STEP: Time<{ CPU_SPEED }> = Time::from_millis(250);
+
+/// Steps per DAC ramp.
+const RAMP_STEPS: i64 = 16;
+
+/// Max value of the 12-bit DAC.
+const DAC_MAX: i64 = 0xfff;
+
+pub struct TestPattern {
+    start: Time<{ CPU_SPEED }>,
+    /// Next step to play.
+    step: usize,
+}
+
+impl TestPattern {
+    pub fn new(now: Time<{ CPU_SPEED }>) -> Self {
+        TestPattern {
+            start: now,
+            step: 0,
+        }
+    }
+
+    /// Gates for this moment, Retain between steps, and the DAC values.
+    pub fn tick(&mut self, now: Time<{ CPU_SPEED }>) -> ([GateSet; 4], [Option<u16>; 4]) {
+        let elapsed = (now - self.start).count();
+
+        let mut gs = [GateSet::Retain; 4];
+
+        if elapsed >= self.step as i64 * STEP.count() {
+            let s = self.step % 16;
+
+            for (i, g) in gs.iter_mut().enumerate() {
+                let on = if s < 8 {
+                    // Marching.
+                    s % 4 == i
+                } else {
+                    // Alternating.
+                    s % 2 == i % 2
+                };
+
+                *g = if on { GateSet::Set } else { GateSet::Clear };
+            }
+
+            self.step += 1;
+        }
+
+        let period = STEP.count() * RAMP_STEPS;
+        let mut cv = [None; 4];
+
+        for (i, c) in cv.iter_mut().enumerate() {
+            let pos = (elapsed + period * i as i64 / 4) % period;
+            *c = Some((pos * DAC_MAX / period) as u16);
+        }
+
+        (gs, cv)
+    }
+}
@@ -0,0 +1,65 @@
+//! Conversions of `Time` to human units.
+
+use alg::clock::Time;
+
+use crate::CPU_SPEED;
+
+/// Conversions of `Time` for logging and tempo readouts.
+pub trait TimeExt {
+    /// The time in milliseconds.
+    fn as_millis_f32(&self) -> f32;
+
+    /// The time in microseconds.
+    fn as_micros_f32(&self) -> f32;
+
+    /// Tempo in BPM, taking the time as the interval between clock pulses
+    /// at `ppqn` pulses per quarter note. None for intervals that aren't positive.
+    fn as_bpm(&self, ppqn: u8) -> Option<f32>;
+}
+
+impl TimeExt for Time<{ CPU_SPEED }> {
+    fn as_millis_f32(&self) -> f32 {
+        self.count() as f32 * 1_000.0 / CPU_SPEED as f32
+    }
+
+    fn as_micros_f32(&self) -> f32 {
+        self.count() as f32 * 1_000_000.0 / CPU_SPEED as f32
+    }
+
+    fn as_bpm(&self, ppqn: u8) -> Option<f32> {
+        if self.count() <= 0 || ppqn == 0 {
+            return None;
+        }
+
+        Some(60.0 * CPU_SPEED as f32 / (self.count() as f32 * ppqn as f32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.01
+    }
+
+    #[test]
+    fn units() {
+        let t: Time<{ CPU_SPEED }> = Time::from_millis(250);
+
+        assert!(close(t.as_millis_f32(), 250.0));
+        assert!(close(t.as_micros_f32(), 250_000.0));
+    }
+
+    #[test]
+    fn bpm() {
+        let t: Time<{ CPU_SPEED }> = Time::from_millis(500);
+        assert!(close(t.as_bpm(1).unwrap(), 120.0));
+
+        let t: Time<{ CPU_SPEED }> = Time::from_millis(250);
+        assert!(close(t.as_bpm(2).unwrap(), 120.0));
+
+        assert_eq!(Time::<{ CPU_SPEED }>::default().as_bpm(1), None);
+        assert_eq!(t.as_bpm(0), None);
+    }
+}
@@ -0,0 +1,214 @@
+//! Commands from a computer over USB, alongside the logging.
+//!
+//! Each command is a line of text, ended by a newline. Numbers are decimal and
+//! tracks count from 1.
+//!
+//! ```ignore
+//! seed <0-9999>       set the seed (up to 65535 in the extended range)
+//! len <2-64>          set the pattern length
+//! steps <track> <n>   set the steps of a track
+//! offs <track> <n>    set the offset of a track
+//! lfo <track> <mode>  set the LFO mode of a track, 0 is random
+//! fate                randomize all the things
+//! display             dump what the display shows
+//! save                dump the settings in hex
+//! load <hex>          restore the settings from a save dump
+//! ```
+//!
+//! Malformed or too long lines are dropped with a warning.
+//!
+//! Replies go out in the log. `display` logs the raw segment bytes in hex, leftmost
+//! digit first, then the decimal point bits and the current input mode:
+//!
+//! ```ignore
+//! display <digit 4> <digit 3> <digit 2> <digit 1> <dp> <input mode>
+//! ```
+//!
+//! `save` logs the bytes of the settings, which is what `load` takes back:
+//!
+//! ```ignore
+//! settings <hex>
+//! ```
+
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::Write;
+use teensy4_bsp as bsp;
+
+use crate::persist;
+use crate::state::Oper;
+use crate::state::OperQueue;
+use crate::state::State;
+use crate::state::TRACK_COUNT;
+
+/// Longest accepted command line, which is a `load` of the settings.
+const LINE_MAX: usize = 8 + 2 * persist::LEN;
+
+/// A parsed command line.
+enum Cmd {
+    /// Change the state.
+    Oper(Oper),
+    /// Read-only dump of the display.
+    Display,
+    /// Dump of the settings.
+    Save,
+    /// Replace the settings.
+    Load(ArrayVec<u8, { persist::LEN }>),
+}
+
+pub struct UsbCmd {
+    reader: bsp::usb::Reader,
+    /// Line being received.
+    line: ArrayVec<u8, LINE_MAX>,
+    /// The current line is too long and dropped when it ends.
+    overflow: bool,
+}
+
+impl UsbCmd {
+    pub fn new(reader: bsp::usb::Reader) -> Self {
+        UsbCmd {
+            reader,
+            line: ArrayVec::new(),
+            overflow: false,
+        }
+    }
+
+    /// Read what the host sent and turn complete lines into operations. Read-only
+    /// commands are answered from the state straight away, and loaded settings
+    /// replace its settings.
+    pub fn tick(&mut self, todo: &mut OperQueue, state: &mut State) {
+        let mut buf = [0; 64];
+
+        let n = match self.reader.read(&mut buf) {
+            Ok(n) => n,
+            Err(_) => return,
+        };
+
+        for b in &buf[..n] {
+            if *b == b'\n' || *b == b'\r' {
+                if self.overflow {
+                    warn!("USB command too long");
+                } else if !self.line.is_empty() {
+                    match parse(&self.line) {
+                        Some(Cmd::Oper(oper)) => {
+                            if let Err(e) = todo.try_push(oper) {
+                                warn!("Operation queue full, dropping: {:?}", e.element());
+                            }
+                        }
+                        Some(Cmd::Display) => display(state),
+                        Some(Cmd::Save) => save(state),
+                        Some(Cmd::Load(bytes)) => load(&bytes, state),
+                        None => warn!("Bad USB command"),
+                    }
+                }
+
+                self.line.clear();
+                self.overflow = false;
+            } else if self.line.try_push(*b).is_err() {
+                self.overflow = true;
+            }
+        }
+    }
+}
+
+fn parse(line: &[u8]) -> Option<Cmd> {
+    let line = core::str::from_utf8(line).ok()?;
+    let mut words = line.split_ascii_whitespace();
+
+    let cmd = words.next()?;
+
+    let mut number = || -> Option<u32> { words.next()?.parse().ok() };
+
+    let parsed = match cmd {
+        "seed" => Cmd::Oper(Oper::SetSeed(number()?)),
+        "len" => Cmd::Oper(Oper::SetLength(number()?.min(u8::MAX as u32) as u8)),
+        "steps" => {
+            let tr = track(number()?)?;
+            Cmd::Oper(Oper::SetSteps(tr, number()?.min(u8::MAX as u32) as u8))
+        }
+        "offs" => {
+            let tr = track(number()?)?;
+            Cmd::Oper(Oper::SetOffset(tr, number()?.min(u8::MAX as u32) as u8))
+        }
+        "lfo" => {
+            let tr = track(number()?)?;
+            Cmd::Oper(Oper::SetLfoMode(tr, number()?.min(u8::MAX as u32) as u8))
+        }
+        "fate" => Cmd::Oper(Oper::Fate),
+        "display" => Cmd::Display,
+        "save" => Cmd::Save,
+        "load" => Cmd::Load(hex(words.next()?)?),
+        _ => return None,
+    };
+
+    // Trailing garbage means we misunderstood the line.
+    if words.next().is_some() {
+        return None;
+    }
+
+    Some(parsed)
+}
+
+/// Log the raw display, leftmost digit first.
+fn display(state: &State) {
+    let segs = state.to_display();
+    let dp = state.to_display_dp();
+
+    info!(
+        "display {:02x} {:02x} {:02x} {:02x} {:02x} {:?}",
+        segs.0[4],
+        segs.0[3],
+        segs.0[2],
+        segs.0[1],
+        dp,
+        state.display_mode()
+    );
+}
+
+/// Log the settings in hex.
+fn save(state: &State) {
+    let mut hex = ArrayString::<{ 2 * persist::LEN }>::new();
+
+    for b in persist::save(state).iter() {
+        // Can't overflow, the string fits all bytes.
+        write!(hex, "{:02x}", b).ok();
+    }
+
+    info!("settings {}", hex);
+}
+
+/// Replace the settings, unless the bytes don't hold valid ones. The rest of the
+/// state, such as the tempo and playhead, carries on.
+fn load(bytes: &[u8], state: &mut State) {
+    match persist::load(bytes, state) {
+        Some(loaded) => {
+            *state = loaded;
+            info!("Settings loaded");
+        }
+        None => warn!("Bad settings"),
+    }
+}
+
+/// Bytes of the settings from hex. Settings of an older version are shorter.
+fn hex(s: &str) -> Option<ArrayVec<u8, { persist::LEN }>> {
+    if s.len() % 2 != 0 || s.len() > 2 * persist::LEN {
+        return None;
+    }
+
+    let mut bytes = ArrayVec::new();
+
+    for pair in s.as_bytes().chunks(2) {
+        let pair = core::str::from_utf8(pair).ok()?;
+        bytes.push(u8::from_str_radix(pair, 16).ok()?);
+    }
+
+    Some(bytes)
+}
+
+/// Track counted from 1 to index.
+fn track(n: u32) -> Option<usize> {
+    if n >= 1 && n <= TRACK_COUNT as u32 {
+        Some(n as usize - 1)
+    } else {
+        None
+    }
+}